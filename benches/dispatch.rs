@@ -0,0 +1,50 @@
+#[macro_use]
+extern crate criterion;
+extern crate iridium;
+
+use criterion::{BatchSize, Criterion};
+use iridium::assembler::Assembler;
+use iridium::vm::VM;
+
+/// Counts down from 10000, adding to an accumulator, so the time is spent dispatching instructions
+const COUNTDOWN: &str = ".data\n.code\n\
+    load $t0 #10000\nload $t1 #0\nload $t2 #0\n\
+    loop: add $t1 $t0 $t1\ndec $t0\nneq $t0 $t2\ndjmpe @loop\nhlt";
+
+/// Converts numbers to text with the bundled library, which mixes calls, heap access and division
+const ITOA: &str = ".include <std/string>\n.data\n.code\n\
+    load $t0 #16\naloc $t0 $s2\nload $s0 #500\nload $s1 #0\n\
+    again: mov $a1 $s2\nadd $s0 $s1 $a0\ncall @itoa\ndec $s0\nneq $s0 $s1\ndjmpe @again\nhlt";
+
+fn vm(program: &[u8]) -> VM {
+    let mut vm = VM::default();
+    vm.add_bytes(program.to_vec());
+    vm
+}
+
+fn bench_program(c: &mut Criterion, name: &str, source: &str) {
+    let program = Assembler::new().assemble(source).unwrap();
+    let mut group = c.benchmark_group(name);
+    group.bench_function("reference", |b| b.iter_batched(
+        || vm(&program),
+        |mut vm| vm.run_reference(),
+        BatchSize::SmallInput
+    ));
+    group.bench_function("decoded", |b| b.iter_batched(
+        || vm(&program),
+        |mut vm| vm.run(),
+        BatchSize::SmallInput
+    ));
+    group.finish();
+}
+
+fn countdown(c: &mut Criterion) {
+    bench_program(c, "countdown", COUNTDOWN);
+}
+
+fn itoa(c: &mut Criterion) {
+    bench_program(c, "itoa", ITOA);
+}
+
+criterion_group!(benches, countdown, itoa);
+criterion_main!(benches);
@@ -9,12 +9,20 @@ pub enum AssemblerError {
     UnknownDirectiveFound{ directive: String },
     NonOpcodeInOpcodeField,
     InsufficientSections,
-    ParseError{ error: String }
+    ParseError{ error: String },
+    UndefinedSymbol{ name: String },
+    LinkError{ error: String },
+    IncludeError{ name: String, error: String },
+    UnknownRegister{ name: String },
+    InvalidRegisterAlias{ name: String },
+    InvalidPseudoInstruction{ instruction: String, error: String },
+    UnknownMnemonic{ name: String, suggestion: Option<String> },
+    InvalidOperands{ instruction: String, error: String }
 }
 
 impl fmt::Display for AssemblerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
+        match self {
             AssemblerError::NoSegmentDeclarationFound{ instruction } => {
                 f.write_str(&format!("No segment declaration (e.g., .code, .data) prior to finding an opcode. Instruction # was {}", instruction))
             }
@@ -22,25 +30,53 @@ impl fmt::Display for AssemblerError {
                 f.write_str(&format!("Found a string constant without a corresponding label. Instruction # was {}", instruction))
             },
             AssemblerError::SymbolAlreadyDeclared => {
-                f.write_str(&format!("This symbol was previously declared"))
+                f.write_str("This symbol was previously declared")
             },
             AssemblerError::UnknownDirectiveFound{ directive } => {
                 f.write_str(&format!("Invalid or unknown directive. Directive name was: {}", directive))
             },
             AssemblerError::NonOpcodeInOpcodeField => {
-                f.write_str(&format!("A non-opcode was found in an opcode field"))
+                f.write_str("A non-opcode was found in an opcode field")
             },
             AssemblerError::InsufficientSections => {
-                f.write_str(&format!("Less than two sections/segments were found in the code"))
+                f.write_str("Less than two sections/segments were found in the code")
             },
             AssemblerError::ParseError{ error } => {
                 f.write_str(&format!("There was an error parsing the code: {}", error))
+            },
+            AssemblerError::UndefinedSymbol{ name } => {
+                f.write_str(&format!("Symbol was used or exported but never defined: {}", name))
+            },
+            AssemblerError::LinkError{ error } => {
+                f.write_str(&format!("There was an error linking the code: {}", error))
+            },
+            AssemblerError::IncludeError{ name, error } => {
+                f.write_str(&format!("Unable to include <{}>: {}", name, error))
+            },
+            AssemblerError::UnknownRegister{ name } => {
+                f.write_str(&format!("There is no register or register alias named ${}", name))
+            },
+            AssemblerError::InvalidRegisterAlias{ name } => {
+                f.write_str(&format!("Invalid register alias {}: aliases cannot reuse a register's conventional name and must name one of $0 to $30, as $at is kept for pseudo-instructions", name))
+            },
+            AssemblerError::InvalidPseudoInstruction{ instruction, error } => {
+                f.write_str(&format!("Invalid pseudo-instruction {}: {}", instruction, error))
+            },
+            AssemblerError::UnknownMnemonic{ name, suggestion } => {
+                match suggestion {
+                    Some(suggestion) => f.write_str(&format!("Unknown mnemonic `{}`, did you mean `{}`?", name, suggestion)),
+                    None => f.write_str(&format!("Unknown mnemonic `{}`", name))
+                }
+            },
+            AssemblerError::InvalidOperands{ instruction, error } => {
+                f.write_str(&format!("Invalid operands in `{}`: {}", instruction, error))
             }
         }
     }
 }
 
 impl Error for AssemblerError {
+    #[allow(unused_variables, non_snake_case)]
     fn description(&self) -> &str {
         match self {
             AssemblerError::NoSegmentDeclarationFound{ instruction: u32 } => {
@@ -63,6 +99,30 @@ impl Error for AssemblerError {
             },
             AssemblerError::ParseError{ error: String } => {
                 "There was an error parsing the code: {}"
+            },
+            AssemblerError::UndefinedSymbol{ .. } => {
+                "Symbol was used or exported but never defined: {}"
+            },
+            AssemblerError::LinkError{ .. } => {
+                "There was an error linking the code: {}"
+            },
+            AssemblerError::IncludeError{ .. } => {
+                "Unable to include a standard library module"
+            },
+            AssemblerError::UnknownRegister{ .. } => {
+                "There is no register or register alias with this name"
+            },
+            AssemblerError::InvalidRegisterAlias{ .. } => {
+                "Invalid register alias"
+            },
+            AssemblerError::InvalidPseudoInstruction{ .. } => {
+                "Invalid pseudo-instruction"
+            },
+            AssemblerError::UnknownMnemonic{ .. } => {
+                "Unknown mnemonic"
+            },
+            AssemblerError::InvalidOperands{ .. } => {
+                "Invalid operands"
             }
         }
     }
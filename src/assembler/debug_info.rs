@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+
+/// Maps the code of an assembled program back to the source it was assembled from, for tools such as
+/// the profiler. Addresses are offsets into the program, header included, like `pc`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    /// Source line, counting from 1, of the instruction at each address
+    pub lines: BTreeMap<usize, u32>,
+    /// Code labels by the address they mark
+    pub labels: BTreeMap<usize, String>,
+    /// The source, one entry per line
+    pub source: Vec<String>
+}
+
+impl DebugInfo {
+    pub fn line_at(&self, pc: usize) -> Option<u32> {
+        self.lines.get(&pc).cloned()
+    }
+
+    /// Text of a source line, counting from 1
+    pub fn source_line(&self, line: u32) -> Option<&str> {
+        self.source.get((line as usize).checked_sub(1)?).map(|text| text.trim())
+    }
+
+    /// The closest label at or before `pc`, with how far past it `pc` is
+    pub fn label_at(&self, pc: usize) -> Option<(&str, usize)> {
+        self.labels.range(..=pc).next_back().map(|(address, name)| (name.as_str(), pc - address))
+    }
+
+    /// `pc` as a label and offset, such as `loop+8`, or just the label when `pc` is the label
+    pub fn location(&self, pc: usize) -> Option<String> {
+        match self.label_at(pc)? {
+            (name, 0) => Some(name.to_string()),
+            (name, offset) => Some(format!("{}+{}", name, offset))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_debug_info() {
+        let source = ".data\n.code\nload $t0 #3\nloop: dec $t0\n\nmov $t1 $t0\nhlt";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let debug = asm.debug_info(source, &program);
+        assert_eq!(debug.lines.values().cloned().collect::<Vec<_>>(), vec![3, 4, 6, 6, 7]);
+        assert_eq!(debug.line_at(68), Some(4));
+        assert_eq!(debug.source_line(6), Some("mov $t1 $t0"));
+        assert_eq!(debug.location(68), Some("loop".to_string()));
+        assert_eq!(debug.location(76), Some("loop+8".to_string()));
+        assert_eq!(debug.location(64), None);
+    }
+}
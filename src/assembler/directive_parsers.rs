@@ -1,10 +1,11 @@
-use nom::alpha1;
+use nom::{alpha1, alphanumeric, digit, multispace, space};
 use nom::types::CompleteStr;
 
 use crate::assembler::instruction_parsers::AssemblerInstruction;
 use crate::assembler::Token;
 use crate::assembler::operand_parsers::operand;
 use crate::assembler::label_parsers::label_declaration;
+use crate::assembler::register_parsers::register;
 
 named!(directive_declaration<CompleteStr, Token>,
     do_parse!(
@@ -38,9 +39,140 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
     )
 );
 
+// An optional ` #2` version following a symbol name on the same line
+named!(symbol_version<CompleteStr, Token>,
+    do_parse!(
+        space >>
+        tag!("#") >>
+        version: digit >>
+        (
+            Token::IntegerOperand { value: version.parse::<i64>().unwrap_or(i64::MAX) }
+        )
+    )
+);
+
+// Parses directives that take a bare symbol name, such as `.global main` or `.extern print`.
+// The name has to be on the same line, otherwise it would be confused with the next instruction.
+named!(symbol_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".") >>
+        name: alt!(tag!("global") | tag!("extern")) >>
+        space >>
+        opt!(tag!("@")) >>
+        symbol: alphanumeric >>
+        version: opt!(symbol_version) >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(Token::Directive { name: name.to_string() }),
+                label: None,
+                operand1: Some(Token::LabelUsage { name: symbol.to_string() }),
+                operand2: version,
+                operand3: None,
+            }
+        )
+    )
+);
+
+// Parses `.import module.symbol`, optionally followed by the version of the export that is required
+named!(import_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".import") >>
+        space >>
+        module: alphanumeric >>
+        tag!(".") >>
+        symbol: alphanumeric >>
+        version: opt!(symbol_version) >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(Token::Directive { name: "import".to_string() }),
+                label: None,
+                operand1: Some(Token::LabelUsage { name: format!("{}.{}", module, symbol) }),
+                operand2: version,
+                operand3: None,
+            }
+        )
+    )
+);
+
+// Parses `.include <std/io>`, which pulls in one of the bundled standard library modules
+named!(include_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".include") >>
+        space >>
+        tag!("<") >>
+        name: take_until!(">") >>
+        tag!(">") >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(Token::Directive { name: "include".to_string() }),
+                label: None,
+                operand1: Some(Token::LabelUsage { name: name.to_string() }),
+                operand2: None,
+                operand3: None,
+            }
+        )
+    )
+);
+
+// Parses `.alias counter $5`, which lets `$counter` be used in place of `$5` until it is `.unalias`ed
+named!(alias_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".alias") >>
+        space >>
+        name: alphanumeric >>
+        space >>
+        target: register >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(Token::Directive { name: "alias".to_string() }),
+                label: None,
+                operand1: Some(Token::LabelUsage { name: name.to_string() }),
+                operand2: Some(target),
+                operand3: None,
+            }
+        )
+    )
+);
+
+named!(unalias_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".unalias") >>
+        space >>
+        name: alphanumeric >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(Token::Directive { name: "unalias".to_string() }),
+                label: None,
+                operand1: Some(Token::LabelUsage { name: name.to_string() }),
+                operand2: None,
+                operand3: None,
+            }
+        )
+    )
+);
+
 named!(pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
+            symbol_directive |
+            import_directive |
+            include_directive |
+            alias_directive |
+            unalias_directive |
             directive_combined
         ) >>
         (
@@ -51,7 +183,7 @@ named!(pub directive<CompleteStr, AssemblerInstruction>,
 
 #[cfg(test)]
 mod tests {
-    #![allow(unused_imports)]
+    #![allow(unused_imports, clippy::bool_assert_comparison)]
     use super::*;
 
     #[test]
@@ -85,4 +217,53 @@ mod tests {
 
         assert_eq!(directive, correct_instruction);
     }
+
+    #[test]
+    fn test_symbol_directive() {
+        let result = directive(CompleteStr(".global main\nhlt"));
+        assert!(result.is_ok());
+        let (rest, global) = result.unwrap();
+        assert_eq!(rest, CompleteStr("hlt"));
+        assert_eq!(global.get_directive_name(), Some("global".to_string()));
+        assert_eq!(global.get_symbol_operand(), Some("main".to_string()));
+
+        let result = symbol_directive(CompleteStr(".extern\nprint"));
+        assert!(result.is_err());
+
+        let (_, versioned) = directive(CompleteStr(".global sqrt #2\n")).unwrap();
+        assert_eq!(versioned.get_version_operand(), Some(2));
+    }
+
+    #[test]
+    fn test_import_directive() {
+        let result = directive(CompleteStr(".import mathlib.sqrt #3\n.code"));
+        assert!(result.is_ok());
+        let (rest, import) = result.unwrap();
+        assert_eq!(rest, CompleteStr(".code"));
+        assert_eq!(import.get_directive_name(), Some("import".to_string()));
+        assert_eq!(import.get_symbol_operand(), Some("mathlib.sqrt".to_string()));
+        assert_eq!(import.get_version_operand(), Some(3));
+    }
+
+    #[test]
+    fn test_include_directive() {
+        let result = directive(CompleteStr(".include <std/io>\n.data"));
+        assert!(result.is_ok());
+        let (rest, include) = result.unwrap();
+        assert_eq!(rest, CompleteStr(".data"));
+        assert_eq!(include.get_directive_name(), Some("include".to_string()));
+        assert_eq!(include.get_symbol_operand(), Some("std/io".to_string()));
+    }
+
+    #[test]
+    fn test_alias_directives() {
+        let (rest, alias) = directive(CompleteStr(".alias counter $5\n.unalias counter\n")).unwrap();
+        assert_eq!(alias.get_directive_name(), Some("alias".to_string()));
+        assert_eq!(alias.get_symbol_operand(), Some("counter".to_string()));
+        assert_eq!(alias.operand2, Some(Token::Register { reg_num: 5 }));
+
+        let (_, unalias) = directive(rest).unwrap();
+        assert_eq!(unalias.get_directive_name(), Some("unalias".to_string()));
+        assert_eq!(unalias.get_symbol_operand(), Some("counter".to_string()));
+    }
 }
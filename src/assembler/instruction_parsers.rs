@@ -1,3 +1,7 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use nom::multispace;
 use nom::types::CompleteStr;
 
 use crate::assembler::Token;
@@ -5,8 +9,11 @@ use crate::assembler::opcode_parsers::*;
 use crate::assembler::operand_parsers::operand;
 use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::SymbolTable;
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::INSTRUCTION_LENGTH;
+use crate::instruction::OperandKind;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -34,6 +41,8 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
         o1: opt!(operand) >>
         o2: opt!(operand) >>
         o3: opt!(operand) >>
+        // Instructions without operands leave their newline behind otherwise
+        opt!(multispace) >>
         (
             AssemblerInstruction{
                 opcode: Some(o),
@@ -52,24 +61,20 @@ impl AssemblerInstruction {
         let mut results = vec![];
         if let Some(ref token) = self.opcode {
             match token {
-                Token::Op { code } => match code {
-                    _ => {
-                        results.push(*code as u8);
-                    }
+                Token::Op { code } => {
+                    results.push(code.to_u8());
                 },
                 _ => {
-                    println!("Non opcode found in opcode field");
+                    warn!("Non opcode found in opcode field");
                 }
             }
         }
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results, symbols)
-            }
+        for token in [&self.operand1, &self.operand2, &self.operand3].iter().copied().flatten() {
+            AssemblerInstruction::extract_operand(token, &mut results, symbols)
         }
 
-        while results.len() < 4 {
+        while results.len() < INSTRUCTION_LENGTH as usize {
             results.push(0);
         }
 
@@ -84,6 +89,50 @@ impl AssemblerInstruction {
         self.opcode.is_some()
     }
 
+    /// Fails if the opcode is not a known mnemonic, suggesting the closest one
+    pub fn check_opcode(&self) -> Result<(), AssemblerError> {
+        match &self.opcode {
+            Some(Token::UnknownOp { name }) => Err(AssemblerError::UnknownMnemonic{
+                name: name.clone(),
+                suggestion: closest_mnemonic(name).map(|s| s.to_string())
+            }),
+            _ => Ok(())
+        }
+    }
+
+    /// Fails if the operands do not match the ones the opcode table lists for the opcode
+    pub fn check_operands(&self) -> Result<(), AssemblerError> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return Ok(())
+        };
+        let operands: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3].iter().copied().flatten().collect();
+        let kinds = code.operands();
+        let invalid = |error: String| AssemblerError::InvalidOperands{ instruction: self.to_string(), error };
+        if operands.len() != kinds.len() {
+            return Err(invalid(format!("{} takes {} operands but {} were given", code.mnemonic(), kinds.len(), operands.len())));
+        }
+        for (index, (token, kind)) in operands.iter().zip(kinds).enumerate() {
+            let valid = match kind {
+                OperandKind::Register => matches!(token, Token::Register { .. } | Token::RegisterAlias { .. }),
+                OperandKind::Integer | OperandKind::Address => matches!(token, Token::IntegerOperand { .. } | Token::LabelUsage { .. })
+            };
+            if !valid {
+                return Err(invalid(format!("operand {} has to be {} {}", index + 1, if *kind == OperandKind::Integer { "an" } else { "a" }, kind.name())));
+            }
+            if let Token::IntegerOperand { value } = token {
+                if u16::try_from(*value).is_err() {
+                    return Err(invalid(format!("operand {} is {}, which does not fit in 16 bits (0 to 65535)", index + 1, value)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_pseudo(&self) -> bool {
+        matches!(self.opcode, Some(Token::PseudoOp { .. }))
+    }
+
     pub fn is_directive(&self) -> bool {
         self.directive.is_some()
     }
@@ -96,28 +145,30 @@ impl AssemblerInstruction {
 
     pub fn get_directive_name(&self) -> Option<String> {
         match &self.directive {
-            Some(d) => {
-                match d {
-                    Token::Directive { name } => {
-                        Some(name.to_string())
-                    }
-                    _ => { None }
-                }
-            }
-            None => { None }
+            Some(Token::Directive { name }) => Some(name.to_string()),
+            _ => None
         }
     }
 
     pub fn get_string_constant(&self) -> Option<String> {
         match &self.operand1 {
-            Some(d) => {
-                match d {
-                    Token::IrString { name } => {
-                        Some(name.to_string())
-                    }
-                    _ => None
-                }
-            }
+            Some(Token::IrString { name }) => Some(name.to_string()),
+            _ => None
+        }
+    }
+
+    /// Name of the symbol a directive such as `.global` refers to
+    pub fn get_symbol_operand(&self) -> Option<String> {
+        match &self.operand1 {
+            Some(Token::LabelUsage { name }) => Some(name.to_string()),
+            _ => None
+        }
+    }
+
+    /// Version given to `.global` or `.import`, if any
+    pub fn get_version_operand(&self) -> Option<u16> {
+        match &self.operand2 {
+            Some(Token::IntegerOperand { value }) => Some(*value as u16),
             _ => None
         }
     }
@@ -138,6 +189,24 @@ impl AssemblerInstruction {
         }
     }
 
+    /// Returns the `@label` operands along with their byte offset inside the encoded instruction
+    pub fn label_usages(&self) -> Vec<(u32, String)> {
+        let mut usages = vec![];
+        let mut position = 1;
+        for token in [&self.operand1, &self.operand2, &self.operand3].iter().copied().flatten() {
+            match token {
+                Token::Register { .. } | Token::RegisterAlias { .. } => { position += 1; },
+                Token::IntegerOperand { .. } => { position += 2; },
+                Token::LabelUsage { name } => {
+                    usages.push((position, name.clone()));
+                    position += 2;
+                },
+                _ => {}
+            }
+        }
+        usages
+    }
+
     fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
         match t {
             Token::Register { reg_num } => {
@@ -151,20 +220,32 @@ impl AssemblerInstruction {
                 results.push(byte1 as u8);
             },
             Token::LabelUsage { name } => {
-                if let Some(value) = symbols.symbol_value(name) {
-                    let byte1 = value;
-                    let byte2 = value >> 8;
-                    results.push(byte2 as u8);
-                    results.push(byte1 as u8);
-                }
+                // Labels that are not known yet, such as externs, are left as zero for the linker to fill in
+                let value = symbols.symbol_value(name).unwrap_or(0);
+                let byte1 = value;
+                let byte2 = value >> 8;
+                results.push(byte2 as u8);
+                results.push(byte1 as u8);
             }
             _ => {
-                println!("Opcode found in operand field: {:#?}", t);
+                warn!("Opcode found in operand field: {:#?}", t);
             }
         }
     }
 }
 
+impl fmt::Display for AssemblerInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens: Vec<String> = [&self.label, &self.opcode, &self.directive, &self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+            .map(|t| t.to_string())
+            .collect();
+        f.write_str(&tokens.join(" "))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(unused_imports)]
@@ -31,7 +31,7 @@ named!(pub label_usage<CompleteStr, Token>,
 
 #[cfg(test)]
 mod tests {
-    #![allow(unused_imports)]
+    #![allow(unused_imports, clippy::bool_assert_comparison)]
     use super::*;
 
     #[test]
@@ -9,27 +9,82 @@ pub mod label_parsers;
 pub mod directive_parsers;
 pub mod assembler_errors;
 pub mod symbols;
+pub mod object;
+pub mod registers;
+pub mod pseudo_instructions;
+pub mod optimizer;
+pub mod debug_info;
+
+use std::fmt;
+
+use byteorder::{LittleEndian, WriteBytesExt};
 
 use crate::instruction::Opcode;
-use program_parsers::{program, Program};
+use crate::disassembler::Disassembler;
+use crate::linker::Linker;
+use crate::loader::PieLayout;
+use crate::stdlib;
+use crate::vm::MAIN_MODULE;
+use program_parsers::{program_with_lines, Program};
 use instruction_parsers::{AssemblerInstruction};
 use assembler_errors::AssemblerError;
-use symbols::{Symbol, SymbolTable, SymbolType};
+use object::{Import, ObjectFile, Relocation};
+use symbols::{Symbol, SymbolSection, SymbolTable, SymbolType, Visibility};
+use registers::RegisterAliases;
+use pseudo_instructions::PseudoOp;
+use optimizer::Rewrite;
+use debug_info::DebugInfo;
 
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub const PIE_HEADER_LENGTH: usize = 64;
-
-#[derive(Debug, PartialEq)]
+/// Where the length of the read only section is stored in the PIE header
+pub const PIE_RO_LENGTH_OFFSET: usize = 4;
+/// Where the length of the data section is stored in the PIE header
+pub const PIE_DATA_LENGTH_OFFSET: usize = 8;
+/// Where the length of the export table is stored in the PIE header
+pub const PIE_EXPORTS_LENGTH_OFFSET: usize = 12;
+/// Where the length of the import table is stored in the PIE header
+pub const PIE_IMPORTS_LENGTH_OFFSET: usize = 16;
+/// Every instruction is encoded as an opcode byte followed by three operand bytes
+pub const INSTRUCTION_LENGTH: u32 = crate::instruction::INSTRUCTION_LENGTH as u32;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Op {code: Opcode},
+    /// A pseudo-instruction such as `mov`, expanded into real instructions before assembling
+    PseudoOp { op: PseudoOp },
+    /// A word in the opcode position that is not a known mnemonic
+    UnknownOp { name: String },
     Register { reg_num: u8 },
-    IntegerOperand { value: i32 },
+    /// A register named by an alias from `.alias`, resolved while assembling
+    RegisterAlias { name: String },
+    IntegerOperand { value: i64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
     IrString { name: String }
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Op { code } => f.write_str(code.mnemonic()),
+            Token::PseudoOp { op } => f.write_str(op.name()),
+            Token::UnknownOp { name } => f.write_str(name),
+            Token::Register { reg_num } => match registers::abi_name(*reg_num) {
+                Some(name) => write!(f, "${}", name),
+                None => write!(f, "${}", reg_num)
+            },
+            Token::RegisterAlias { name } => write!(f, "${}", name),
+            Token::IntegerOperand { value } => write!(f, "#{}", value),
+            Token::LabelDeclaration { name } => write!(f, "{}:", name),
+            Token::LabelUsage { name } => write!(f, "@{}", name),
+            Token::Directive { name } => write!(f, ".{}", name),
+            Token::IrString { name } => write!(f, "'{}'", name)
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Assembler {
     phase: AssemblerPhase,
@@ -37,22 +92,50 @@ pub struct Assembler {
     pub ro: Vec<u8>,
     pub bytecode: Vec<u8>,
     ro_offset: u32,
+    code_offset: u32,
+    relocations: Vec<Relocation>,
+    imports: Vec<Import>,
+    includes: Vec<String>,
+    aliases: RegisterAliases,
+    /// Code offset of every instruction that came from a pseudo-instruction, with the pseudo-instruction
+    pub pseudo_origins: Vec<(u32, String)>,
+    /// Code offset of every instruction with the source line it was written on
+    pub source_lines: Vec<(u32, u32)>,
+    /// Where each instruction of the expanded program came from
+    origins: Vec<Origin>,
+    /// Runs the peephole optimizer between the two phases when set
+    pub optimize: bool,
+    /// What the peephole optimizer rewrote
+    pub rewrites: Vec<Rewrite>,
+    /// Why the peephole optimizer left the program alone, if it did
+    pub optimizer_skipped: Option<&'static str>,
     sections: Vec<AssemblerSection>,
     current_section: Option<AssemblerSection>,
     current_instruction: u32,
     errors: Vec<AssemblerError>
 }
 
-#[derive(Debug, PartialEq)]
+/// Where an instruction of the expanded program came from
+#[derive(Debug, Clone, PartialEq)]
+struct Origin {
+    /// The pseudo-instruction it was expanded from, if any
+    pseudo: Option<String>,
+    /// Source line it was written on, counting from 1
+    line: u32
+}
+
+#[derive(Debug, Default, PartialEq)]
 pub enum AssemblerPhase {
+    #[default]
     First,
     Second
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub enum AssemblerSection {
     Data { starting_instruction: Option<u32> },
     Code { starting_instruction: Option<u32> },
+    #[default]
     Unknown
 }
 
@@ -61,6 +144,17 @@ impl Assembler {
         Assembler {
             current_instruction: 0,
             ro_offset: 0,
+            code_offset: 0,
+            relocations: vec![],
+            imports: vec![],
+            includes: vec![],
+            aliases: RegisterAliases::new(),
+            pseudo_origins: vec![],
+            source_lines: vec![],
+            origins: vec![],
+            optimize: false,
+            rewrites: vec![],
+            optimizer_skipped: None,
             ro: vec![],
             bytecode: vec![],
             sections: vec![],
@@ -71,39 +165,142 @@ impl Assembler {
         }
     }
 
+    /// Assembles a complete program into an executable with a PIE header
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
-        match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
-                let mut assembled_program = self.write_pie_header();
+        let object = self.assemble_object(raw)?;
+        let mut linker = Linker::new();
+        linker.add_object("<input>", object);
+        linker.link().map_err(|errors| {
+            errors.iter().map(|e| AssemblerError::LinkError{ error: e.to_string() }).collect()
+        })
+    }
+
+    /// Assembles a module into a relocatable object file that can be linked with other modules
+    pub fn assemble_object(&mut self, raw: &str) -> Result<ObjectFile, Vec<AssemblerError>> {
+        match program_with_lines(CompleteStr(raw)) {
+            Ok((remainder, _)) if !remainder.trim().is_empty() => {
+                let line = remainder.trim().lines().next().unwrap_or_default();
+                Err(vec![AssemblerError::ParseError{ error: format!("unable to parse `{}`", line) }])
+            },
+            Ok((_, (program, lines))) => {
+                let mut program = self.expand_pseudo_instructions(program, &lines);
+                if !self.errors.is_empty() {
+                    return Err(self.errors.clone());
+                }
+
                 self.process_first_phase(&program);
+                if self.optimize {
+                    self.optimizer_skipped = optimizer::skip_reason(&program);
+                    self.rewrites = optimizer::optimize(&mut program, &mut self.origins);
+                    if !self.rewrites.is_empty() {
+                        self.place_code_labels(&program);
+                    }
+                }
 
                 if !self.errors.is_empty() {
                     return Err(self.errors.clone());
                 }
 
                 if self.sections.len() != 2 {
-                    println!("Did not find at least two sections.");
                     self.errors.push(AssemblerError::InsufficientSections);
                     return Err(self.errors.clone());
                 }
 
-                let mut body = self.process_second_phase(&program);
+                let code = self.process_second_phase(&program);
+
+                if !self.errors.is_empty() {
+                    return Err(self.errors.clone());
+                }
 
-                assembled_program.append(&mut body);
-                Ok(assembled_program)
+                Ok(ObjectFile {
+                    code,
+                    ro: self.ro.clone(),
+                    symbols: self.symbols.clone(),
+                    relocations: self.relocations.clone(),
+                    imports: self.imports.clone(),
+                    includes: self.includes.clone()
+                })
             },
             Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
+                debug!("There was an error assembling the code: {:?}", e);
                 Err(vec![AssemblerError::ParseError{ error: e.to_string() }])
             }
         }
     }
 
+    /// Replaces pseudo-instructions with the real instructions they stand for, so both phases only
+    /// ever see real instructions and label offsets account for the expanded size
+    fn expand_pseudo_instructions(&mut self, p: Program, lines: &[u32]) -> Program {
+        let mut instructions = vec![];
+        for (i, line) in p.instructions.into_iter().zip(lines.iter().cloned()) {
+            if !i.is_pseudo() {
+                instructions.push(i);
+                self.origins.push(Origin { pseudo: None, line });
+                continue;
+            }
+            match pseudo_instructions::expand(&i) {
+                Ok(expanded) => {
+                    for real in expanded {
+                        instructions.push(real);
+                        self.origins.push(Origin { pseudo: Some(i.to_string()), line });
+                    }
+                },
+                Err(e) => self.errors.push(e)
+            }
+        }
+        Program { instructions }
+    }
+
+    /// Works out code label offsets again after the optimizer has removed instructions
+    fn place_code_labels(&mut self, p: &Program) {
+        self.code_offset = 0;
+        for i in &p.instructions {
+            if i.is_opcode() {
+                if let Some(name) = i.get_label_name() {
+                    self.symbols.set_symbol_offset(&name, self.code_offset);
+                }
+                self.code_offset += INSTRUCTION_LENGTH;
+            }
+        }
+        self.size_code_labels();
+    }
+
+    /// What the peephole optimizer did, a line per rewrite, or why it left the program alone
+    pub fn optimizer_report(&self) -> Vec<String> {
+        match self.optimizer_skipped {
+            Some(reason) => vec![format!("optimizer skipped: {}", reason)],
+            None => self.rewrites.iter().map(|rewrite| rewrite.to_string()).collect()
+        }
+    }
+
+    /// Disassembles assembled code, noting which pseudo-instruction each instruction came from
+    pub fn listing(&self, code: &[u8]) -> Vec<String> {
+        let mut disassembler = Disassembler::new();
+        for (offset, origin) in &self.pseudo_origins {
+            disassembler.annotate(*offset as usize, origin.clone());
+        }
+        disassembler.listing(code, 0)
+    }
+
+    /// Maps the code of a program this assembler just assembled back to `source`, which has to be the
+    /// source it was assembled from
+    pub fn debug_info(&self, source: &str, program: &[u8]) -> DebugInfo {
+        let code_start = PieLayout::parse(MAIN_MODULE, program).map_or(0, |layout| layout.code_start);
+        let labels = self.symbols.iter()
+            .filter(|symbol| symbol.symbol_type == SymbolType::CodeLabel && symbol.section == Some(SymbolSection::Code))
+            .filter_map(|symbol| symbol.offset.map(|offset| (code_start + offset as usize, symbol.name.clone())));
+        DebugInfo {
+            lines: self.source_lines.iter().map(|(offset, line)| (code_start + *offset as usize, *line)).collect(),
+            labels: labels.collect(),
+            source: source.lines().map(String::from).collect()
+        }
+    }
+
     fn process_first_phase(&mut self, p: &Program) {
         for i in &p.instructions {
             if i.is_label() {
                 if self.current_section.is_some() {
-                    self.process_label_declaration(&i);
+                    self.process_label_declaration(i);
                 } else {
                     self.errors.push(AssemblerError::NoSegmentDeclarationFound{ instruction: self.current_instruction });
                 }
@@ -112,18 +309,45 @@ impl Assembler {
             if i.is_directive() {
                 self.process_directive(i);
             }
-            
+
+            if i.is_opcode() {
+                if let Err(e) = i.check_opcode().and_then(|_| i.check_operands()) {
+                    self.errors.push(e);
+                }
+                self.code_offset += INSTRUCTION_LENGTH;
+            }
+
             self.current_instruction += 1;
         }
+        self.check_globals_defined();
+        self.size_code_labels();
         self.phase = AssemblerPhase::Second;
     }
 
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         self.current_instruction = 0;
+        self.aliases = RegisterAliases::new();
         let mut program = vec![];
 
-        for i in &p.instructions {
+        for (index, i) in p.instructions.iter().enumerate() {
             if i.is_opcode() {
+                if let Some(origin) = self.origins.get(index) {
+                    if let Some(pseudo) = &origin.pseudo {
+                        self.pseudo_origins.push((program.len() as u32, pseudo.clone()));
+                    }
+                    self.source_lines.push((program.len() as u32, origin.line));
+                }
+                let i = match self.aliases.resolve(i) {
+                    Ok(resolved) => resolved,
+                    Err(e) => {
+                        self.errors.push(e);
+                        self.current_instruction += 1;
+                        continue;
+                    }
+                };
+                for (position, name) in i.label_usages() {
+                    self.process_label_usage(&name, program.len() as u32 + position);
+                }
                 let mut bytes = i.to_bytes(&self.symbols);
                 program.append(&mut bytes);
             }
@@ -144,13 +368,166 @@ impl Assembler {
             }
         };
 
+        // A `.global` may have been seen before the label itself, in which case the symbol already exists
+        // but has not been defined yet
+        let (visibility, version) = match self.symbols.get(&name) {
+            Some(existing) if existing.is_defined() || existing.is_extern() => {
+                self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+                return;
+            },
+            Some(existing) => (existing.visibility, existing.version),
+            None => (Visibility::Local, None)
+        };
+
+        let mut symbol = if i.is_opcode() {
+            let mut symbol = Symbol::new_with_offset(name, SymbolType::CodeLabel, self.code_offset);
+            symbol.section = Some(SymbolSection::Code);
+            symbol
+        } else if i.get_directive_name().as_deref() == Some("asciiz") {
+            let mut symbol = Symbol::new(name, SymbolType::IrString);
+            symbol.section = Some(SymbolSection::ReadOnly);
+            symbol
+        } else {
+            let mut symbol = Symbol::new(name, SymbolType::DataLabel);
+            symbol.section = match self.current_section {
+                Some(AssemblerSection::Code { .. }) => Some(SymbolSection::Code),
+                _ => Some(SymbolSection::Data)
+            };
+            symbol
+        };
+        symbol.visibility = visibility;
+        symbol.version = version;
+        symbol.defined_at = Some(self.current_instruction);
+        self.symbols.add_symbol(symbol);
+    }
+
+    /// Every `@label` operand needs a relocation, since the final address of the label is only known
+    /// once the linker has placed all sections
+    fn process_label_usage(&mut self, name: &str, offset: u32) {
+        if !self.symbols.has_symbol(name) {
+            self.errors.push(AssemblerError::UndefinedSymbol{ name: name.to_string() });
+            return;
+        }
+        self.relocations.push(Relocation { offset, symbol: name.to_string() });
+    }
+
+    /// Handles `.global` and `.extern`, which only change the visibility of a symbol
+    fn process_visibility_directive(&mut self, i: &AssemblerInstruction, visibility: Visibility) {
+        if self.phase != AssemblerPhase::First { return; };
+
+        let name = match i.get_symbol_operand() {
+            Some(name) => name,
+            None => {
+                self.errors.push(AssemblerError::ParseError{ error: format!("Expected a symbol name after {:?}", i.get_directive_name()) });
+                return;
+            }
+        };
+
+        match self.symbols.get_mut(&name) {
+            Some(existing) => {
+                if existing.is_import() || (visibility == Visibility::Extern && existing.is_defined()) {
+                    self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+                    return;
+                }
+                existing.visibility = visibility;
+                existing.version = i.get_version_operand();
+            },
+            None => {
+                let mut symbol = Symbol::new(name, SymbolType::CodeLabel);
+                symbol.visibility = visibility;
+                symbol.version = i.get_version_operand();
+                self.symbols.add_symbol(symbol);
+            }
+        }
+    }
+
+    /// Handles `.import module.symbol`, which makes `symbol` callable with `callx` once the VM has
+    /// loaded `module` at runtime
+    fn process_import_directive(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First { return; };
+
+        let (module, name) = match i.get_symbol_operand() {
+            Some(path) => match path.find('.') {
+                Some(dot) => (path[..dot].to_string(), path[dot + 1..].to_string()),
+                None => {
+                    self.errors.push(AssemblerError::ParseError{ error: format!("Expected module.symbol after .import, found {}", path) });
+                    return;
+                }
+            },
+            None => {
+                self.errors.push(AssemblerError::ParseError{ error: "Expected module.symbol after .import".to_string() });
+                return;
+            }
+        };
+
         if self.symbols.has_symbol(&name) {
             self.errors.push(AssemblerError::SymbolAlreadyDeclared);
             return;
         }
 
-        let symbol = Symbol::new(name, SymbolType::Label);
+        let mut symbol = Symbol::new(name.clone(), SymbolType::Import);
+        symbol.visibility = Visibility::Extern;
+        symbol.version = i.get_version_operand();
+        symbol.defined_at = Some(self.current_instruction);
         self.symbols.add_symbol(symbol);
+        self.imports.push(Import { module, symbol: name, version: i.get_version_operand() });
+    }
+
+    /// Handles `.include <std/...>`. The bundled module is assembled on its own so its labels cannot
+    /// clash with ours, and everything it exports is declared `.extern` here for the linker to resolve.
+    fn process_include_directive(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First { return; };
+
+        let name = i.get_symbol_operand().unwrap_or_default();
+        if self.includes.contains(&name) {
+            return;
+        }
+        let object = match stdlib::assemble(&name) {
+            Ok(object) => object,
+            Err(error) => {
+                self.errors.push(AssemblerError::IncludeError{ name, error });
+                return;
+            }
+        };
+
+        for export in object.exports() {
+            if self.symbols.has_symbol(&export.name) {
+                self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+                continue;
+            }
+            let mut symbol = Symbol::new(export.name.clone(), export.symbol_type);
+            symbol.visibility = Visibility::Extern;
+            self.symbols.add_symbol(symbol);
+        }
+        self.includes.push(name);
+    }
+
+    /// Every symbol that is exported has to be defined somewhere in this module
+    fn check_globals_defined(&mut self) {
+        for symbol in self.symbols.exported() {
+            if !symbol.is_defined() {
+                self.errors.push(AssemblerError::UndefinedSymbol{ name: symbol.name.clone() });
+            }
+        }
+    }
+
+    /// A code label spans everything up to the next code label, or the end of the code
+    fn size_code_labels(&mut self) {
+        let mut offsets: Vec<(String, u32)> = self.symbols.iter()
+            .filter(|s| s.symbol_type == SymbolType::CodeLabel && s.is_defined())
+            .map(|s| (s.name.clone(), s.offset.unwrap_or(0)))
+            .collect();
+        offsets.sort_by_key(|(_, offset)| *offset);
+
+        for (index, (name, offset)) in offsets.iter().enumerate() {
+            let end = match offsets.get(index + 1) {
+                Some((_, next)) => *next,
+                None => self.code_offset
+            };
+            if let Some(symbol) = self.symbols.get_mut(name) {
+                symbol.size = end - offset;
+            }
+        }
     }
 
     fn process_directive(&mut self, i: &AssemblerInstruction) {
@@ -159,7 +536,7 @@ impl Assembler {
                 name
             },
             None => {
-                println!("Directive has an invalid name: {:?}", i);
+                warn!("Directive has an invalid name: {:?}", i);
                 return;
             }
         };
@@ -169,9 +546,28 @@ impl Assembler {
                 "asciiz" => {
                     self.handle_asciiz(i);
                 },
+                "global" => {
+                    self.process_visibility_directive(i, Visibility::Global);
+                },
+                "extern" => {
+                    self.process_visibility_directive(i, Visibility::Extern);
+                },
+                "import" => {
+                    self.process_import_directive(i);
+                },
+                "include" => {
+                    self.process_include_directive(i);
+                },
+                "alias" | "unalias" => {
+                    // Aliases only matter while encoding, so they are applied in the second phase
+                    if self.phase == AssemblerPhase::Second {
+                        if let Err(e) = self.aliases.process_directive(i) {
+                            self.errors.push(e);
+                        }
+                    }
+                },
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound{ directive: directive_name.clone() });
-                    return;
                 }
             }
         } else {
@@ -182,7 +578,7 @@ impl Assembler {
     fn process_section_header(&mut self, header_name: &str) {
         let new_section: AssemblerSection = header_name.into();
         if new_section == AssemblerSection::Unknown {
-            println!("Found a section  header is unknown: {:?}", header_name);
+            warn!("Found a section header that is unknown: {:?}", header_name);
             return;
         }
 
@@ -196,9 +592,14 @@ impl Assembler {
         match i.get_string_constant() {
             Some(s) => {
                 match i.get_label_name() {
-                    Some(name) => { self.symbols.set_symbol_offset(&name, self.ro_offset); },
+                    Some(name) => {
+                        if let Some(symbol) = self.symbols.get_mut(&name) {
+                            symbol.offset = Some(self.ro_offset);
+                            symbol.size = s.len() as u32 + 1;
+                        }
+                    },
                     None => {
-                        println!("Found a string constant with no associated label!");
+                        warn!("Found a string constant with no associated label!");
                         return;
                     }
                 };
@@ -212,38 +613,30 @@ impl Assembler {
                 self.ro_offset += 1;
             },
             None => {
-                println!("String constant following an .asciiz was empty");
+                warn!("String constant following an .asciiz was empty");
             }
         }
     }
-
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in &PIE_HEADER_PREFIX {
-            header.push(byte.clone());
-        }
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
-        }
-
-        println!("Header length: {}", header.len());
-        header
-    }
 }
 
-impl Default for AssemblerPhase {
-    fn default() -> AssemblerPhase {
-        AssemblerPhase::First
+/// Builds the header of an executable. The read only section, data section, export table and
+/// import table follow it in that order, then the code.
+pub fn pie_header(ro_length: u32, data_length: u32, exports_length: u32, imports_length: u32) -> Vec<u8> {
+    let mut header = vec![];
+    for byte in &PIE_HEADER_PREFIX {
+        header.push(*byte);
     }
-}
-
-impl Default for AssemblerSection {
-    fn default() -> Self {
-        AssemblerSection::Unknown
+    header.write_u32::<LittleEndian>(ro_length).unwrap();
+    header.write_u32::<LittleEndian>(data_length).unwrap();
+    header.write_u32::<LittleEndian>(exports_length).unwrap();
+    header.write_u32::<LittleEndian>(imports_length).unwrap();
+    while header.len() < PIE_HEADER_LENGTH {
+        header.push(0);
     }
+    header
 }
 
-impl<'a> From<&'a str> for AssemblerSection {
+impl From<&str> for AssemblerSection {
     fn from(name: &str) -> AssemblerSection {
         match name {
             "data" => {
@@ -261,14 +654,16 @@ impl<'a> From<&'a str> for AssemblerSection {
 
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::bool_assert_comparison)]
     use super::*;
+    use super::program_parsers::program;
     use crate::vm::VM;
     #[test]
     fn test_symbol_table() {
         let mut sym = SymbolTable::default();
-        let new_symbol = Symbol::new_with_offset("test".to_string(), SymbolType::Label, 12);
+        let new_symbol = Symbol::new_with_offset("test".to_string(), SymbolType::CodeLabel, 12);
         sym.add_symbol(new_symbol);
-        assert_eq!(sym.symbols.len(), 1);
+        assert_eq!(sym.len(), 1);
         let v = sym.symbol_value("test");
         assert_eq!(true, v.is_some());
         let v = v.unwrap();
@@ -280,7 +675,7 @@ mod tests {
     #[test]
     fn test_assemble_program() {
         let mut asm = Assembler::new();
-        let test_string = ".data\n.code\nload $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
+        let test_string = ".data\n.code\nload $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\ndjmpe @test\nhlt";
         // let test_string = "test: inc $0\njmp test";
         println!("Attempting to assemble: {:?}", test_string);
         let program = asm.assemble(test_string).unwrap();
@@ -328,4 +723,168 @@ mod tests {
         asm.process_first_phase(&p);
         assert_eq!(asm.errors.len(), 0);
     }
+
+    #[test]
+    fn test_typed_symbols() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nhello: .asciiz 'Hello'\n.code\nload $0 #1\nloop: inc $0\nhlt";
+        assert!(asm.assemble(test_string).is_ok());
+
+        let hello = asm.symbols.get("hello").unwrap();
+        assert_eq!(hello.symbol_type, SymbolType::IrString);
+        assert_eq!(hello.section, Some(SymbolSection::ReadOnly));
+        assert_eq!(hello.offset, Some(0));
+        assert_eq!(hello.size, 6);
+        assert_eq!(hello.defined_at, Some(1));
+
+        let label = asm.symbols.get("loop").unwrap();
+        assert_eq!(label.symbol_type, SymbolType::CodeLabel);
+        assert_eq!(label.section, Some(SymbolSection::Code));
+        assert_eq!(label.offset, Some(4));
+        assert_eq!(label.size, 8);
+        assert_eq!(label.visibility, Visibility::Local);
+    }
+
+    #[test]
+    fn test_global_and_extern_symbols() {
+        let mut asm = Assembler::new();
+        let test_string = ".global main\n.extern print\n.data\n.code\nmain: load $0 #1\nhlt";
+        assert!(asm.assemble(test_string).is_ok());
+        assert!(asm.symbols.get("main").unwrap().is_global());
+        assert!(asm.symbols.get("main").unwrap().is_defined());
+        assert!(asm.symbols.get("print").unwrap().is_extern());
+        assert_eq!(asm.symbols.exported().len(), 1);
+    }
+
+    #[test]
+    fn test_undefined_global() {
+        let mut asm = Assembler::new();
+        let test_string = ".global missing\n.data\n.code\nhlt";
+        assert!(asm.assemble(test_string).is_err());
+    }
+
+    #[test]
+    fn test_extern_redefined_locally() {
+        let mut asm = Assembler::new();
+        let test_string = ".extern print\n.data\n.code\nprint: hlt";
+        assert!(asm.assemble(test_string).is_err());
+    }
+
+    #[test]
+    fn test_register_aliases() {
+        let mut asm = Assembler::new();
+        let object = asm.assemble_object(".data\n.code\n.alias counter $5\ninc $counter\nload $sp #8\n.unalias counter\nhlt").unwrap();
+        assert_eq!(object.code, vec![18, 5, 0, 0, 0, 30, 0, 8, 5, 0, 0, 0]);
+
+        let mut asm = Assembler::new();
+        let errors = asm.assemble_object(".data\n.code\n.alias counter $5\n.unalias counter\ninc $counter").unwrap_err();
+        assert_eq!(errors[0].to_string(), "There is no register or register alias named $counter");
+
+        let mut asm = Assembler::new();
+        assert!(asm.assemble_object(".data\n.code\n.alias sp $5\nhlt").is_err());
+        // Otherwise `mov` could be handed `$at` under another name
+        let mut asm = Assembler::new();
+        let errors = asm.assemble_object(".data\n.code\n.alias scratch $31\nmov $scratch $t0\nhlt").unwrap_err();
+        assert!(matches!(errors[0], AssemblerError::InvalidRegisterAlias{ .. }));
+        let mut asm = Assembler::new();
+        assert!(asm.assemble_object(".data\n.code\n.alias scratch $at\nmov $t0 $scratch\nhlt").is_err());
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let mut asm = Assembler::new();
+        let source = ".data\n.code\nli $t0 #-70000\nclr $t1\nli $t2 #3\n\
+                      loop: beq $t1 $t2 @done\ninc $t1\nb @loop\ndone: mov $a0 $t1\nneg $a1 $t0\nhlt";
+        let program = asm.assemble(source).unwrap();
+        let mut vm = VM::default();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers[4], -70000);
+        assert_eq!(vm.registers[0], 3);
+        assert_eq!(vm.registers[1], 70000);
+
+        // `li $t0 #-70000` expands to eight instructions, so `loop` is pushed back by all of them
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(40));
+        let object = Assembler::new().assemble_object(source).unwrap();
+        let listing = asm.listing(&object.code);
+        assert!(listing[9].starts_with("0036: load $t2 #3"));
+        assert!(listing[9].ends_with("; li $t2 #3"));
+        assert!(listing[13].ends_with("; b @loop"));
+        assert!(!listing[12].contains(";"));
+    }
+
+    #[test]
+    fn test_integer_operand_limits() {
+        let program = Assembler::new().assemble(".data\n.code\nli $t0 #2147483647\nli $t1 #-2147483648\nload $t2 #65535\nhlt").unwrap();
+        let mut vm = VM::default();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers[4], i32::MAX);
+        assert_eq!(vm.registers[5], i32::MIN);
+        assert_eq!(vm.registers[6], 65535);
+
+        let errors = Assembler::new().assemble_object(".data\n.code\nli $0 #5000000000\nhlt").unwrap_err();
+        assert!(matches!(errors[0], AssemblerError::InvalidPseudoInstruction{ .. }));
+        assert!(errors[0].to_string().contains("operand 2 is 5000000000"));
+        assert!(Assembler::new().assemble_object(".data\n.code\nli $0 #-2147483649\nhlt").is_err());
+        assert!(Assembler::new().assemble_object(".data\n.code\nli $0 #50000000000000000000\nhlt").is_err());
+
+        let errors = Assembler::new().assemble_object(".data\n.code\nload $0 #70000\nhlt").unwrap_err();
+        assert!(matches!(errors[0], AssemblerError::InvalidOperands{ .. }));
+        assert!(errors[0].to_string().contains("operand 2 is 70000"));
+        assert!(Assembler::new().assemble_object(".data\n.code\nload $0 #-1\nhlt").is_err());
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let errors = Assembler::new().assemble_object(".data\n.code\nload $300 #1\nhlt").unwrap_err();
+        assert!(matches!(errors[0], AssemblerError::ParseError{ .. }));
+        assert!(Assembler::new().assemble_object(".data\n.code\nload $32 #1\nhlt").is_err());
+    }
+
+    #[test]
+    fn test_optimizer_keeps_labels_correct() {
+        let source = ".data\n.code\nclr $t0\nli $t1 #3\nnop\nb @loop\nloop: inc $t0\nmov $t0 $t0\nbne $t0 $t1 @loop\nhlt";
+        let mut plain = Assembler::new();
+        let unoptimized = plain.assemble_object(source).unwrap();
+
+        let mut asm = Assembler::new();
+        asm.optimize = true;
+        let program = asm.assemble(source).unwrap();
+        let rules: Vec<&str> = asm.rewrites.iter().map(|r| r.rule).collect();
+        assert_eq!(rules, vec!["remove-nop", "jump-to-next", "add-zero"]);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(8));
+        assert_eq!(plain.symbols.symbol_value("loop"), Some(20));
+        assert_eq!(unoptimized.code.len(), 44);
+
+        let mut vm = VM::default();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers[4], 3);
+
+        let mut asm = Assembler::new();
+        asm.optimize = true;
+        asm.assemble(".data\n.code\nnop\nload $t0 #4\njmpf $t0\nhlt").unwrap();
+        assert!(asm.rewrites.is_empty());
+        assert_eq!(asm.optimizer_report(), vec!["optimizer skipped: relative jumps present"]);
+    }
+
+    #[test]
+    fn test_unknown_mnemonic() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".data\n.code\nlod $0 #1\nhlt").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "Unknown mnemonic `lod`, did you mean `load`?");
+
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".data\n.code\nfrobnicate\nhlt").unwrap_err();
+        assert_eq!(errors[0].to_string(), "Unknown mnemonic `frobnicate`");
+    }
+
+    #[test]
+    fn test_unparsed_input() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".data\n.code\nload $0 #1\n% oops\nhlt").unwrap_err();
+        assert_eq!(errors[0].to_string(), "There was an error parsing the code: unable to parse `% oops`");
+    }
 }
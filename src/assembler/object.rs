@@ -0,0 +1,406 @@
+use std::fmt;
+use std::error::Error;
+use std::io::{Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::assembler::symbols::{Symbol, SymbolSection, SymbolTable, SymbolType, Visibility};
+
+pub const OBJECT_HEADER_PREFIX: [u8; 4] = [45, 73, 82, 79];
+pub const OBJECT_VERSION: u8 = 4;
+
+/// A relocatable module produced by the assembler. Addresses inside `code` are relative to the start
+/// of the section the symbol lives in until the linker patches them using `relocations`.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectFile {
+    /// Bytecode of the module, without a PIE header
+    pub code: Vec<u8>,
+    /// Read only data such as `.asciiz` strings
+    pub ro: Vec<u8>,
+    /// Every symbol the module declares, including the ones it exports and imports
+    pub symbols: SymbolTable,
+    /// Places in `code` that refer to a symbol and need its final address
+    pub relocations: Vec<Relocation>,
+    /// Symbols the module expects a shared module to provide at load time
+    pub imports: Vec<Import>,
+    /// Bundled standard library modules pulled in with `.include`, which the linker adds for us
+    pub includes: Vec<String>
+}
+
+/// A 16 bit operand at `offset` in the code section that has to be replaced with the address of `symbol`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: u32,
+    pub symbol: String
+}
+
+/// A symbol that is looked up in a shared module when the program is loaded, declared with
+/// `.import module.symbol`. A version of `None` accepts any version of the export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub module: String,
+    pub symbol: String,
+    pub version: Option<u16>
+}
+
+/// A symbol a linked program makes available to programs that import it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub version: Option<u16>,
+    /// Offset of the symbol from the start of the program, including the header
+    pub address: u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectFileError {
+    BadHeader,
+    UnsupportedVersion{ version: u8 },
+    Truncated,
+    InvalidSymbol{ name: String }
+}
+
+impl ObjectFile {
+    /// Returns the symbols exported with `.global`
+    pub fn exports(&self) -> Vec<&Symbol> {
+        self.symbols.exported()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&OBJECT_HEADER_PREFIX);
+        bytes.push(OBJECT_VERSION);
+
+        for section in &[&self.code, &self.ro] {
+            bytes.write_u32::<LittleEndian>(section.len() as u32).unwrap();
+        }
+        for section in &[&self.code, &self.ro] {
+            bytes.extend_from_slice(section);
+        }
+
+        let symbols = self.symbols.sorted();
+        bytes.write_u32::<LittleEndian>(symbols.len() as u32).unwrap();
+        for symbol in symbols {
+            write_name(&mut bytes, &symbol.name);
+            bytes.push(symbol_type_to_u8(symbol.symbol_type));
+            bytes.push(section_to_u8(symbol.section));
+            bytes.push(visibility_to_u8(symbol.visibility));
+            write_optional_u32(&mut bytes, symbol.offset);
+            bytes.write_u32::<LittleEndian>(symbol.size).unwrap();
+            write_optional_u32(&mut bytes, symbol.defined_at);
+            write_version(&mut bytes, symbol.version);
+        }
+
+        bytes.write_u32::<LittleEndian>(self.relocations.len() as u32).unwrap();
+        for relocation in &self.relocations {
+            bytes.write_u32::<LittleEndian>(relocation.offset).unwrap();
+            write_name(&mut bytes, &relocation.symbol);
+        }
+
+        bytes.append(&mut encode_imports(&self.imports));
+
+        bytes.write_u32::<LittleEndian>(self.includes.len() as u32).unwrap();
+        for include in &self.includes {
+            write_name(&mut bytes, include);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, ObjectFileError> {
+        if bytes.len() < 5 || bytes[0..4] != OBJECT_HEADER_PREFIX {
+            return Err(ObjectFileError::BadHeader);
+        }
+        if bytes[4] != OBJECT_VERSION {
+            return Err(ObjectFileError::UnsupportedVersion{ version: bytes[4] });
+        }
+
+        let mut cursor = Cursor::new(&bytes[5..]);
+        let code_len = read_u32(&mut cursor)?;
+        let ro_len = read_u32(&mut cursor)?;
+        let code = read_bytes(&mut cursor, code_len)?;
+        let ro = read_bytes(&mut cursor, ro_len)?;
+
+        let mut symbols = SymbolTable::new();
+        for _ in 0..read_u32(&mut cursor)? {
+            let name = read_name(&mut cursor)?;
+            let symbol_type = symbol_type_from_u8(read_u8(&mut cursor)?);
+            let section = section_from_u8(read_u8(&mut cursor)?);
+            let visibility = visibility_from_u8(read_u8(&mut cursor)?);
+            let (symbol_type, section, visibility) = match (symbol_type, section, visibility) {
+                (Some(t), Some(s), Some(v)) => (t, s, v),
+                _ => return Err(ObjectFileError::InvalidSymbol{ name })
+            };
+            let mut symbol = Symbol::new(name, symbol_type);
+            symbol.section = section;
+            symbol.visibility = visibility;
+            symbol.offset = read_optional_u32(&mut cursor)?;
+            symbol.size = read_u32(&mut cursor)?;
+            symbol.defined_at = read_optional_u32(&mut cursor)?;
+            symbol.version = read_version(&mut cursor)?;
+            symbols.add_symbol(symbol);
+        }
+
+        let mut relocations = vec![];
+        for _ in 0..read_u32(&mut cursor)? {
+            let offset = read_u32(&mut cursor)?;
+            let symbol = read_name(&mut cursor)?;
+            relocations.push(Relocation { offset, symbol });
+        }
+
+        let imports = read_imports(&mut cursor)?;
+
+        let mut includes = vec![];
+        for _ in 0..read_u32(&mut cursor)? {
+            includes.push(read_name(&mut cursor)?);
+        }
+        Ok(ObjectFile { code, ro, symbols, relocations, imports, includes })
+    }
+}
+
+pub fn encode_imports(imports: &[Import]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.write_u32::<LittleEndian>(imports.len() as u32).unwrap();
+    for import in imports {
+        write_name(&mut bytes, &import.module);
+        write_name(&mut bytes, &import.symbol);
+        write_version(&mut bytes, import.version);
+    }
+    bytes
+}
+
+pub fn decode_imports(bytes: &[u8]) -> Result<Vec<Import>, ObjectFileError> {
+    read_imports(&mut Cursor::new(bytes))
+}
+
+pub fn encode_exports(exports: &[Export]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.write_u32::<LittleEndian>(exports.len() as u32).unwrap();
+    for export in exports {
+        write_name(&mut bytes, &export.name);
+        write_version(&mut bytes, export.version);
+        bytes.write_u32::<LittleEndian>(export.address).unwrap();
+    }
+    bytes
+}
+
+pub fn decode_exports(bytes: &[u8]) -> Result<Vec<Export>, ObjectFileError> {
+    let mut cursor = Cursor::new(bytes);
+    let mut exports = vec![];
+    for _ in 0..read_u32(&mut cursor)? {
+        let name = read_name(&mut cursor)?;
+        let version = read_version(&mut cursor)?;
+        let address = read_u32(&mut cursor)?;
+        exports.push(Export { name, version, address });
+    }
+    Ok(exports)
+}
+
+fn read_imports(cursor: &mut Cursor<&[u8]>) -> Result<Vec<Import>, ObjectFileError> {
+    let mut imports = vec![];
+    for _ in 0..read_u32(cursor)? {
+        let module = read_name(cursor)?;
+        let symbol = read_name(cursor)?;
+        let version = read_version(cursor)?;
+        imports.push(Import { module, symbol, version });
+    }
+    Ok(imports)
+}
+
+/// Versions are stored as a u16 where zero means unversioned
+fn write_version(bytes: &mut Vec<u8>, version: Option<u16>) {
+    bytes.write_u16::<LittleEndian>(version.unwrap_or(0)).unwrap();
+}
+
+fn read_version(cursor: &mut Cursor<&[u8]>) -> Result<Option<u16>, ObjectFileError> {
+    match cursor.read_u16::<LittleEndian>().map_err(|_| ObjectFileError::Truncated)? {
+        0 => Ok(None),
+        v => Ok(Some(v))
+    }
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn write_optional_u32(bytes: &mut Vec<u8>, value: Option<u32>) {
+    match value {
+        Some(v) => {
+            bytes.push(1);
+            bytes.write_u32::<LittleEndian>(v).unwrap();
+        },
+        None => {
+            bytes.push(0);
+        }
+    }
+}
+
+fn read_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8, ObjectFileError> {
+    cursor.read_u8().map_err(|_| ObjectFileError::Truncated)
+}
+
+fn read_u32(cursor: &mut Cursor<&[u8]>) -> Result<u32, ObjectFileError> {
+    cursor.read_u32::<LittleEndian>().map_err(|_| ObjectFileError::Truncated)
+}
+
+fn read_optional_u32(cursor: &mut Cursor<&[u8]>) -> Result<Option<u32>, ObjectFileError> {
+    match read_u8(cursor)? {
+        0 => Ok(None),
+        _ => Ok(Some(read_u32(cursor)?))
+    }
+}
+
+fn read_bytes(cursor: &mut Cursor<&[u8]>, len: u32) -> Result<Vec<u8>, ObjectFileError> {
+    // A damaged length could ask for far more than the file holds, so check before allocating
+    let remaining = cursor.get_ref().len().saturating_sub(cursor.position() as usize);
+    if len as usize > remaining {
+        return Err(ObjectFileError::Truncated);
+    }
+    let mut buffer = vec![0; len as usize];
+    cursor.read_exact(&mut buffer).map_err(|_| ObjectFileError::Truncated)?;
+    Ok(buffer)
+}
+
+fn read_name(cursor: &mut Cursor<&[u8]>) -> Result<String, ObjectFileError> {
+    let len = cursor.read_u16::<LittleEndian>().map_err(|_| ObjectFileError::Truncated)?;
+    let bytes = read_bytes(cursor, u32::from(len))?;
+    String::from_utf8(bytes).map_err(|e| ObjectFileError::InvalidSymbol{ name: String::from_utf8_lossy(e.as_bytes()).to_string() })
+}
+
+fn symbol_type_to_u8(t: SymbolType) -> u8 {
+    match t {
+        SymbolType::CodeLabel => 0,
+        SymbolType::DataLabel => 1,
+        SymbolType::IrString => 3,
+        SymbolType::Import => 4
+    }
+}
+
+fn symbol_type_from_u8(v: u8) -> Option<SymbolType> {
+    match v {
+        0 => Some(SymbolType::CodeLabel),
+        1 => Some(SymbolType::DataLabel),
+        3 => Some(SymbolType::IrString),
+        4 => Some(SymbolType::Import),
+        _ => None
+    }
+}
+
+fn section_to_u8(s: Option<SymbolSection>) -> u8 {
+    match s {
+        None => 0,
+        Some(SymbolSection::Code) => 1,
+        Some(SymbolSection::Data) => 2,
+        Some(SymbolSection::ReadOnly) => 3
+    }
+}
+
+fn section_from_u8(v: u8) -> Option<Option<SymbolSection>> {
+    match v {
+        0 => Some(None),
+        1 => Some(Some(SymbolSection::Code)),
+        2 => Some(Some(SymbolSection::Data)),
+        3 => Some(Some(SymbolSection::ReadOnly)),
+        _ => None
+    }
+}
+
+fn visibility_to_u8(v: Visibility) -> u8 {
+    match v {
+        Visibility::Local => 0,
+        Visibility::Global => 1,
+        Visibility::Extern => 2
+    }
+}
+
+fn visibility_from_u8(v: u8) -> Option<Visibility> {
+    match v {
+        0 => Some(Visibility::Local),
+        1 => Some(Visibility::Global),
+        2 => Some(Visibility::Extern),
+        _ => None
+    }
+}
+
+impl fmt::Display for ObjectFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectFileError::BadHeader => {
+                f.write_str("File is not an Iridium object file")
+            },
+            ObjectFileError::UnsupportedVersion{ version } => {
+                f.write_str(&format!("Unsupported object file version: {}", version))
+            },
+            ObjectFileError::Truncated => {
+                f.write_str("Object file ended unexpectedly")
+            },
+            ObjectFileError::InvalidSymbol{ name } => {
+                f.write_str(&format!("Object file contains an invalid symbol: {}", name))
+            }
+        }
+    }
+}
+
+impl Error for ObjectFileError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_round_trip() {
+        let mut symbols = SymbolTable::new();
+        let mut main = Symbol::new_with_offset("main".to_string(), SymbolType::CodeLabel, 0);
+        main.section = Some(SymbolSection::Code);
+        main.visibility = Visibility::Global;
+        main.size = 8;
+        main.version = Some(2);
+        let mut print = Symbol::new("print".to_string(), SymbolType::CodeLabel);
+        print.visibility = Visibility::Extern;
+        symbols.add_symbol(main);
+        symbols.add_symbol(print);
+
+        let object = ObjectFile {
+            code: vec![0, 0, 0, 1, 20, 0, 0, 0],
+            ro: vec![72, 105, 0],
+            symbols,
+            relocations: vec![Relocation { offset: 5, symbol: "print".to_string() }],
+            imports: vec![Import { module: "io".to_string(), symbol: "print".to_string(), version: None }],
+            includes: vec!["std/mem".to_string()]
+        };
+
+        let decoded = ObjectFile::from_bytes(&object.to_bytes()).unwrap();
+        assert_eq!(decoded.code, object.code);
+        assert_eq!(decoded.ro, object.ro);
+        assert_eq!(decoded.relocations, object.relocations);
+        assert_eq!(decoded.imports, object.imports);
+        assert_eq!(decoded.includes, object.includes);
+        assert_eq!(decoded.symbols.get("main"), object.symbols.get("main"));
+        assert_eq!(decoded.symbols.get("print"), object.symbols.get("print"));
+        assert_eq!(decoded.exports().len(), 1);
+    }
+
+    #[test]
+    fn test_export_table_round_trip() {
+        let exports = vec![
+            Export { name: "sqrt".to_string(), version: Some(2), address: 68 },
+            Export { name: "abs".to_string(), version: None, address: 80 }
+        ];
+        assert_eq!(decode_exports(&encode_exports(&exports)).unwrap(), exports);
+    }
+
+    #[test]
+    fn test_bad_object() {
+        assert_eq!(ObjectFile::from_bytes(&[1, 2, 3]).unwrap_err(), ObjectFileError::BadHeader);
+        let mut bytes = OBJECT_HEADER_PREFIX.to_vec();
+        bytes.push(OBJECT_VERSION);
+        bytes.push(12);
+        assert_eq!(ObjectFile::from_bytes(&bytes).unwrap_err(), ObjectFileError::Truncated);
+
+        // A code section claiming to be 4GB long is rejected without allocating it
+        let mut bytes = OBJECT_HEADER_PREFIX.to_vec();
+        bytes.push(OBJECT_VERSION);
+        bytes.extend_from_slice(&[255, 255, 255, 255, 0, 0, 0, 0]);
+        assert_eq!(ObjectFile::from_bytes(&bytes).unwrap_err(), ObjectFileError::Truncated);
+    }
+}
@@ -3,18 +3,63 @@ use nom::types::CompleteStr;
 
 use crate::assembler::Token;
 use crate::assembler::Opcode;
+use crate::assembler::pseudo_instructions::{PseudoOp, PSEUDO_OPS};
+use crate::instruction::OPCODES;
 
+/// Every mnemonic the assembler understands, real and pseudo, used to suggest fixes for typos
+pub fn mnemonics() -> Vec<&'static str> {
+    OPCODES.iter()
+        .filter(|info| info.opcode != Opcode::IGL)
+        .map(|info| info.mnemonic)
+        .chain(PSEUDO_OPS.iter().map(|op| op.name()))
+        .collect()
+}
+
+// Words that are not a known mnemonic are kept as `UnknownOp`, so the assembler can report them
+// with a suggestion rather than encoding an illegal instruction
 named!(pub opcode<CompleteStr, Token>,
     do_parse!(
         opcode: alpha1 >>
         (
-            Token::Op{code: Opcode::from(opcode)}
+            match PseudoOp::from_name(&opcode) {
+                Some(op) => Token::PseudoOp{ op },
+                None => match Opcode::from_mnemonic(&opcode) {
+                    Some(code) => Token::Op{ code },
+                    None => Token::UnknownOp{ name: opcode.to_string() }
+                }
+            }
         )
     )
 );
 
+/// Returns the known mnemonic closest to a word, if it is close enough to be a likely typo
+pub fn closest_mnemonic(word: &str) -> Option<&'static str> {
+    let word = word.to_lowercase();
+    let (distance, mnemonic) = mnemonics().into_iter()
+        .map(|m| (edit_distance(&word, m), m))
+        .min_by_key(|(distance, _)| *distance)?;
+    // Short words are only a couple of edits away from most other short words
+    let limit = if word.len() <= 3 { 1 } else { 2 };
+    if distance <= limit { Some(mnemonic) } else { None }
+}
+
+/// Levenshtein distance between two words
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 mod tests {
-    #![allow(unused_imports)]
+    #![allow(unused_imports, clippy::bool_assert_comparison)]
     use super::*;
 
     #[test]
@@ -28,6 +73,27 @@ mod tests {
 
         let result = opcode(CompleteStr("aold"));
         let (_, token) = result.unwrap();
-        assert_eq!(token, Token::Op{ code: Opcode::IGL });
+        assert_eq!(token, Token::UnknownOp{ name: "aold".to_string() });
+
+        let (_, token) = opcode(CompleteStr("beq")).unwrap();
+        assert_eq!(token, Token::PseudoOp{ op: PseudoOp::BEQ });
+    }
+
+    #[test]
+    fn test_closest_mnemonic() {
+        assert_eq!(closest_mnemonic("lod"), Some("load"));
+        assert_eq!(closest_mnemonic("LAOD"), Some("load"));
+        assert_eq!(closest_mnemonic("jmpq"), Some("jmp"));
+        assert_eq!(closest_mnemonic("prtz"), Some("prts"));
+        assert_eq!(closest_mnemonic("xyzzy"), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_mnemonics_are_known() {
+        for mnemonic in mnemonics() {
+            let (_, token) = opcode(CompleteStr(mnemonic)).unwrap();
+            assert!(matches!(token, Token::Op{ .. } | Token::PseudoOp{ .. }), "{} is not known", mnemonic);
+        }
     }
 }
@@ -9,9 +9,14 @@ named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
+            sign: opt!(tag!("-")) >>
             reg_num: digit >>
             (
-                Token::IntegerOperand{value: reg_num.parse::<i32>().unwrap()}
+                {
+                    // Numbers too big for an i64 are kept as i64::MAX, which no operand accepts
+                    let value = reg_num.parse::<i64>().unwrap_or(i64::MAX);
+                    Token::IntegerOperand{value: if sign.is_some() { -value } else { value }}
+                }
             )
         )
     )
@@ -38,7 +43,7 @@ named!(irstring<CompleteStr, Token>,
 );
 
 mod tests {
-    #![allow(unused_imports)]
+    #![allow(unused_imports, clippy::bool_assert_comparison)]
     use super::*;
 
     #[test]
@@ -51,6 +56,9 @@ mod tests {
 
         let result = integer_operand(CompleteStr("10"));
         assert_eq!(result.is_ok(), false);
+
+        let (_, value) = integer_operand(CompleteStr("#-70000")).unwrap();
+        assert_eq!(value, Token::IntegerOperand{value: -70000});
     }
 
     #[test]
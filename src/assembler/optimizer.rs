@@ -0,0 +1,226 @@
+use std::fmt;
+
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::program_parsers::Program;
+use crate::assembler::registers::abi_register;
+use crate::assembler::Token;
+use crate::instruction::Opcode;
+
+/// A rewrite the peephole optimizer made, for the optimization report
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewrite {
+    /// Name of the rule that fired
+    pub rule: &'static str,
+    /// Position of the first rewritten instruction in the program, after pseudo-instructions were expanded
+    pub instruction: usize,
+    /// The instructions that were removed
+    pub removed: Vec<String>
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at instruction {}: removed {}", self.rule, self.instruction, self.removed.join("; "))
+    }
+}
+
+/// Removes wasteful instruction sequences, mostly left behind by pseudo-instructions. Only instructions
+/// that are next to each other in the source are considered, and a label on a removed instruction moves
+/// to the instruction that follows it, so jumps still land in the same place.
+///
+/// The rules assume `$at` holds nothing worth keeping between instructions, as it is reserved for the
+/// assembler. Addresses written as plain numbers are not adjusted, so programs should jump to labels.
+/// `origins` has one entry per instruction, saying where it came from, and is kept in step with the program.
+/// Programs `skip_reason` gives a reason for are left alone.
+pub fn optimize<T>(program: &mut Program, origins: &mut Vec<T>) -> Vec<Rewrite> {
+    let mut rewrites = vec![];
+    if skip_reason(program).is_some() {
+        return rewrites;
+    }
+    let mut index = 0;
+    while index < program.instructions.len() {
+        match find_rewrite(&program.instructions, index) {
+            Some((rule, count)) => {
+                let instructions = &mut program.instructions;
+                let label = instructions[index].label.clone();
+                let removed: Vec<AssemblerInstruction> = instructions.drain(index..index + count).collect();
+                origins.drain(index..index + count);
+                if label.is_some() {
+                    instructions[index].label = label;
+                }
+                rewrites.push(Rewrite {
+                    rule,
+                    instruction: index,
+                    removed: removed.iter().map(|i| i.to_string()).collect()
+                });
+                // Removing instructions can create a new opportunity with the one before
+                index = index.saturating_sub(1);
+            },
+            None => index += 1
+        }
+    }
+    rewrites
+}
+
+/// Why the optimizer would leave a program alone, if it would
+pub fn skip_reason(program: &Program) -> Option<&'static str> {
+    // Relative jumps count bytes, so removing anything could change where they land
+    if program.instructions.iter().any(|i| matches!(opcode(i), Some(Opcode::JMPF) | Some(Opcode::JMPB))) {
+        return Some("relative jumps present");
+    }
+    None
+}
+
+/// Returns the rule that applies to the instructions starting at `index` and how many of them it removes
+fn find_rewrite(instructions: &[AssemblerInstruction], index: usize) -> Option<(&'static str, usize)> {
+    let first = &instructions[index];
+    let second = instructions.get(index + 1);
+    let third = instructions.get(index + 2);
+
+    if opcode(first) == Some(Opcode::NOP) && can_remove(instructions, index, 1) {
+        return Some(("remove-nop", 1));
+    }
+
+    // A load whose value is overwritten before anything reads it
+    if let (Some(Opcode::LOAD), Some(next)) = (opcode(first), second) {
+        if let Some(register) = register(&first.operand1) {
+            if overwrites(next, register) && can_remove(instructions, index, 1) {
+                return Some(("dead-load", 1));
+            }
+        }
+    }
+
+    // `djmpe @next` where `next` labels the instruction that follows anyway
+    if opcode(first) == Some(Opcode::DJMPE) {
+        if let (Some(Token::LabelUsage { name }), Some(next)) = (&first.operand1, second) {
+            if next.get_label_name().as_ref() == Some(name) && can_remove(instructions, index, 1) {
+                return Some(("jump-to-next", 1));
+            }
+        }
+    }
+
+    let scratch = abi_register("at").unwrap();
+    if let (Some(second), Some(third)) = (second, third) {
+        // `load $at @next; jmp $at`, which is what `b @next` expands to
+        if opcode(first) == Some(Opcode::LOAD) && register(&first.operand1) == Some(scratch) &&
+           opcode(second) == Some(Opcode::JMP) && register(&second.operand1) == Some(scratch) {
+            if let Some(Token::LabelUsage { name }) = &first.operand2 {
+                if third.get_label_name().as_ref() == Some(name) && can_remove(instructions, index, 2) {
+                    return Some(("jump-to-next", 2));
+                }
+            }
+        }
+    }
+
+    if let Some(second) = second {
+        // `load $at #0; add $x $at $x` adds zero to a register, which is what `mov $x $x` expands to
+        if opcode(first) == Some(Opcode::LOAD) && register(&first.operand1) == Some(scratch) &&
+           first.operand2 == Some(Token::IntegerOperand { value: 0 }) &&
+           opcode(second) == Some(Opcode::ADD) && register(&second.operand2) == Some(scratch) &&
+           register(&second.operand1).is_some() && register(&second.operand1) == register(&second.operand3) &&
+           can_remove(instructions, index, 2) {
+            return Some(("add-zero", 2));
+        }
+    }
+
+    None
+}
+
+/// Instructions can only be removed if nothing jumps into the middle of them, and a label on the first
+/// one can move to the instruction after them
+fn can_remove(instructions: &[AssemblerInstruction], index: usize, count: usize) -> bool {
+    if instructions[index + 1..index + count].iter().any(|i| i.is_label()) {
+        return false;
+    }
+    if !instructions[index].is_label() {
+        return true;
+    }
+    match instructions.get(index + count) {
+        Some(next) => next.is_opcode() && !next.is_label(),
+        None => false
+    }
+}
+
+fn opcode(i: &AssemblerInstruction) -> Option<Opcode> {
+    match i.opcode {
+        Some(Token::Op { code }) => Some(code),
+        _ => None
+    }
+}
+
+/// Only registers given by number or conventional name are considered, since an alias could name any register
+fn register(token: &Option<Token>) -> Option<u8> {
+    match token {
+        Some(Token::Register { reg_num }) => Some(*reg_num),
+        _ => None
+    }
+}
+
+/// True if an instruction writes to a register without reading it first
+fn overwrites(i: &AssemblerInstruction, target: u8) -> bool {
+    match opcode(i) {
+        Some(Opcode::LOAD) => register(&i.operand1) == Some(target),
+        Some(Opcode::ADD) | Some(Opcode::SUB) | Some(Opcode::MUL) | Some(Opcode::DIV) => {
+            register(&i.operand3) == Some(target) &&
+            register(&i.operand1).is_some_and(|r| r != target) &&
+            register(&i.operand2).is_some_and(|r| r != target)
+        },
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program;
+    use nom::types::CompleteStr;
+
+    fn run(source: &str) -> (Vec<String>, Vec<Rewrite>) {
+        let (_, mut program) = program(CompleteStr(source)).unwrap();
+        let mut origins: Vec<Option<String>> = vec![None; program.instructions.len()];
+        let rewrites = optimize(&mut program, &mut origins);
+        assert_eq!(origins.len(), program.instructions.len());
+        (program.instructions.iter().map(|i| i.to_string()).collect(), rewrites)
+    }
+
+    #[test]
+    fn test_remove_nop_and_dead_load() {
+        let (instructions, rewrites) = run("start: nop\nload $t0 #1\nload $t0 #2\nadd $t1 $t1 $t1\nhlt");
+        assert_eq!(instructions, vec!["start: load $t0 #2", "add $t1 $t1 $t1", "hlt"]);
+        let rules: Vec<&str> = rewrites.iter().map(|r| r.rule).collect();
+        assert_eq!(rules, vec!["remove-nop", "dead-load"]);
+        assert_eq!(rewrites[1].removed, vec!["start: load $t0 #1"]);
+    }
+
+    #[test]
+    fn test_dead_load_kept_when_read() {
+        let (instructions, rewrites) = run("load $t0 #1\nadd $t0 $t1 $t0\nload $t2 #1\nadd $a0 $a1 $t2\nhlt");
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(rewrites.len(), 1);
+    }
+
+    #[test]
+    fn test_jump_to_next() {
+        let (instructions, _) = run("load $at @next\njmp $at\nnext: eq $a0 $a1\ndjmpe @done\ndone: hlt");
+        assert_eq!(instructions, vec!["next: eq $a0 $a1", "done: hlt"]);
+
+        // Something may jump to `jmp` with a different address in `$at`
+        let (instructions, _) = run("load $at @next\nagain: jmp $at\nnext: hlt");
+        assert_eq!(instructions.len(), 3);
+    }
+
+    #[test]
+    fn test_add_zero() {
+        let (instructions, rewrites) = run("load $at #0\nadd $t0 $at $t0\nload $at #0\nadd $t0 $at $t1\nhlt");
+        assert_eq!(instructions, vec!["load $at #0", "add $t0 $at $t1", "hlt"]);
+        assert_eq!(rewrites[0].to_string(), "add-zero at instruction 0: removed load $at #0; add $t0 $at $t0");
+    }
+
+    #[test]
+    fn test_relative_jumps_disable_optimizer() {
+        let (instructions, rewrites) = run("nop\nload $t0 #4\njmpf $t0\nhlt");
+        assert_eq!(instructions.len(), 4);
+        assert!(rewrites.is_empty());
+        let (_, program) = program(CompleteStr("nop\nload $t0 #4\njmpb $t0\nhlt")).unwrap();
+        assert_eq!(skip_reason(&program), Some("relative jumps present"));
+    }
+}
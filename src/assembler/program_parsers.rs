@@ -1,4 +1,5 @@
 use nom::types::CompleteStr;
+use nom::IResult;
 
 use crate::assembler::instruction_parsers::{AssemblerInstruction, instruction};
 use crate::assembler::directive_parsers::directive;
@@ -24,14 +25,38 @@ named!(pub program<CompleteStr, Program>,
         instructions: many1!(alt!(instruction | directive)) >>
         (
             Program {
-                instructions: instructions
+                instructions
             }
         )
     )
 );
 
+named!(statement<CompleteStr, AssemblerInstruction>, alt!(instruction | directive));
+
+/// Parses a program like `program`, along with the source line, counting from 1, that each instruction
+/// and directive starts on
+pub fn program_with_lines(input: CompleteStr) -> IResult<CompleteStr, (Program, Vec<u32>)> {
+    let mut instructions = vec![];
+    let mut lines = vec![];
+    let mut rest = input;
+    loop {
+        let start = input.len() - rest.trim_start().len();
+        match statement(rest) {
+            Ok((remaining, instruction)) if remaining.len() < rest.len() => {
+                instructions.push(instruction);
+                lines.push(1 + input[..start].matches('\n').count() as u32);
+                rest = remaining;
+            },
+            Err(e) if instructions.is_empty() => return Err(e),
+            _ => break
+        }
+    }
+    Ok((rest, (Program { instructions }, lines)))
+}
+
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::bool_assert_comparison)]
     use super::*;
 
     #[test]
@@ -46,6 +71,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_program_with_lines() {
+        let (leftover, (p, lines)) = program_with_lines(CompleteStr(".data\n.code\n\nload $0 #100\nloop: inc $0\n  hlt\n")).unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(p.instructions.len(), 5);
+        assert_eq!(lines, vec![1, 2, 4, 5, 6]);
+        assert!(program_with_lines(CompleteStr("$0 load")).is_err());
+    }
+
     #[test]
     fn test_program_to_bytes() {
         println!("before program");
@@ -0,0 +1,219 @@
+use std::convert::TryFrom;
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::registers::abi_register;
+use crate::assembler::Token;
+use crate::instruction::Opcode;
+
+/// Instructions the assembler accepts that have no opcode of their own and are expanded into one
+/// or more real instructions. Those that need a scratch register use `$at`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PseudoOp {
+    /// `mov $dst $src` copies a register
+    MOV,
+    /// `li $dst #value` loads any 32 bit constant, including negative ones
+    LI,
+    /// `b @label` jumps to a label
+    B,
+    /// `beq $a $b @label` jumps to a label if two registers are equal
+    BEQ,
+    /// `bne $a $b @label` jumps to a label if two registers are not equal
+    BNE,
+    /// `neg $dst [$src]` negates a register
+    NEG,
+    /// `not $dst [$src]` flips every bit of a register
+    NOT,
+    /// `clr $dst` sets a register to zero
+    CLR
+}
+
+pub const PSEUDO_OPS: [PseudoOp; 8] = [
+    PseudoOp::MOV, PseudoOp::LI, PseudoOp::B, PseudoOp::BEQ, PseudoOp::BNE, PseudoOp::NEG, PseudoOp::NOT, PseudoOp::CLR
+];
+
+impl PseudoOp {
+    pub fn from_name(name: &str) -> Option<PseudoOp> {
+        PSEUDO_OPS.iter().find(|op| op.name() == name).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PseudoOp::MOV => "mov",
+            PseudoOp::LI => "li",
+            PseudoOp::B => "b",
+            PseudoOp::BEQ => "beq",
+            PseudoOp::BNE => "bne",
+            PseudoOp::NEG => "neg",
+            PseudoOp::NOT => "not",
+            PseudoOp::CLR => "clr"
+        }
+    }
+}
+
+/// Expands a pseudo-instruction into the real instructions it stands for. Real instructions are
+/// returned unchanged. A label on the pseudo-instruction stays on the first instruction it expands to.
+pub fn expand(i: &AssemblerInstruction) -> Result<Vec<AssemblerInstruction>, AssemblerError> {
+    let op = match i.opcode {
+        Some(Token::PseudoOp { op }) => op,
+        _ => return Ok(vec![i.clone()])
+    };
+    let invalid = |error: &str| AssemblerError::InvalidPseudoInstruction{ instruction: i.to_string(), error: error.to_string() };
+    let operands: Vec<&Token> = [&i.operand1, &i.operand2, &i.operand3].iter().copied().flatten().collect();
+    let register = |index: usize| match operands.get(index) {
+        Some(token @ Token::Register { .. }) | Some(token @ Token::RegisterAlias { .. }) => Ok((*token).clone()),
+        _ => Err(invalid(&format!("operand {} has to be a register", index + 1)))
+    };
+    let label = |index: usize| match operands.get(index) {
+        Some(token @ Token::LabelUsage { .. }) => Ok((*token).clone()),
+        _ => Err(invalid(&format!("operand {} has to be a label", index + 1)))
+    };
+    let expected = |count: usize| if operands.len() == count {
+        Ok(())
+    } else {
+        Err(invalid(&format!("expected {} operands but found {}", count, operands.len())))
+    };
+
+    let scratch = Token::Register { reg_num: abi_register("at").unwrap() };
+    if operands.contains(&&scratch) && op != PseudoOp::CLR {
+        return Err(invalid("$at is used as scratch space by pseudo-instructions and cannot be an operand"));
+    }
+
+    let mut expanded = match op {
+        PseudoOp::MOV => {
+            expected(2)?;
+            vec![
+                real(Opcode::LOAD, vec![scratch.clone(), integer(0)]),
+                real(Opcode::ADD, vec![register(1)?, scratch, register(0)?])
+            ]
+        },
+        PseudoOp::LI => {
+            expected(2)?;
+            match operands[1] {
+                Token::IntegerOperand { value } => match i32::try_from(*value) {
+                    Ok(value) => load_constant(register(0)?, value, scratch),
+                    Err(_) => return Err(invalid(&format!("operand 2 is {}, which does not fit in 32 bits", value)))
+                },
+                _ => return Err(invalid("operand 2 has to be an integer"))
+            }
+        },
+        PseudoOp::B => {
+            expected(1)?;
+            vec![
+                real(Opcode::LOAD, vec![scratch.clone(), label(0)?]),
+                real(Opcode::JMP, vec![scratch])
+            ]
+        },
+        PseudoOp::BEQ | PseudoOp::BNE => {
+            expected(3)?;
+            let compare = if op == PseudoOp::BEQ { Opcode::EQ } else { Opcode::NEQ };
+            vec![
+                real(compare, vec![register(0)?, register(1)?]),
+                real(Opcode::DJMPE, vec![label(2)?])
+            ]
+        },
+        PseudoOp::NEG | PseudoOp::NOT => {
+            if operands.is_empty() || operands.len() > 2 {
+                return Err(invalid(&format!("expected 1 or 2 operands but found {}", operands.len())));
+            }
+            let source = register(operands.len() - 1)?;
+            let mut instructions = vec![real(Opcode::LOAD, vec![scratch.clone(), integer(0)])];
+            if op == PseudoOp::NOT {
+                // In two's complement !x is -1 - x
+                instructions.push(real(Opcode::DEC, vec![scratch.clone()]));
+            }
+            instructions.push(real(Opcode::SUB, vec![scratch, source, register(0)?]));
+            instructions
+        },
+        PseudoOp::CLR => {
+            expected(1)?;
+            vec![real(Opcode::LOAD, vec![register(0)?, integer(0)])]
+        }
+    };
+    expanded[0].label = i.label.clone();
+    Ok(expanded)
+}
+
+/// `load` only takes 16 bits, so larger constants are built from their upper and lower halves.
+/// Negative constants are loaded as their magnitude and then negated.
+fn load_constant(destination: Token, value: i32, scratch: Token) -> Vec<AssemblerInstruction> {
+    if (0..=i32::from(u16::MAX)).contains(&value) {
+        return vec![real(Opcode::LOAD, vec![destination, integer(value)])];
+    }
+
+    let magnitude = value.unsigned_abs();
+    let mut instructions = if magnitude <= u32::from(u16::MAX) {
+        vec![real(Opcode::LOAD, vec![destination.clone(), integer(magnitude as i32)])]
+    } else {
+        // Shifting left by 16 bits is two multiplications by 256, as 65536 does not fit in `load`
+        vec![
+            real(Opcode::LOAD, vec![destination.clone(), integer((magnitude >> 16) as i32)]),
+            real(Opcode::LOAD, vec![scratch.clone(), integer(256)]),
+            real(Opcode::MUL, vec![destination.clone(), scratch.clone(), destination.clone()]),
+            real(Opcode::MUL, vec![destination.clone(), scratch.clone(), destination.clone()])
+        ]
+    };
+    if magnitude > u32::from(u16::MAX) && magnitude & 0xFFFF != 0 {
+        instructions.push(real(Opcode::LOAD, vec![scratch.clone(), integer((magnitude & 0xFFFF) as i32)]));
+        instructions.push(real(Opcode::ADD, vec![destination.clone(), scratch.clone(), destination.clone()]));
+    }
+    if value < 0 {
+        instructions.push(real(Opcode::LOAD, vec![scratch.clone(), integer(0)]));
+        instructions.push(real(Opcode::SUB, vec![scratch, destination.clone(), destination]));
+    }
+    instructions
+}
+
+fn real(code: Opcode, operands: Vec<Token>) -> AssemblerInstruction {
+    let mut operands = operands.into_iter();
+    AssemblerInstruction {
+        opcode: Some(Token::Op { code }),
+        label: None,
+        directive: None,
+        operand1: operands.next(),
+        operand2: operands.next(),
+        operand3: operands.next()
+    }
+}
+
+fn integer(value: i32) -> Token {
+    Token::IntegerOperand { value: value.into() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::instruction_parsers::instruction;
+    use nom::types::CompleteStr;
+
+    fn expand_source(source: &str) -> Result<Vec<String>, AssemblerError> {
+        let (_, i) = instruction(CompleteStr(source)).unwrap();
+        expand(&i).map(|instructions| instructions.iter().map(|i| i.to_string()).collect())
+    }
+
+    #[test]
+    fn test_expand_pseudo_instructions() {
+        assert_eq!(expand_source("mov $t0 $a0").unwrap(), vec!["load $at #0", "add $a0 $at $t0"]);
+        assert_eq!(expand_source("b @loop").unwrap(), vec!["load $at @loop", "jmp $at"]);
+        assert_eq!(expand_source("beq $1 $2 @done").unwrap(), vec!["eq $a1 $a2", "djmpe @done"]);
+        assert_eq!(expand_source("neg $3").unwrap(), vec!["load $at #0", "sub $at $a3 $a3"]);
+        assert_eq!(expand_source("not $3 $4").unwrap(), vec!["load $at #0", "dec $at", "sub $at $t0 $a3"]);
+        assert_eq!(expand_source("clr $counter").unwrap(), vec!["load $counter #0"]);
+        assert_eq!(expand_source("load $0 #1").unwrap(), vec!["load $a0 #1"]);
+    }
+
+    #[test]
+    fn test_expand_li() {
+        assert_eq!(expand_source("li $0 #500").unwrap(), vec!["load $a0 #500"]);
+        assert_eq!(expand_source("li $0 #65536").unwrap().len(), 4);
+        assert_eq!(expand_source("li $0 #70000").unwrap().len(), 6);
+        assert_eq!(expand_source("li $0 #-1").unwrap(), vec!["load $a0 #1", "load $at #0", "sub $at $a0 $a0"]);
+    }
+
+    #[test]
+    fn test_invalid_pseudo_instructions() {
+        assert!(expand_source("b $1").is_err());
+        assert!(expand_source("beq $1 $2").is_err());
+        assert!(expand_source("mov $at $1").is_err());
+        assert!(expand_source("li $0 $1").is_err());
+    }
+}
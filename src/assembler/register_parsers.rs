@@ -1,24 +1,50 @@
 use nom::types::CompleteStr;
-use nom::digit;
+use nom::{alphanumeric, digit, ErrorKind};
 
 use crate::assembler::Token;
+use crate::assembler::registers::abi_register;
+
+// Registers are numbered 0 to 31. Any other number is a failure rather than an error, so `$32` is
+// not tried as an alias next.
+named!(numbered_register <CompleteStr, Token>,
+    do_parse!(
+        tag!("$") >>
+        reg_num: digit >>
+        reg_num: return_error!(ErrorKind::Custom(1), expr_opt!(
+            reg_num.parse::<u8>().ok().filter(|reg_num| *reg_num < 32)
+        )) >>
+        (
+            Token::Register{ reg_num }
+        )
+    )
+);
+
+// `$sp` and the other conventional names map straight to a register. Any other name is an alias
+// defined with `.alias`, which the assembler resolves since aliases can change along the way.
+named!(named_register <CompleteStr, Token>,
+    do_parse!(
+        tag!("$") >>
+        name: alphanumeric >>
+        (
+            match abi_register(&name) {
+                Some(reg_num) => Token::Register{ reg_num },
+                None => Token::RegisterAlias{ name: name.to_string() }
+            }
+        )
+    )
+);
 
 named!(pub register <CompleteStr, Token>,
    ws!(
-        do_parse!(
-            tag!("$") >>
-            reg_num: digit >>
-            (
-                Token::Register{
-                    reg_num: reg_num.parse::<u8>().unwrap()
-                }
-            )
+        alt!(
+            numbered_register |
+            named_register
         )
    )
 );
 
 mod tests {
-    #![allow(unused_imports)]
+    #![allow(unused_imports, clippy::bool_assert_comparison)]
     use super::*;
 
     #[test]
@@ -27,7 +53,17 @@ mod tests {
         assert_eq!(result.is_ok(), true);
         let result = register(CompleteStr("0"));
         assert_eq!(result.is_ok(), false);
-        let result = register(CompleteStr("$a"));
+        let result = register(CompleteStr("$"));
         assert_eq!(result.is_ok(), false);
+        assert_eq!(register(CompleteStr("$31")), Ok((CompleteStr(""), Token::Register{ reg_num: 31 })));
+        assert!(register(CompleteStr("$32")).is_err());
+        assert!(register(CompleteStr("$300")).is_err());
+    }
+
+    #[test]
+    fn test_parse_named_register() {
+        assert_eq!(register(CompleteStr("$sp")), Ok((CompleteStr(""), Token::Register{ reg_num: 30 })));
+        assert_eq!(register(CompleteStr("$t0")), Ok((CompleteStr(""), Token::Register{ reg_num: 4 })));
+        assert_eq!(register(CompleteStr("$counter")), Ok((CompleteStr(""), Token::RegisterAlias{ name: "counter".to_string() })));
     }
 }
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::Token;
+
+/// Number of general purpose registers in the VM
+pub const REGISTER_COUNT: usize = 32;
+
+/// Conventional names for each register, so `$sp` can be written instead of `$30`:
+///
+/// * `$a0`-`$a3`: arguments and return values
+/// * `$t0`-`$t11`: temporaries a called routine is free to clobber
+/// * `$s0`-`$s11`: saved registers a called routine has to preserve
+/// * `$ra`, `$fp`, `$sp`: return address, frame pointer and stack pointer
+/// * `$at`: reserved for the assembler, which uses it to expand pseudo-instructions
+pub const ABI_NAMES: [&str; REGISTER_COUNT] = [
+    "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7", "t8", "t9", "t10", "t11",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+    "ra", "fp", "sp", "at",
+];
+
+/// Returns the conventional name of a register, without the leading `$`
+pub fn abi_name(register: u8) -> Option<&'static str> {
+    ABI_NAMES.get(register as usize).copied()
+}
+
+/// Returns the register a conventional name such as `sp` refers to
+pub fn abi_register(name: &str) -> Option<u8> {
+    ABI_NAMES.iter().position(|n| *n == name).map(|r| r as u8)
+}
+
+/// Names given to registers with `.alias counter $5`, on top of the conventional names
+#[derive(Debug, Clone, Default)]
+pub struct RegisterAliases {
+    aliases: HashMap<String, u8>
+}
+
+impl RegisterAliases {
+    pub fn new() -> RegisterAliases {
+        RegisterAliases {
+            aliases: HashMap::new()
+        }
+    }
+
+    /// Conventional names cannot be redefined and `$at` cannot be given an alias, but an alias can be
+    /// moved to another register
+    pub fn define(&mut self, name: &str, register: u8) -> Result<(), AssemblerError> {
+        // Pseudo-instructions check for `$at` before aliases are resolved, so an alias would get past them
+        if abi_register(name).is_some() || register as usize >= REGISTER_COUNT || Some(register) == abi_register("at") {
+            return Err(AssemblerError::InvalidRegisterAlias{ name: name.to_string() });
+        }
+        self.aliases.insert(name.to_string(), register);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), AssemblerError> {
+        match self.aliases.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(AssemblerError::UnknownRegister{ name: name.to_string() })
+        }
+    }
+
+    /// Looks a name up among the aliases and then the conventional names
+    pub fn lookup(&self, name: &str) -> Option<u8> {
+        self.aliases.get(name).copied().or_else(|| abi_register(name))
+    }
+
+    /// Aliases currently given to a register, sorted by name
+    pub fn aliases_of(&self, register: u8) -> Vec<&str> {
+        let mut names: Vec<&str> = self.aliases.iter()
+            .filter(|(_, r)| **r == register)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The name a register is best shown as: its first alias if it has one, otherwise its conventional name
+    pub fn display_name(&self, register: u8) -> String {
+        match self.aliases_of(register).first() {
+            Some(alias) => format!("${}", alias),
+            None => match abi_name(register) {
+                Some(name) => format!("${}", name),
+                None => format!("${}", register)
+            }
+        }
+    }
+
+    /// Applies `.alias` and `.unalias`, returning false for any other directive
+    pub fn process_directive(&mut self, i: &AssemblerInstruction) -> Result<bool, AssemblerError> {
+        let name = match i.get_directive_name() {
+            Some(name) => name,
+            None => return Ok(false)
+        };
+        let alias = i.get_symbol_operand().unwrap_or_default();
+        match name.as_ref() {
+            "alias" => {
+                let register = match &i.operand2 {
+                    Some(Token::Register { reg_num }) => *reg_num,
+                    Some(Token::RegisterAlias { name }) => match self.lookup(name) {
+                        Some(register) => register,
+                        None => return Err(AssemblerError::UnknownRegister{ name: name.clone() })
+                    },
+                    _ => return Err(AssemblerError::InvalidRegisterAlias{ name: alias })
+                };
+                self.define(&alias, register)?;
+                Ok(true)
+            },
+            "unalias" => {
+                self.remove(&alias)?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    /// Replaces every aliased register operand of an instruction with the register it names
+    pub fn resolve(&self, i: &AssemblerInstruction) -> Result<AssemblerInstruction, AssemblerError> {
+        let mut resolved = i.clone();
+        for operand in [&mut resolved.operand1, &mut resolved.operand2, &mut resolved.operand3] {
+            if let Some(Token::RegisterAlias { name }) = operand {
+                match self.lookup(name) {
+                    Some(register) => *operand = Some(Token::Register { reg_num: register }),
+                    None => return Err(AssemblerError::UnknownRegister{ name: name.clone() })
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abi_names() {
+        assert_eq!(abi_register("a0"), Some(0));
+        assert_eq!(abi_register("t0"), Some(4));
+        assert_eq!(abi_register("s11"), Some(27));
+        assert_eq!(abi_register("sp"), Some(30));
+        assert_eq!(abi_register("counter"), None);
+        assert_eq!(abi_name(29), Some("fp"));
+        assert_eq!(abi_name(32), None);
+    }
+
+    #[test]
+    fn test_define_and_remove_aliases() {
+        let mut aliases = RegisterAliases::new();
+        assert!(aliases.define("counter", 5).is_ok());
+        assert_eq!(aliases.lookup("counter"), Some(5));
+        assert_eq!(aliases.display_name(5), "$counter");
+        assert_eq!(aliases.display_name(6), "$t2");
+        assert!(aliases.define("sp", 5).is_err());
+        assert!(aliases.define("big", 40).is_err());
+        assert!(aliases.define("scratch", 31).is_err());
+        assert!(aliases.remove("counter").is_ok());
+        assert!(aliases.remove("counter").is_err());
+        assert_eq!(aliases.lookup("counter"), None);
+    }
+}
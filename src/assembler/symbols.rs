@@ -1,20 +1,55 @@
-#[derive(Debug, Clone)]
+use std::collections::hash_map;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Symbol {
-    name: String,
-    offset: Option<u32>,
-    symbol_type: SymbolType
+    /// Name the symbol was declared with
+    pub name: String,
+    /// Offset of the symbol relative to the start of its section
+    pub offset: Option<u32>,
+    /// What kind of value the symbol refers to
+    pub symbol_type: SymbolType,
+    /// Section that owns the symbol, `None` while it is undefined or external
+    pub section: Option<SymbolSection>,
+    /// Size in bytes of the thing the symbol names
+    pub size: u32,
+    /// Whether the symbol is local, exported or imported from another module
+    pub visibility: Visibility,
+    /// Instruction number the symbol was defined at
+    pub defined_at: Option<u32>,
+    /// Version an exported symbol is published under, or the version an import requires
+    pub version: Option<u16>
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SymbolType {
-    Label,
-    Integer,
-    IrString
+    CodeLabel,
+    DataLabel,
+    IrString,
+    /// Resolved at load time from a shared module, see `.import`
+    Import
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolSection {
+    Code,
+    Data,
+    ReadOnly
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    /// Only visible inside the module that declares it
+    Local,
+    /// Declared with `.global` and exported to other modules
+    Global,
+    /// Declared with `.extern` and expected to be defined in another module
+    Extern
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct SymbolTable {
-    pub symbols: Vec<Symbol>
+    symbols: HashMap<String, Symbol>
 }
 
 impl Symbol {
@@ -22,55 +57,147 @@ impl Symbol {
         Symbol {
             name,
             symbol_type,
-            offset: None
+            offset: None,
+            section: None,
+            size: 0,
+            visibility: Visibility::Local,
+            defined_at: None,
+            version: None
         }
     }
 
     pub fn new_with_offset(name: String, symbol_type: SymbolType, offset: u32) -> Symbol {
         Symbol {
-            name,
-            symbol_type,
-            offset: Some(offset)
+            offset: Some(offset),
+            ..Symbol::new(name, symbol_type)
         }
     }
+
+    /// True once the symbol has been given a location in one of the sections
+    pub fn is_defined(&self) -> bool {
+        self.section.is_some() || self.offset.is_some()
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.visibility == Visibility::Global
+    }
+
+    pub fn is_extern(&self) -> bool {
+        self.visibility == Visibility::Extern
+    }
+
+    pub fn is_import(&self) -> bool {
+        self.symbol_type == SymbolType::Import
+    }
 }
 
 impl SymbolTable {
     pub fn new() -> Self {
-        Self { 
-            symbols: vec![]
+        Self {
+            symbols: HashMap::new()
         }
     }
 
-    pub fn add_symbol(&mut self, s: Symbol) {
-        self.symbols.push(s);
+    /// Adds a symbol, replacing and returning any symbol that already had the same name
+    pub fn add_symbol(&mut self, s: Symbol) -> Option<Symbol> {
+        self.symbols.insert(s.name.clone(), s)
     }
 
-    pub fn has_symbol(&mut self, s: &str) -> bool {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return true;
-            }
-        }
-        false
+    pub fn has_symbol(&self, s: &str) -> bool {
+        self.symbols.contains_key(s)
+    }
+
+    pub fn get(&self, s: &str) -> Option<&Symbol> {
+        self.symbols.get(s)
+    }
+
+    pub fn get_mut(&mut self, s: &str) -> Option<&mut Symbol> {
+        self.symbols.get_mut(s)
     }
 
     pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
-        for symbol in &mut self.symbols {
-            if symbol.name == s {
+        match self.symbols.get_mut(s) {
+            Some(symbol) => {
                 symbol.offset = Some(offset);
-                return true;
-            }
+                true
+            },
+            None => false
         }
-        false
     }
 
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return symbol.offset;
-            }
-        }
-        None
+        self.symbols.get(s).and_then(|symbol| symbol.offset)
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Iterates over every symbol in no particular order
+    pub fn iter(&self) -> hash_map::Values<'_, String, Symbol> {
+        self.symbols.values()
+    }
+
+    /// Returns all symbols sorted by name, which is handy for listings
+    pub fn sorted(&self) -> Vec<&Symbol> {
+        let mut symbols: Vec<&Symbol> = self.symbols.values().collect();
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+        symbols
+    }
+
+    /// Returns the symbols declared `.global`, sorted by name
+    pub fn exported(&self) -> Vec<&Symbol> {
+        self.sorted().into_iter().filter(|s| s.is_global()).collect()
+    }
+
+    /// Returns the symbols declared `.extern`, sorted by name
+    pub fn externs(&self) -> Vec<&Symbol> {
+        self.sorted().into_iter().filter(|s| s.is_extern()).collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a SymbolTable {
+    type Item = &'a Symbol;
+    type IntoIter = hash_map::Values<'a, String, Symbol>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_replaces_existing_symbol() {
+        let mut table = SymbolTable::new();
+        assert!(table.add_symbol(Symbol::new("test".to_string(), SymbolType::CodeLabel)).is_none());
+        let previous = table.add_symbol(Symbol::new_with_offset("test".to_string(), SymbolType::CodeLabel, 8));
+        assert!(previous.is_some());
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.symbol_value("test"), Some(8));
+    }
+
+    #[test]
+    fn test_exported_and_externs() {
+        let mut table = SymbolTable::new();
+        let mut main = Symbol::new_with_offset("main".to_string(), SymbolType::CodeLabel, 0);
+        main.visibility = Visibility::Global;
+        let mut print = Symbol::new("print".to_string(), SymbolType::CodeLabel);
+        print.visibility = Visibility::Extern;
+        table.add_symbol(main);
+        table.add_symbol(print);
+        table.add_symbol(Symbol::new_with_offset("loop".to_string(), SymbolType::CodeLabel, 4));
+
+        let exported: Vec<&str> = table.exported().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(exported, vec!["main"]);
+        let externs: Vec<&str> = table.externs().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(externs, vec!["print"]);
+        assert_eq!(table.iter().count(), 3);
     }
 }
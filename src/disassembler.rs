@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use crate::assembler::registers::RegisterAliases;
+use crate::instruction::{Instruction, Opcode, Operand, INSTRUCTION_LENGTH};
+use crate::loader::PieLayout;
+use crate::vm::values::ValueType;
+
+/// Turns bytecode back into assembly, showing registers by their conventional names or aliases
+#[derive(Debug, Clone, Default)]
+pub struct Disassembler {
+    aliases: RegisterAliases,
+    /// Notes shown next to the instruction at an address, such as the pseudo-instruction it came from
+    annotations: HashMap<usize, String>
+}
+
+impl Disassembler {
+    pub fn new() -> Disassembler {
+        Disassembler {
+            aliases: RegisterAliases::new(),
+            annotations: HashMap::new()
+        }
+    }
+
+    /// Shows registers that have an alias by that alias instead of their conventional name
+    pub fn with_aliases(aliases: RegisterAliases) -> Disassembler {
+        Disassembler {
+            aliases,
+            annotations: HashMap::new()
+        }
+    }
+
+    pub fn annotate(&mut self, address: usize, note: String) {
+        self.annotations.insert(address, note);
+    }
+
+    /// Disassembles a single instruction. Missing operand bytes are treated as zero.
+    pub fn instruction(&self, bytes: &[u8]) -> String {
+        let mut padded = bytes.to_vec();
+        padded.resize(padded.len().max(INSTRUCTION_LENGTH), 0);
+        match Instruction::decode(&padded) {
+            Ok((instruction, _)) => {
+                let mut text = instruction.opcode().mnemonic().to_string();
+                for operand in instruction.operands() {
+                    match operand {
+                        Operand::Register(register) => text.push_str(&format!(" {}", self.aliases.display_name(*register))),
+                        _ => text.push_str(&format!(" {}", operand))
+                    }
+                }
+                text
+            },
+            Err(_) => format!("{} {}", Opcode::IGL.mnemonic(), padded[0])
+        }
+    }
+
+    /// Disassembles code one instruction at a time, prefixing each line with its address.
+    /// `start` is the address of the first byte, so the listing matches what the VM sees.
+    pub fn listing(&self, code: &[u8], start: usize) -> Vec<String> {
+        code.chunks(INSTRUCTION_LENGTH)
+            .enumerate()
+            .map(|(index, bytes)| {
+                let address = start + index * INSTRUCTION_LENGTH;
+                let line = format!("{:04}: {}", address, self.instruction(bytes));
+                match self.annotations.get(&address).cloned().or_else(|| type_note(bytes)) {
+                    Some(note) => format!("{:<32}; {}", line, note),
+                    None => line
+                }
+            })
+            .collect()
+    }
+
+    /// Disassembles the code of a program, skipping its PIE header and sections if it has them
+    pub fn program(&self, program: &[u8]) -> Vec<String> {
+        match PieLayout::parse("<program>", program) {
+            Ok(layout) => self.listing(&program[layout.code_start..], layout.code_start),
+            Err(_) => self.listing(program, 0)
+        }
+    }
+}
+
+/// Names the type a `cast` converts to, as the instruction only has its number
+fn type_note(bytes: &[u8]) -> Option<String> {
+    match Instruction::decode(bytes) {
+        Ok((ref instruction, _)) if instruction.opcode() == Opcode::CAST => match instruction.operands() {
+            [_, Operand::Integer(code)] => ValueType::from_code(*code).map(|to| format!("to {}", to)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_instructions() {
+        let disassembler = Disassembler::new();
+        assert_eq!(disassembler.instruction(&[0, 30, 1, 244]), "load $sp #500");
+        assert_eq!(disassembler.instruction(&[1, 0, 4, 28]), "add $a0 $t0 $ra");
+        assert_eq!(disassembler.instruction(&[22, 0, 64, 0]), "call #64");
+        assert_eq!(disassembler.instruction(&[5, 0, 0, 0]), "hlt");
+        assert_eq!(disassembler.instruction(&[200, 0, 0, 0]), "igl 200");
+    }
+
+    #[test]
+    fn test_disassemble_with_aliases() {
+        let mut aliases = RegisterAliases::new();
+        aliases.define("counter", 5).unwrap();
+        let disassembler = Disassembler::with_aliases(aliases);
+        assert_eq!(disassembler.listing(&[18, 5, 0, 0, 19, 6, 0, 0], 64), vec!["0064: inc $counter", "0068: dec $t2"]);
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let program = crate::assembler::Assembler::new().assemble(".data\n.code\nload $fp #1\nhlt").unwrap();
+        assert_eq!(Disassembler::new().program(&program), vec!["0064: load $fp #1", "0068: hlt"]);
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut disassembler = Disassembler::new();
+        disassembler.annotate(4, "clr $t0".to_string());
+        let listing = disassembler.listing(&[5, 0, 0, 0, 0, 4, 0, 0], 0);
+        assert_eq!(listing[0], "0000: hlt");
+        assert_eq!(listing[1], "0004: load $t0 #0               ; clr $t0");
+
+        let listing = Disassembler::new().listing(&[40, 4, 0, 2, 40, 4, 0, 9], 0);
+        assert_eq!(listing, vec!["0000: cast $t0 #2               ; to float", "0004: cast $t0 #9"]);
+    }
+
+    #[test]
+    fn test_assembler_and_disassembler_agree() {
+        use crate::assembler::instruction_parsers::instruction;
+        use crate::assembler::symbols::SymbolTable;
+        use crate::instruction::{OperandKind, OPCODES};
+        use nom::types::CompleteStr;
+
+        // Every opcode with operands of every kind it takes has to survive a trip through the
+        // assembler and back, which catches the parser, encoder and decoder disagreeing on the table
+        for info in OPCODES.iter().filter(|info| info.opcode != Opcode::IGL) {
+            let mut source = info.mnemonic.to_string();
+            for (index, kind) in info.operands.iter().enumerate() {
+                match kind {
+                    OperandKind::Register => source.push_str(&format!(" $t{}", index)),
+                    OperandKind::Integer | OperandKind::Address => source.push_str(" #300")
+                }
+            }
+            let (rest, parsed) = instruction(CompleteStr(&source)).unwrap();
+            assert!(rest.is_empty(), "{} did not parse completely", source);
+            assert!(parsed.check_operands().is_ok(), "{} was rejected", source);
+            let bytes = parsed.to_bytes(&SymbolTable::new());
+            assert_eq!(bytes.len(), INSTRUCTION_LENGTH);
+            assert_eq!(bytes[0], info.code);
+            assert_eq!(Disassembler::new().instruction(&bytes), source);
+        }
+    }
+}
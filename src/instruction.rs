@@ -1,5 +1,11 @@
+use std::error::Error;
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
 use nom::types::CompleteStr;
 
+use crate::assembler::registers::abi_name;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Opcode {
     LOAD,
@@ -24,79 +30,333 @@ pub enum Opcode {
     DEC,
     DJMPE,
     PRTS,
+    CALL,
+    RET,
+    CALLX,
+    LDB,
+    STB,
+    PRTH,
+    PRTI,
+    PRTX,
+    PRTC,
+    RDI,
+    RDL,
+    SYSCALL,
+    FREE,
+    NEW,
+    GC,
+    LDW,
+    STW,
+    TYPEOF,
+    CAST,
     IGL,
 }
 
+/// What an operand of an instruction holds, which also decides how many bytes it is encoded in
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OperandKind {
+    /// A register number, one byte
+    Register,
+    /// A 16 bit immediate value, big endian
+    Integer,
+    /// A 16 bit address, such as a jump target, an offset into the read only section or an import slot
+    Address
+}
+
+/// Everything the assembler, VM and disassembler need to know about an opcode
 #[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub code: u8,
+    pub operands: &'static [OperandKind],
+    pub description: &'static str
+}
+
+/// Encoded length of every instruction, including the opcode and any padding after the operands
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// Code the illegal instruction is decoded from. It is never emitted by the assembler.
+pub const IGL_CODE: u8 = 255;
+
+use self::OperandKind::{Address, Integer, Register};
+
+macro_rules! opcode_table {
+    ($($opcode:ident, $mnemonic:expr, $code:expr, [$($operand:ident),*], $description:expr;)*) => {
+        /// The instruction set. Every entry but the last is at the index of its code. The last is `igl`,
+        /// whose code is `IGL_CODE`, so look entries up with `Opcode::info` or `Opcode::from` rather than
+        /// by indexing with a code.
+        pub const OPCODES: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                opcode: Opcode::$opcode,
+                mnemonic: $mnemonic,
+                code: $code,
+                operands: &[$($operand),*],
+                description: $description
+            },)*
+        ];
+    };
+}
+
+opcode_table! {
+    LOAD, "load", 0, [Register, Integer], "Loads a 16 bit value into a register";
+    ADD, "add", 1, [Register, Register, Register], "Adds two registers and stores the result in the third";
+    SUB, "sub", 2, [Register, Register, Register], "Subtracts the second register from the first and stores the result in the third";
+    MUL, "mul", 3, [Register, Register, Register], "Multiplies two registers and stores the result in the third";
+    DIV, "div", 4, [Register, Register, Register], "Divides the first register by the second, storing the quotient in the third and keeping the remainder";
+    HLT, "hlt", 5, [], "Stops the VM";
+    JMP, "jmp", 6, [Register], "Jumps to the address in a register";
+    JMPF, "jmpf", 7, [Register], "Jumps forward by the number of bytes in a register, counted from the end of the operands";
+    JMPB, "jmpb", 8, [Register], "Jumps backward by the number of bytes in a register, counted from the end of the operands";
+    EQ, "eq", 9, [Register, Register], "Sets the equal flag if two registers are equal";
+    NEQ, "neq", 10, [Register, Register], "Sets the equal flag if two registers are not equal";
+    GT, "gt", 11, [Register, Register], "Sets the equal flag if the first register is greater than the second";
+    LT, "lt", 12, [Register, Register], "Sets the equal flag if the first register is less than the second";
+    GTE, "gte", 13, [Register, Register], "Sets the equal flag if the first register is greater than or equal to the second";
+    LTE, "lte", 14, [Register, Register], "Sets the equal flag if the first register is less than or equal to the second";
+    JMPE, "jmpe", 15, [Register], "Jumps to the address in a register if the equal flag is set";
+    NOP, "nop", 16, [], "Does nothing";
+    ALOC, "aloc", 17, [Register, Register], "Allocates a heap block of the size in the first register and puts its address in the second";
+    INC, "inc", 18, [Register], "Adds one to a register";
+    DEC, "dec", 19, [Register], "Subtracts one from a register";
+    DJMPE, "djmpe", 20, [Address], "Jumps to an address if the equal flag is set";
+    PRTS, "prts", 21, [Address], "Prints the NUL terminated string at an offset into the read only section";
+    CALL, "call", 22, [Address], "Pushes the address of the next instruction and jumps to an address";
+    RET, "ret", 23, [], "Returns to the address pushed by the last call";
+    CALLX, "callx", 24, [Address], "Calls a routine in a shared module through a slot of the import table";
+    LDB, "ldb", 25, [Register, Register], "Loads the heap byte at the address in the second register into the first";
+    STB, "stb", 26, [Register, Register], "Stores the low byte of the first register at the heap address in the second";
+    PRTH, "prth", 27, [Register], "Prints the NUL terminated string at the heap address in a register";
+    PRTI, "prti", 28, [Register], "Prints a register as a signed decimal number";
+    PRTX, "prtx", 29, [Register], "Prints a register as a hexadecimal number";
+    PRTC, "prtc", 30, [Register], "Prints the character whose code point is in a register";
+    RDI, "rdi", 31, [Register], "Reads a line holding a decimal number into a register, setting the equal flag if it was valid";
+    RDL, "rdl", 32, [Register, Register], "Reads a line into the heap buffer at the address in the first register, whose size is in the second";
+    SYSCALL, "syscall", 33, [Integer], "Calls the host function registered under a number";
+    FREE, "free", 34, [Register], "Frees the heap block whose address is in a register";
+    NEW, "new", 35, [Register, Register, Register], "Allocates an object of the size in the first register with the type tag in the second, and puts its address in the third";
+    GC, "gc", 36, [], "Runs the garbage collector, if GC mode is on";
+    LDW, "ldw", 37, [Register, Register], "Loads the little endian 32 bit word at the address in the second register into the first";
+    STW, "stw", 38, [Register, Register], "Stores the first register as a little endian 32 bit word at the address in the second";
+    TYPEOF, "typeof", 39, [Register, Register], "Puts the number of the type of the first register's value in the second: 0 nil, 1 int, 2 float, 3 bool, 4 pointer";
+    CAST, "cast", 40, [Register, Integer], "Converts a register's value to the type with the given number";
+    IGL, "igl", IGL_CODE, [], "Illegal instruction, stops the VM";
+}
+
+impl OperandKind {
+    /// Number of bytes the operand is encoded in
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register => 1,
+            OperandKind::Integer | OperandKind::Address => 2
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OperandKind::Register => "register",
+            OperandKind::Integer => "integer",
+            OperandKind::Address => "address"
+        }
+    }
+}
+
+impl Opcode {
+    pub fn info(self) -> &'static OpcodeInfo {
+        // The variants are declared in the same order as the table
+        &OPCODES[self as usize]
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self.info().code
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    pub fn operands(self) -> &'static [OperandKind] {
+        self.info().operands
+    }
+
+    /// Looks an opcode up by its mnemonic. `igl` is not a mnemonic anyone can write.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODES.iter()
+            .find(|info| info.mnemonic == mnemonic && info.opcode != Opcode::IGL)
+            .map(|info| info.opcode)
+    }
+}
+
+/// Describes the instruction set as JSON, so tools outside this crate can use the same table
+pub fn isa_json() -> String {
+    let entries: Vec<String> = OPCODES.iter().map(|info| {
+        let operands: Vec<String> = info.operands.iter().map(|o| format!("\"{}\"", o.name())).collect();
+        format!(
+            "    {{\"mnemonic\": \"{}\", \"code\": {}, \"operands\": [{}], \"description\": \"{}\"}}",
+            info.mnemonic, info.code, operands.join(", "), info.description.replace('"', "\\\"")
+        )
+    }).collect();
+    format!("{{\n  \"instruction_length\": {},\n  \"opcodes\": [\n{}\n  ]\n}}\n", INSTRUCTION_LENGTH, entries.join(",\n"))
+}
+
+/// A decoded operand, holding the value it was encoded with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    Register(u8),
+    Integer(u16),
+    Address(u16)
+}
+
+impl Operand {
+    pub fn kind(self) -> OperandKind {
+        match self {
+            Operand::Register(_) => OperandKind::Register,
+            Operand::Integer(_) => OperandKind::Integer,
+            Operand::Address(_) => OperandKind::Address
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(register) => match abi_name(*register) {
+                Some(name) => write!(f, "${}", name),
+                None => write!(f, "${}", register)
+            },
+            Operand::Integer(value) | Operand::Address(value) => write!(f, "#{}", value)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstructionError {
+    /// Fewer bytes were left than the instruction is encoded in
+    Truncated{ needed: usize, available: usize },
+    IllegalOpcode{ code: u8 },
+    /// The operands do not match the ones the opcode table lists for the opcode
+    InvalidOperands{ opcode: Opcode, operands: Vec<Operand> }
+}
+
+impl fmt::Display for InstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstructionError::Truncated{ needed, available } => {
+                f.write_str(&format!("Instruction needs {} bytes but only {} are left", needed, available))
+            },
+            InstructionError::IllegalOpcode{ code } => {
+                f.write_str(&format!("{} is not a valid opcode", code))
+            },
+            InstructionError::InvalidOperands{ opcode, operands } => {
+                let kinds: Vec<&str> = opcode.operands().iter().map(|k| k.name()).collect();
+                f.write_str(&format!("{} takes operands [{}] but was given {:?}", opcode.mnemonic(), kinds.join(", "), operands))
+            }
+        }
+    }
+}
+
+impl Error for InstructionError {}
+
+/// A decoded instruction. The operands always match the ones the opcode table lists for the opcode.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Instruction {
-    opcode: Opcode
+    opcode: Opcode,
+    operands: Vec<Operand>
 }
 
 impl Instruction {
+    /// An instruction without operands, such as `hlt`
     pub fn new(opcode: Opcode) -> Instruction {
         Instruction {
-            opcode
+            opcode,
+            operands: vec![]
         }
     }
+
+    pub fn with_operands(opcode: Opcode, operands: Vec<Operand>) -> Result<Instruction, InstructionError> {
+        let kinds: Vec<OperandKind> = operands.iter().map(|o| o.kind()).collect();
+        if opcode == Opcode::IGL || kinds != opcode.operands() {
+            return Err(InstructionError::InvalidOperands{ opcode, operands });
+        }
+        Ok(Instruction { opcode, operands })
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    pub fn operands(&self) -> &[Operand] {
+        &self.operands
+    }
+
+    /// Decodes the instruction at the start of `bytes`, returning it with the number of bytes it took up
+    pub fn decode(bytes: &[u8]) -> Result<(Instruction, usize), InstructionError> {
+        let code = match bytes.first() {
+            Some(code) => *code,
+            None => return Err(InstructionError::Truncated{ needed: 1, available: 0 })
+        };
+        let opcode = Opcode::from(code);
+        if opcode == Opcode::IGL {
+            return Err(InstructionError::IllegalOpcode{ code });
+        }
+        let info = opcode.info();
+        if bytes.len() < INSTRUCTION_LENGTH {
+            return Err(InstructionError::Truncated{ needed: INSTRUCTION_LENGTH, available: bytes.len() });
+        }
+
+        let mut position = 1;
+        let operands = info.operands.iter().map(|kind| {
+            let operand = match kind {
+                OperandKind::Register => Operand::Register(bytes[position]),
+                OperandKind::Integer => Operand::Integer(BigEndian::read_u16(&bytes[position..])),
+                OperandKind::Address => Operand::Address(BigEndian::read_u16(&bytes[position..]))
+            };
+            position += kind.width();
+            operand
+        }).collect();
+        Ok((Instruction { opcode, operands }, INSTRUCTION_LENGTH))
+    }
+
+    /// Encodes the instruction the same way the assembler does, padded to its full length
+    pub fn encode(&self) -> Vec<u8> {
+        let info = self.opcode.info();
+        let mut bytes = Vec::with_capacity(INSTRUCTION_LENGTH);
+        bytes.push(info.code);
+        for operand in &self.operands {
+            match operand {
+                Operand::Register(register) => bytes.push(*register),
+                Operand::Integer(value) | Operand::Address(value) => {
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+        bytes.resize(INSTRUCTION_LENGTH, 0);
+        bytes
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.opcode.mnemonic())?;
+        for operand in &self.operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
 }
 
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
-        match v {
-            0  => Opcode::LOAD,
-            1  => Opcode::ADD,
-            2  => Opcode::SUB,
-            3  => Opcode::MUL,
-            4  => Opcode::DIV,
-            5  => Opcode::HLT,
-            6  => Opcode::JMP,
-            7  => Opcode::JMPF,
-            8  => Opcode::JMPB,
-            9  => Opcode::EQ,
-            10 => Opcode::NEQ,
-            11 => Opcode::GT,
-            12 => Opcode::LT,
-            13 => Opcode::GTE,
-            14 => Opcode::LTE,
-            15 => Opcode::JMPE,
-            16 => Opcode::NOP,
-            17 => Opcode::ALOC,
-            18 => Opcode::INC,
-            19 => Opcode::DEC,
-            20 => Opcode::DJMPE,
-            21 => Opcode::PRTS,
-            _  => Opcode::IGL,
+        match OPCODES.get(v as usize) {
+            Some(info) if info.code == v => info.opcode,
+            _ => Opcode::IGL
         }
     }
 }
 
 impl<'a> From<CompleteStr<'a>> for Opcode {
     fn from(v: CompleteStr<'a>) -> Self {
-        match v {
-            CompleteStr("load") => Opcode::LOAD,
-            CompleteStr("add") => Opcode::ADD,
-            CompleteStr("sub") => Opcode::SUB,
-            CompleteStr("mul") => Opcode::MUL,
-            CompleteStr("div") => Opcode::DIV,
-            CompleteStr("hlt") => Opcode::HLT,
-            CompleteStr("jmp") => Opcode::JMP,
-            CompleteStr("jmpf") => Opcode::JMPF,
-            CompleteStr("jmpb") => Opcode::JMPB,
-            CompleteStr("eq") => Opcode::EQ,
-            CompleteStr("neq") => Opcode::NEQ,
-            CompleteStr("gte") => Opcode::GTE,
-            CompleteStr("gt") => Opcode::GT,
-            CompleteStr("lte") => Opcode::LTE,
-            CompleteStr("lt") => Opcode::LT,
-            CompleteStr("jmpe") => Opcode::JMPE,
-            CompleteStr("nop") => Opcode::NOP,
-            CompleteStr("aloc") => Opcode::ALOC,
-            CompleteStr("inc") => Opcode::INC,
-            CompleteStr("dec") => Opcode::DEC,
-            CompleteStr("djmpe") => Opcode::DJMPE,
-            CompleteStr("prts") => Opcode::PRTS,
-            _ => Opcode::IGL,
-        }
+        Opcode::from_mnemonic(&v).unwrap_or(Opcode::IGL)
     }
 }
 
@@ -122,5 +382,75 @@ mod tests {
         assert_eq!(opcode, Opcode::LOAD);
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
+        assert_eq!(Opcode::from(CompleteStr("igl")), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_opcode_table_is_consistent() {
+        for (index, info) in OPCODES.iter().enumerate() {
+            if info.opcode == Opcode::IGL {
+                assert_eq!(index, OPCODES.len() - 1, "igl has to be the last entry");
+                assert_eq!(info.code, IGL_CODE);
+                assert_eq!(info.opcode as usize, index, "igl is declared out of order");
+                assert_eq!(Opcode::IGL.info(), info);
+                continue;
+            }
+            assert_eq!(info.code as usize, index, "{} is out of order", info.mnemonic);
+            assert_eq!(info.opcode as usize, index, "{} is declared out of order", info.mnemonic);
+            assert_eq!(Opcode::from(info.code), info.opcode);
+            assert_eq!(Opcode::from(CompleteStr(info.mnemonic)), info.opcode);
+            assert_eq!(info.opcode.to_u8(), info.code);
+            assert_eq!(info.opcode.mnemonic(), info.mnemonic);
+            assert_eq!(format!("{:?}", info.opcode).to_lowercase(), info.mnemonic);
+            let encoded: usize = 1 + info.operands.iter().map(|o| o.width()).sum::<usize>();
+            assert!(encoded <= INSTRUCTION_LENGTH, "{} does not fit in {} bytes", info.mnemonic, INSTRUCTION_LENGTH);
+            assert_eq!(OPCODES.iter().filter(|i| i.mnemonic == info.mnemonic).count(), 1);
+        }
+        assert_eq!(Opcode::from(IGL_CODE), Opcode::IGL);
+        assert_eq!(Opcode::from(OPCODES.len() as u8 - 1), Opcode::IGL);
+        for code in 0..=u8::MAX {
+            let opcode = Opcode::from(code);
+            assert!(opcode == Opcode::IGL || opcode.to_u8() == code, "{} decodes as {}", code, opcode.mnemonic());
+        }
+    }
+
+    #[test]
+    fn test_isa_json() {
+        let json = isa_json();
+        assert!(json.contains("{\"mnemonic\": \"load\", \"code\": 0, \"operands\": [\"register\", \"integer\"], \"description\":"));
+        assert_eq!(json.matches("\"mnemonic\"").count(), OPCODES.len());
+    }
+
+    #[test]
+    fn test_decode_instruction() {
+        let (instruction, length) = Instruction::decode(&[0, 4, 1, 244, 5]).unwrap();
+        assert_eq!(length, 4);
+        assert_eq!(instruction.opcode(), Opcode::LOAD);
+        assert_eq!(instruction.operands(), &[Operand::Register(4), Operand::Integer(500)]);
+        assert_eq!(instruction.to_string(), "load $t0 #500");
+
+        let (instruction, _) = Instruction::decode(&[22, 0, 64, 0]).unwrap();
+        assert_eq!(instruction.operands(), &[Operand::Address(64)]);
+        assert_eq!(instruction.to_string(), "call #64");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Instruction::decode(&[]), Err(InstructionError::Truncated{ needed: 1, available: 0 }));
+        assert_eq!(Instruction::decode(&[0, 4]), Err(InstructionError::Truncated{ needed: 4, available: 2 }));
+        assert_eq!(Instruction::decode(&[200, 0, 0, 0]), Err(InstructionError::IllegalOpcode{ code: 200 }));
+    }
+
+    #[test]
+    fn test_encode_instruction() {
+        let instruction = Instruction::with_operands(Opcode::ADD, vec![Operand::Register(0), Operand::Register(1), Operand::Register(2)]).unwrap();
+        assert_eq!(instruction.encode(), vec![1, 0, 1, 2]);
+        assert_eq!(Instruction::new(Opcode::HLT).encode(), vec![5, 0, 0, 0]);
+        assert!(Instruction::with_operands(Opcode::LOAD, vec![Operand::Register(0)]).is_err());
+        assert!(Instruction::with_operands(Opcode::DJMPE, vec![Operand::Integer(4)]).is_err());
+
+        // Encoding and decoding again gives back the same instruction
+        let (decoded, _) = Instruction::decode(&instruction.encode()).unwrap();
+        assert_eq!(decoded, instruction);
     }
 }
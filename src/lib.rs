@@ -0,0 +1,15 @@
+
+#[macro_use]
+extern crate nom;
+#[macro_use]
+extern crate log;
+extern crate byteorder;
+
+pub mod assembler;
+pub mod disassembler;
+pub mod instruction;
+pub mod linker;
+pub mod loader;
+pub mod repl;
+pub mod stdlib;
+pub mod vm;
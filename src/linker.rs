@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::error::Error;
+
+use crate::assembler::object::{encode_exports, encode_imports, Export, Import, ObjectFile};
+use crate::assembler::symbols::{Symbol, SymbolSection};
+use crate::assembler::{pie_header, PIE_HEADER_LENGTH};
+use crate::stdlib;
+
+/// Combines relocatable object files into a single executable PIE program.
+/// Code from the first object is placed first, so it is where execution begins.
+#[derive(Debug, Default)]
+pub struct Linker {
+    objects: Vec<(String, ObjectFile)>,
+    /// Problems found while adding objects, reported when linking
+    errors: Vec<LinkerError>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkerError {
+    DuplicateSymbol{ name: String, first: String, second: String },
+    UndefinedSymbol{ name: String, module: String },
+    RelocationOutOfRange{ name: String, module: String, address: usize },
+    InvalidRelocation{ offset: u32, module: String },
+    IncludeError{ name: String, error: String }
+}
+
+/// Where each section of an object ends up in the linked program
+#[derive(Debug, Clone, Copy)]
+struct Placement {
+    code: usize,
+    ro: usize
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            objects: vec![],
+            errors: vec![]
+        }
+    }
+
+    /// Adds an object along with any standard library modules it includes
+    pub fn add_object(&mut self, name: &str, object: ObjectFile) {
+        let includes = object.includes.clone();
+        self.objects.push((name.to_string(), object));
+        for include in includes {
+            self.add_include(&include);
+        }
+    }
+
+    /// Standard library modules are linked in once, however many objects include them
+    fn add_include(&mut self, include: &str) {
+        let name = format!("<{}>", include);
+        if self.objects.iter().any(|(n, _)| *n == name) {
+            return;
+        }
+        match stdlib::assemble(include) {
+            Ok(object) => self.add_object(&name, object),
+            Err(error) => self.errors.push(LinkerError::IncludeError{ name: include.to_string(), error })
+        }
+    }
+
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkerError>> {
+        let mut errors = self.errors.clone();
+        let imports = self.collect_imports();
+        let globals = self.collect_globals(&mut errors);
+
+        // The tables sit between the data and the code, so their size has to be known before any
+        // code address can be worked out. Addresses are fixed width, so the placeholders are enough.
+        let mut exports = self.collect_exports(&globals);
+        let imports_table = if imports.is_empty() { vec![] } else { encode_imports(&imports) };
+        let exports_length = if exports.is_empty() { 0 } else { encode_exports(&exports).len() };
+        let placements = self.place_sections(exports_length + imports_table.len());
+        for export in &mut exports {
+            let (module, symbol) = globals[&export.name];
+            export.address = address_of(symbol, placements[module]).unwrap_or(0) as u32;
+        }
+
+        let mut code = vec![];
+        for (index, (name, object)) in self.objects.iter().enumerate() {
+            let mut module_code = object.code.clone();
+            for relocation in &object.relocations {
+                let address = match self.resolve(index, &relocation.symbol, &placements, &globals, &imports) {
+                    Some(address) => address,
+                    None => {
+                        errors.push(LinkerError::UndefinedSymbol{ name: relocation.symbol.clone(), module: name.clone() });
+                        continue;
+                    }
+                };
+                if address > usize::from(u16::MAX) {
+                    errors.push(LinkerError::RelocationOutOfRange{ name: relocation.symbol.clone(), module: name.clone(), address });
+                    continue;
+                }
+                let offset = relocation.offset as usize;
+                if offset + 2 > module_code.len() {
+                    errors.push(LinkerError::InvalidRelocation{ offset: relocation.offset, module: name.clone() });
+                    continue;
+                }
+                module_code[offset] = (address >> 8) as u8;
+                module_code[offset + 1] = address as u8;
+            }
+            code.append(&mut module_code);
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let ro: Vec<u8> = self.objects.iter().flat_map(|(_, o)| o.ro.iter().cloned()).collect();
+        let exports_table = if exports.is_empty() { vec![] } else { encode_exports(&exports) };
+        let mut program = pie_header(ro.len() as u32, 0, exports_table.len() as u32, imports_table.len() as u32);
+        program.extend(ro);
+        program.extend(exports_table);
+        program.extend(imports_table);
+        program.append(&mut code);
+        Ok(program)
+    }
+
+    /// `tables_length` is the combined size of the export and import tables that precede the code
+    fn place_sections(&self, tables_length: usize) -> Vec<Placement> {
+        let total_ro: usize = self.objects.iter().map(|(_, o)| o.ro.len()).sum();
+        let mut next = Placement {
+            code: PIE_HEADER_LENGTH + total_ro + tables_length,
+            ro: 0
+        };
+
+        let mut placements = vec![];
+        for (_, object) in &self.objects {
+            placements.push(next);
+            next.code += object.code.len();
+            next.ro += object.ro.len();
+        }
+        placements
+    }
+
+    /// Maps every exported symbol to the object that defines it, reporting symbols exported twice
+    fn collect_globals(&self, errors: &mut Vec<LinkerError>) -> HashMap<String, (usize, &Symbol)> {
+        let mut globals: HashMap<String, (usize, &Symbol)> = HashMap::new();
+        for (index, (name, object)) in self.objects.iter().enumerate() {
+            for symbol in object.exports() {
+                if let Some((first, _)) = globals.get(&symbol.name) {
+                    errors.push(LinkerError::DuplicateSymbol{
+                        name: symbol.name.clone(),
+                        first: self.objects[*first].0.clone(),
+                        second: name.clone()
+                    });
+                    continue;
+                }
+                globals.insert(symbol.name.clone(), (index, symbol));
+            }
+        }
+        globals
+    }
+
+    /// Merges the imports of all objects, so each module and symbol pair gets one slot in the import table
+    fn collect_imports(&self) -> Vec<Import> {
+        let mut imports: Vec<Import> = vec![];
+        for (_, object) in &self.objects {
+            for import in &object.imports {
+                if !imports.contains(import) {
+                    imports.push(import.clone());
+                }
+            }
+        }
+        imports
+    }
+
+    /// Exported code labels become the export table of the program, so it can be loaded as a shared
+    /// module. Their addresses are filled in once the sections have been placed.
+    fn collect_exports(&self, globals: &HashMap<String, (usize, &Symbol)>) -> Vec<Export> {
+        let mut exports: Vec<Export> = globals.iter()
+            .filter(|(_, (_, symbol))| symbol.section == Some(SymbolSection::Code))
+            .map(|(name, (_, symbol))| Export {
+                name: name.clone(),
+                version: symbol.version,
+                address: 0
+            })
+            .collect();
+        exports.sort_by(|a, b| a.name.cmp(&b.name));
+        exports
+    }
+
+    /// Symbols defined in the same object win over exported symbols from other objects. Imported
+    /// symbols resolve to their slot in the import table rather than to an address.
+    fn resolve(&self, index: usize, name: &str, placements: &[Placement], globals: &HashMap<String, (usize, &Symbol)>, imports: &[Import]) -> Option<usize> {
+        let object = &self.objects[index].1;
+        match object.symbols.get(name) {
+            Some(symbol) if symbol.is_import() => {
+                let import = object.imports.iter().find(|i| i.symbol == name)?;
+                imports.iter().position(|i| i == import)
+            },
+            Some(symbol) if symbol.is_defined() && !symbol.is_extern() => {
+                address_of(symbol, placements[index])
+            },
+            _ => {
+                let (module, symbol) = globals.get(name)?;
+                address_of(symbol, placements[*module])
+            }
+        }
+    }
+}
+
+/// Code addresses are absolute offsets into the program, while read only addresses are offsets into
+/// the read only section. Objects have no data of their own, so data labels are offsets into the heap.
+fn address_of(symbol: &Symbol, placement: Placement) -> Option<usize> {
+    let offset = symbol.offset? as usize;
+    match symbol.section? {
+        SymbolSection::Code => Some(placement.code + offset),
+        SymbolSection::Data => Some(offset),
+        SymbolSection::ReadOnly => Some(placement.ro + offset)
+    }
+}
+
+impl fmt::Display for LinkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkerError::DuplicateSymbol{ name, first, second } => {
+                f.write_str(&format!("Symbol {} is defined in both {} and {}", name, first, second))
+            },
+            LinkerError::UndefinedSymbol{ name, module } => {
+                f.write_str(&format!("Undefined symbol {} referenced in {}", name, module))
+            },
+            LinkerError::RelocationOutOfRange{ name, module, address } => {
+                f.write_str(&format!("Address {} of symbol {} referenced in {} does not fit in 16 bits", address, name, module))
+            },
+            LinkerError::InvalidRelocation{ offset, module } => {
+                f.write_str(&format!("Relocation at offset {} in {} is outside the code section", offset, module))
+            },
+            LinkerError::IncludeError{ name, error } => {
+                f.write_str(&format!("Unable to include <{}>: {}", name, error))
+            }
+        }
+    }
+}
+
+impl Error for LinkerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn object(source: &str) -> ObjectFile {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_two_modules() {
+        let main = object(".extern answer\n.data\n.code\ncall @answer\nload $2 #7\nhlt");
+        let lib = object(".global answer\n.data\n.code\nhlt\nanswer: load $1 #42\nret");
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        linker.add_object("lib.o", lib);
+        let program = linker.link().unwrap();
+
+        // main's three instructions come first, then lib's `hlt` so `answer` sits 16 bytes in
+        let code_start = crate::loader::PieLayout::parse("main", &program).unwrap().code_start;
+        let answer = code_start + 16;
+        assert_eq!(program[code_start + 1], (answer >> 8) as u8);
+        assert_eq!(program[code_start + 2], answer as u8);
+
+        let mut vm = VM::default();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.registers[1], 42);
+        // The call came back to main, which carried on from after it
+        assert_eq!(vm.registers[2], 7);
+    }
+
+    #[test]
+    fn test_link_ro_sections() {
+        let main = object(".data\nhello: .asciiz 'Hi'\n.code\nprts @hello\nhlt");
+        let lib = object(".data\nbye: .asciiz 'Bye'\n.code\nprts @bye\nhlt");
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        linker.add_object("lib.o", lib);
+        let program = linker.link().unwrap();
+
+        // Both read only sections are concatenated, so `bye` starts after "Hi\0"
+        let code_start = PIE_HEADER_LENGTH + 7;
+        assert_eq!(&program[PIE_HEADER_LENGTH..code_start], b"Hi\0Bye\0");
+        assert_eq!(program[code_start + 8..code_start + 11], [21, 0, 3]);
+    }
+
+    #[test]
+    fn test_link_import_and_export_tables() {
+        let main = object(".import mathlib.sqrt #2\n.import mathlib.abs\n.data\n.code\ncallx @abs\ncallx @sqrt\nhlt");
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        let program = linker.link().unwrap();
+        let layout = crate::loader::PieLayout::parse("main", &program).unwrap();
+        assert_eq!(layout.imports, vec![
+            Import { module: "mathlib".to_string(), symbol: "sqrt".to_string(), version: Some(2) },
+            Import { module: "mathlib".to_string(), symbol: "abs".to_string(), version: None }
+        ]);
+        // `callx` operands are indexes into the import table
+        assert_eq!(program[layout.code_start..layout.code_start + 8], [24, 0, 1, 0, 24, 0, 0, 0]);
+
+        let lib = object(".global sqrt #2\n.data\n.code\nhlt\nsqrt: ret");
+        let mut linker = Linker::new();
+        linker.add_object("lib.o", lib);
+        let program = linker.link().unwrap();
+        let layout = crate::loader::PieLayout::parse("lib", &program).unwrap();
+        assert_eq!(layout.exports, vec![Export { name: "sqrt".to_string(), version: Some(2), address: layout.code_start as u32 + 4 }]);
+    }
+
+    #[test]
+    fn test_duplicate_symbol() {
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(".global main\n.data\n.code\nmain: hlt"));
+        linker.add_object("b.o", object(".global main\n.data\n.code\nmain: hlt"));
+        let errors = linker.link().unwrap_err();
+        assert_eq!(errors, vec![LinkerError::DuplicateSymbol{ name: "main".to_string(), first: "a.o".to_string(), second: "b.o".to_string() }]);
+    }
+
+    #[test]
+    fn test_undefined_symbol() {
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(".extern missing\n.data\n.code\ndjmpe @missing"));
+        let errors = linker.link().unwrap_err();
+        assert_eq!(errors, vec![LinkerError::UndefinedSymbol{ name: "missing".to_string(), module: "a.o".to_string() }]);
+    }
+
+    #[test]
+    fn test_includes_are_linked_once() {
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(".include <std/io>\n.data\n.code\nhlt"));
+        linker.add_object("b.o", object(".include <std/string>\n.global b\n.data\n.code\nb: ret"));
+        let names: Vec<&str> = linker.objects.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["a.o", "<std/io>", "<std/string>", "b.o"]);
+        assert!(linker.link().is_ok());
+    }
+}
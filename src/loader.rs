@@ -0,0 +1,216 @@
+use std::fmt;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::assembler::object::{decode_exports, decode_imports, Export, Import};
+use crate::assembler::{PIE_DATA_LENGTH_OFFSET, PIE_EXPORTS_LENGTH_OFFSET, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX,
+                       PIE_IMPORTS_LENGTH_OFFSET, PIE_RO_LENGTH_OFFSET};
+
+/// File extension shared modules are expected to have on the search path
+pub const MODULE_EXTENSION: &str = "pie";
+
+/// Where each part of a PIE program lives, as read from its header
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieLayout {
+    pub ro: Range<usize>,
+    pub data: Range<usize>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+    pub code_start: usize
+}
+
+/// Finds shared modules by name in a list of directories
+#[derive(Debug, Clone, Default)]
+pub struct ModuleLoader {
+    pub search_paths: Vec<PathBuf>
+}
+
+/// A shared module loaded to satisfy an import. Each module keeps its own program and read only data,
+/// which the VM swaps in while code from the module is running.
+#[derive(Debug, Clone, Default)]
+pub struct LoadedModule {
+    pub name: String,
+    pub program: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub exports: Vec<Export>,
+    /// For each slot of the module's import table, the module and address the slot resolved to
+    pub imports: Vec<(usize, usize)>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    BadHeader{ module: String },
+    InvalidTable{ module: String, error: String },
+    ModuleNotFound{ module: String, searched: Vec<PathBuf> },
+    UnreadableModule{ module: String, error: String },
+    SymbolNotFound{ module: String, symbol: String },
+    VersionMismatch{ module: String, symbol: String, required: u16, found: Option<u16> },
+    UnsupportedDataSection{ module: String }
+}
+
+impl PieLayout {
+    pub fn parse(module: &str, program: &[u8]) -> Result<PieLayout, LoadError> {
+        if program.len() < PIE_HEADER_LENGTH || program[0..4] != PIE_HEADER_PREFIX {
+            return Err(LoadError::BadHeader{ module: module.to_string() });
+        }
+
+        let ro_length = LittleEndian::read_u32(&program[PIE_RO_LENGTH_OFFSET..]) as usize;
+        let data_length = LittleEndian::read_u32(&program[PIE_DATA_LENGTH_OFFSET..]) as usize;
+        let exports_length = LittleEndian::read_u32(&program[PIE_EXPORTS_LENGTH_OFFSET..]) as usize;
+        let imports_length = LittleEndian::read_u32(&program[PIE_IMPORTS_LENGTH_OFFSET..]) as usize;
+
+        let ro = PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + ro_length;
+        let data = ro.end..ro.end + data_length;
+        let exports = data.end..data.end + exports_length;
+        let imports = exports.end..exports.end + imports_length;
+        if imports.end > program.len() {
+            return Err(LoadError::BadHeader{ module: module.to_string() });
+        }
+
+        let invalid_table = |e| LoadError::InvalidTable{ module: module.to_string(), error: format!("{}", e) };
+        let code_start = imports.end;
+        let exports = if exports.is_empty() { vec![] } else { decode_exports(&program[exports]).map_err(invalid_table)? };
+        let imports = if imports.is_empty() { vec![] } else { decode_imports(&program[imports]).map_err(invalid_table)? };
+
+        Ok(PieLayout { ro, data, exports, imports, code_start })
+    }
+}
+
+impl ModuleLoader {
+    pub fn new() -> ModuleLoader {
+        ModuleLoader {
+            search_paths: vec![]
+        }
+    }
+
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.search_paths.push(path.into());
+    }
+
+    /// Returns the first `<module>.pie` found on the search path
+    pub fn find(&self, module: &str) -> Result<PathBuf, LoadError> {
+        for directory in &self.search_paths {
+            let candidate = directory.join(module).with_extension(MODULE_EXTENSION);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+        Err(LoadError::ModuleNotFound{ module: module.to_string(), searched: self.search_paths.clone() })
+    }
+
+    /// Reads and checks a module, returning it with its import table still unresolved
+    pub fn load(&self, module: &str) -> Result<LoadedModule, LoadError> {
+        let path = self.find(module)?;
+        let mut program = vec![];
+        File::open(&path)
+            .and_then(|mut fh| fh.read_to_end(&mut program))
+            .map_err(|e| LoadError::UnreadableModule{ module: module.to_string(), error: e.to_string() })?;
+
+        let layout = PieLayout::parse(module, &program)?;
+        if !layout.data.is_empty() {
+            return Err(LoadError::UnsupportedDataSection{ module: module.to_string() });
+        }
+        Ok(LoadedModule {
+            name: module.to_string(),
+            ro_data: program[layout.ro].to_vec(),
+            program,
+            exports: layout.exports,
+            imports: vec![]
+        })
+    }
+}
+
+impl LoadedModule {
+    /// Finds the address of an export, checking it has the version the import asked for
+    pub fn resolve(&self, import: &Import) -> Result<usize, LoadError> {
+        let export = match self.exports.iter().find(|e| e.name == import.symbol) {
+            Some(export) => export,
+            None => return Err(LoadError::SymbolNotFound{ module: self.name.clone(), symbol: import.symbol.clone() })
+        };
+        if let Some(required) = import.version {
+            if export.version != Some(required) {
+                return Err(LoadError::VersionMismatch{
+                    module: self.name.clone(),
+                    symbol: import.symbol.clone(),
+                    required,
+                    found: export.version
+                });
+            }
+        }
+        Ok(export.address as usize)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadHeader{ module } => {
+                f.write_str(&format!("Module {} does not have a valid PIE header", module))
+            },
+            LoadError::InvalidTable{ module, error } => {
+                f.write_str(&format!("Module {} has an invalid import or export table: {}", module, error))
+            },
+            LoadError::ModuleNotFound{ module, searched } => {
+                f.write_str(&format!("Module {} was not found in the search path {:?}", module, searched))
+            },
+            LoadError::UnreadableModule{ module, error } => {
+                f.write_str(&format!("Module {} could not be read: {}", module, error))
+            },
+            LoadError::SymbolNotFound{ module, symbol } => {
+                f.write_str(&format!("Module {} does not export {}", module, symbol))
+            },
+            LoadError::VersionMismatch{ module, symbol, required, found } => {
+                match found {
+                    Some(found) => f.write_str(&format!("Module {} exports version {} of {}, but version {} is required", module, found, symbol, required)),
+                    None => f.write_str(&format!("Module {} exports an unversioned {}, but version {} is required", module, symbol, required))
+                }
+            },
+            LoadError::UnsupportedDataSection{ module } => {
+                f.write_str(&format!("Module {} has a data section, which shared modules cannot have", module))
+            }
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(exports: Vec<Export>) -> LoadedModule {
+        LoadedModule {
+            name: "mathlib".to_string(),
+            exports,
+            ..LoadedModule::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_versions() {
+        let lib = module(vec![Export { name: "sqrt".to_string(), version: Some(2), address: 100 }]);
+        let any = Import { module: "mathlib".to_string(), symbol: "sqrt".to_string(), version: None };
+        assert_eq!(lib.resolve(&any), Ok(100));
+        let two = Import { version: Some(2), ..any.clone() };
+        assert_eq!(lib.resolve(&two), Ok(100));
+        let three = Import { version: Some(3), ..any.clone() };
+        assert!(lib.resolve(&three).is_err());
+        let missing = Import { symbol: "cbrt".to_string(), ..any };
+        assert_eq!(lib.resolve(&missing), Err(LoadError::SymbolNotFound{ module: "mathlib".to_string(), symbol: "cbrt".to_string() }));
+    }
+
+    #[test]
+    fn test_module_not_found() {
+        let mut loader = ModuleLoader::new();
+        loader.add_search_path("/does/not/exist");
+        match loader.load("mathlib") {
+            Err(LoadError::ModuleNotFound{ module, .. }) => assert_eq!(module, "mathlib"),
+            other => panic!("Expected ModuleNotFound, got {:?}", other)
+        }
+    }
+}
@@ -1,61 +1,235 @@
 #[macro_use]
 extern crate clap;
 #[macro_use]
-extern crate nom;
-#[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate byteorder;
+extern crate iridium;
 
+#[allow(unused_imports)]
 use clap::{Arg, App, SubCommand};
+use log::LevelFilter;
 use std::fs::File;
 use std::io::Read;
 use std::io::prelude::*;
 use std::path::Path;
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+use iridium::{assembler, instruction, linker, repl, vm};
 
 fn main() {
-    env_logger::init();
-    info!("Starting logging!");
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
+    let trace = matches.is_present("TRACE");
+    let mut logger = env_logger::Builder::from_default_env();
+    if trace {
+        logger.filter(Some("iridium::vm"), LevelFilter::Trace);
+    }
+    logger.init();
+    info!("Starting logging!");
+
+    if let Some(matches) = matches.subcommand_matches("assemble") {
+        let source = read_file(matches.value_of("INPUT_FILE").unwrap());
+        let mut asm = assembler::Assembler::new();
+        asm.optimize = matches.is_present("OPTIMIZE");
+        match asm.assemble_object(&source) {
+            Ok(object) => {
+                for line in asm.optimizer_report() {
+                    println!("{}", line);
+                }
+                if matches.is_present("LISTING") {
+                    for line in asm.listing(&object.code) {
+                        println!("{}", line);
+                    }
+                }
+                write_file(matches.value_of("OUTPUT_FILE").unwrap(), &object.to_bytes());
+                std::process::exit(0);
+            },
+            Err(errors) => {
+                for error in errors {
+                    println!("{}", error);
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.subcommand_matches("isa").is_some() {
+        print!("{}", instruction::isa_json());
+        std::process::exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("link") {
+        let mut linker = linker::Linker::new();
+        for filename in matches.values_of("OBJECT_FILES").unwrap() {
+            match assembler::object::ObjectFile::from_bytes(&read_bytes(filename)) {
+                Ok(object) => linker.add_object(filename, object),
+                Err(e) => {
+                    println!("Unable to read {}: {}", filename, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        match linker.link() {
+            Ok(program) => {
+                write_file(matches.value_of("OUTPUT_FILE").unwrap(), &program);
+                std::process::exit(0);
+            },
+            Err(errors) => {
+                for error in errors {
+                    println!("{}", error);
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(filename) = matches.value_of("RESUME") {
+        let mut vm = configure_vm(&matches, trace);
+        if let Err(e) = vm.restore(&read_bytes(filename)) {
+            println!("Unable to resume from {}: {}", filename, e);
+            std::process::exit(1);
+        }
+        if matches.is_present("PROFILE") || matches.is_present("PROFILE_FOLDED") {
+            vm.profiler = Some(vm::profiler::Profiler::new(None));
+        }
+        vm.resume();
+        report(&matches, &vm);
+        std::process::exit(if vm.error().is_some() { 1 } else { 0 });
+    }
+
     let target_file = matches.value_of("INPUT_FILE");
     match target_file {
         Some(filename) => {
-            let program = read_file(filename);
-            let mut asm = assembler::Assembler::default();
-            let mut vm = vm::VM::default();
-            let program = asm.assemble(&program);
+            let bytes = read_bytes(filename);
+            let (program, debug_info) = if bytes.starts_with(&assembler::PIE_HEADER_PREFIX) {
+                (Ok(bytes), None)
+            } else {
+                let source = String::from_utf8_lossy(&bytes);
+                let mut asm = assembler::Assembler::new();
+                asm.optimize = matches.is_present("OPTIMIZE");
+                let program = asm.assemble(&source);
+                for line in asm.optimizer_report() {
+                    info!("{}", line);
+                }
+                let debug_info = program.as_ref().ok().map(|program| asm.debug_info(&source, program));
+                (program, debug_info)
+            };
+            let mut vm = configure_vm(&matches, trace);
+            if matches.is_present("PROFILE") || matches.is_present("PROFILE_FOLDED") {
+                vm.profiler = Some(vm::profiler::Profiler::new(debug_info));
+            }
+            // Modules next to the program are found without any extra flags
+            if let Some(directory) = Path::new(filename).parent() {
+                vm.loader.add_search_path(directory);
+            }
             match program {
                 Ok(p) => {
                     vm.add_bytes(p);
                     vm.run();
-                    std::process::exit(0);
+                    report(&matches, &vm);
+                    std::process::exit(if vm.error().is_some() { 1 } else { 0 });
                 },
-                Err(_e) => {}
+                Err(errors) => {
+                    for error in errors {
+                        println!("{}", error);
+                    }
+                    std::process::exit(1);
+                }
             }
         },
         None => {
-            start_repl();
+            start_repl(trace);
         }
     }
 }
 
-fn start_repl() {
+/// Sets a VM up the way the flags ask, for running a program or resuming one from a snapshot
+fn configure_vm(matches: &clap::ArgMatches, trace: bool) -> vm::VM {
+    let mut vm = vm::VM::default();
+    vm.trace = trace;
+    vm.files.root = matches.value_of("FILE_ROOT").map(std::path::PathBuf::from);
+    vm.gc.enabled = matches.is_present("GC");
+    vm.tagged = matches.is_present("TAGGED");
+    if let Some(max_heap) = matches.value_of("MAX_HEAP") {
+        match max_heap.parse() {
+            Ok(max_heap) => vm.allocator.max_heap = max_heap,
+            Err(e) => {
+                println!("Invalid maximum heap size {}: {}", max_heap, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let policy = match (matches.value_of("SANDBOX"), matches.value_of("SANDBOX_CONFIG")) {
+        (Some(spec), _) => Some(vm::sandbox::SandboxPolicy::parse(spec)),
+        (None, Some(config)) => Some(vm::sandbox::SandboxPolicy::parse(&read_file(config))),
+        (None, None) => None
+    };
+    match policy {
+        Some(Ok(policy)) => vm.sandbox = policy,
+        Some(Err(e)) => {
+            println!("Invalid sandbox policy: {}", e);
+            std::process::exit(1);
+        },
+        None => {}
+    }
+    if let Some(paths) = matches.values_of("LIBRARY_PATH") {
+        for path in paths {
+            vm.loader.add_search_path(path);
+        }
+    }
+    if let Some(trace_file) = matches.value_of("TRACE_FILE") {
+        let mut filter = vm::recorder::TraceFilter::default();
+        let parsed = matches.value_of("TRACE_PC").map(vm::recorder::TraceFilter::parse_pc_range).transpose()
+            .and_then(|range| Ok((range, matches.value_of("TRACE_OPCODES").map(vm::recorder::TraceFilter::parse_opcodes).transpose()?)));
+        match parsed {
+            Ok((pc_range, opcodes)) => {
+                filter.pc_range = pc_range;
+                filter.opcodes = opcodes.unwrap_or_default();
+            },
+            Err(e) => {
+                println!("Invalid trace filter: {}", e);
+                std::process::exit(1);
+            }
+        }
+        match vm::recorder::TraceRecorder::create(trace_file, filter) {
+            Ok(recorder) => vm.recorder = Some(recorder),
+            Err(e) => {
+                println!("Unable to create trace file {}: {}", trace_file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    vm
+}
+
+/// Prints the statistics the flags ask for once a program has stopped
+fn report(matches: &clap::ArgMatches, vm: &vm::VM) {
+    if matches.is_present("GC_STATS") {
+        eprintln!("GC: {}", vm.gc.stats);
+    }
+    if matches.is_present("PROFILE") {
+        eprint!("{}", vm.profile_report().unwrap_or_default());
+    }
+    if let Some(folded) = matches.value_of("PROFILE_FOLDED") {
+        write_file(folded, vm.folded_stacks().unwrap_or_default().as_bytes());
+    }
+}
+
+fn start_repl(trace: bool) {
     let mut repl = repl::REPL::default();
+    repl.set_trace(trace);
     repl.run();
 }
 
 fn read_file(tmp: &str) -> String {
+    String::from_utf8_lossy(&read_bytes(tmp)).to_string()
+}
+
+fn read_bytes(tmp: &str) -> Vec<u8> {
     let filename = Path::new(tmp);
     match File::open(Path::new(filename)) {
         Ok(mut fh) => {
-            let mut contents = String::new();
-            match fh.read_to_string(&mut contents) {
+            let mut contents = vec![];
+            match fh.read_to_end(&mut contents) {
                 Ok(_) => {
                     contents
                 },
@@ -71,3 +245,11 @@ fn read_file(tmp: &str) -> String {
         }
     }
 }
+
+fn write_file(tmp: &str, contents: &[u8]) {
+    let result = File::create(Path::new(tmp)).and_then(|mut fh| fh.write_all(contents));
+    if let Err(e) = result {
+        println!("There was an error writing the file: {:?}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::assembler::debug_info::DebugInfo;
+use crate::assembler::registers::{abi_name, RegisterAliases};
+use crate::disassembler::Disassembler;
+use crate::instruction::{Opcode, INSTRUCTION_LENGTH};
+use crate::loader::{LoadError, PieLayout};
+use crate::vm::{MAIN_MODULE, VM};
+
+/// Instructions shown on either side of the current one
+const CONTEXT: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugError {
+    UnknownLabel{ name: String },
+    InvalidAddress{ address: String },
+    /// A watchpoint or condition named something other than a register or `heap[address]`
+    InvalidTarget{ target: String },
+    InvalidCondition{ condition: String },
+    NoSuchPoint{ id: usize },
+    /// The program has stopped, or was never loaded
+    NotRunning
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::UnknownLabel{ name } => f.write_str(&format!("There is no label named {}", name)),
+            DebugError::InvalidAddress{ address } => f.write_str(&format!("{} is not an address or a label", address)),
+            DebugError::InvalidTarget{ target } => {
+                f.write_str(&format!("{} is not a register or heap[address]", target))
+            },
+            DebugError::InvalidCondition{ condition } => {
+                f.write_str(&format!("{} is not a condition like $3 == 10", condition))
+            },
+            DebugError::NoSuchPoint{ id } => f.write_str(&format!("There is no breakpoint or watchpoint {}", id)),
+            DebugError::NotRunning => f.write_str("The program is not running, load it with .load_file")
+        }
+    }
+}
+
+impl Error for DebugError {}
+
+/// Something a watchpoint or condition can look at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Register(u8),
+    /// A byte of the heap
+    Heap(u32)
+}
+
+impl Target {
+    /// Reads `$3`, `$t0`, an alias, or `heap[16]`
+    pub fn parse(text: &str, aliases: &RegisterAliases) -> Result<Target, DebugError> {
+        let invalid = || DebugError::InvalidTarget{ target: text.to_string() };
+        if let Some(name) = text.strip_prefix('$') {
+            let register = name.parse::<u8>().ok().or_else(|| aliases.lookup(name)).ok_or_else(invalid)?;
+            return if (register as usize) < 32 { Ok(Target::Register(register)) } else { Err(invalid()) };
+        }
+        let address = text.strip_prefix("heap[").and_then(|rest| rest.strip_suffix(']')).ok_or_else(invalid)?;
+        parse_number(address).map(Target::Heap).ok_or_else(invalid)
+    }
+
+    /// The value now, or `None` for a heap byte past the end of the heap
+    pub fn read(self, vm: &VM) -> Option<i32> {
+        match self {
+            Target::Register(register) => Some(vm.registers[register as usize]),
+            Target::Heap(address) => vm.heap().get(address as usize).map(|byte| i32::from(*byte))
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Register(register) => match abi_name(*register) {
+                Some(name) => write!(f, "${}", name),
+                None => write!(f, "${}", register)
+            },
+            Target::Heap(address) => write!(f, "heap[{}]", address)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Target(Target),
+    Constant(i32)
+}
+
+impl Value {
+    fn parse(text: &str, aliases: &RegisterAliases) -> Result<Value, DebugError> {
+        match text.strip_prefix('#').unwrap_or(text).parse::<i32>() {
+            Ok(constant) => Ok(Value::Constant(constant)),
+            Err(_) => Target::parse(text, aliases).map(Value::Target)
+        }
+    }
+
+    fn read(self, vm: &VM) -> Option<i32> {
+        match self {
+            Value::Target(target) => target.read(vm),
+            Value::Constant(constant) => Some(constant)
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Target(target) => write!(f, "{}", target),
+            Value::Constant(constant) => write!(f, "{}", constant)
+        }
+    }
+}
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+/// A comparison a conditional breakpoint checks, such as `$3 == 10` or `heap[8] > $t0`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    left: Value,
+    comparison: &'static str,
+    right: Value
+}
+
+impl Condition {
+    pub fn parse(text: &str, aliases: &RegisterAliases) -> Result<Condition, DebugError> {
+        let invalid = || DebugError::InvalidCondition{ condition: text.to_string() };
+        let (position, comparison) = COMPARISONS.iter()
+            .filter_map(|comparison| text.find(comparison).map(|position| (position, *comparison)))
+            .min_by_key(|(position, _)| *position)
+            .ok_or_else(invalid)?;
+        let left = Value::parse(text[..position].trim(), aliases).map_err(|_| invalid())?;
+        let right = Value::parse(text[position + comparison.len()..].trim(), aliases).map_err(|_| invalid())?;
+        Ok(Condition { left, comparison, right })
+    }
+
+    /// Whether the comparison holds. Heap bytes past the end of the heap never compare.
+    pub fn holds(&self, vm: &VM) -> bool {
+        let (left, right) = match (self.left.read(vm), self.right.read(vm)) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return false
+        };
+        match self.comparison {
+            "==" => left == right,
+            "!=" => left != right,
+            "<=" => left <= right,
+            ">=" => left >= right,
+            "<" => left < right,
+            _ => left > right
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.left, self.comparison, self.right)
+    }
+}
+
+/// Why the program stopped running
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// It got to a breakpoint, whose condition held if it has one
+    Breakpoint{ id: usize, pc: usize },
+    /// Something being watched changed
+    Watchpoint{ id: usize, target: Target, old: Option<i32>, new: Option<i32> },
+    /// It ran as many instructions as it was asked to
+    Stepped,
+    /// It got to `hlt` or the end of the program
+    Halted,
+    /// It stopped with an error
+    Failed{ error: String }
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |value: &Option<i32>| value.map_or("nothing".to_string(), |v| v.to_string());
+        match self {
+            Stop::Breakpoint{ id, pc } => write!(f, "Breakpoint {} at {}", id, pc),
+            Stop::Watchpoint{ id, target, old, new } => {
+                write!(f, "Watchpoint {}: {} changed from {} to {}", id, target, show(old), show(new))
+            },
+            Stop::Stepped => f.write_str("Stepped"),
+            Stop::Halted => f.write_str("The program has finished"),
+            Stop::Failed{ error } => write!(f, "The program stopped: {}", error)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Point {
+    /// Stops before running the instruction at a `pc` of the main program
+    Breakpoint{ pc: usize, condition: Option<Condition> },
+    /// Stops after an instruction that changed the target, and remembers its value
+    Watchpoint{ target: Target, value: Option<i32> }
+}
+
+/// Breakpoints and watchpoints for running a program in the REPL a little at a time. They share one
+/// list of numbers, so `.delete` works on either.
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    /// Lets breakpoints be set on labels, and the context show source lines
+    pub debug_info: Option<DebugInfo>,
+    points: BTreeMap<usize, Point>,
+    next_id: usize,
+    /// Set while there is a loaded program that has not stopped yet
+    running: bool
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Loads the VM's program and stops before its first instruction
+    pub fn load(&mut self, vm: &mut VM, debug_info: Option<DebugInfo>) -> Result<(), LoadError> {
+        self.running = false;
+        self.debug_info = debug_info;
+        vm.start_paused()?;
+        self.running = true;
+        self.read_watched(vm);
+        Ok(())
+    }
+
+    /// Picks up a VM whose state was just restored from a snapshot, so it carries on from there. The
+    /// debug info belonged to the program loaded before, so it is dropped.
+    pub fn restored(&mut self, vm: &VM) {
+        self.running = vm.error().is_none();
+        self.debug_info = None;
+        self.read_watched(vm);
+    }
+
+    /// Remembers the current value of everything being watched
+    fn read_watched(&mut self, vm: &VM) {
+        for point in self.points.values_mut() {
+            if let Point::Watchpoint{ target, value } = point {
+                *value = target.read(vm);
+            }
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Sets a breakpoint from `.break` arguments: an address, or a label with or without its `@`,
+    /// then optionally `if` and a condition. Returns the breakpoint's number.
+    pub fn add_breakpoint(&mut self, spec: &str, aliases: &RegisterAliases) -> Result<usize, DebugError> {
+        let (location, condition) = match spec.find(" if ") {
+            Some(position) => (&spec[..position], Some(Condition::parse(spec[position + 4..].trim(), aliases)?)),
+            None => (spec, None)
+        };
+        let location = location.trim();
+        let pc = match parse_number(location) {
+            Some(pc) => pc as usize,
+            None => {
+                let name = location.strip_prefix('@').unwrap_or(location);
+                if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(DebugError::InvalidAddress{ address: location.to_string() });
+                }
+                self.debug_info.as_ref()
+                    .and_then(|debug| debug.labels.iter().find(|(_, label)| *label == name).map(|(pc, _)| *pc))
+                    .ok_or_else(|| DebugError::UnknownLabel{ name: name.to_string() })?
+            }
+        };
+        Ok(self.add(Point::Breakpoint{ pc, condition }))
+    }
+
+    /// Watches a register or heap byte given as `.watch` arguments. Returns the watchpoint's number.
+    pub fn add_watchpoint(&mut self, spec: &str, aliases: &RegisterAliases, vm: &VM) -> Result<usize, DebugError> {
+        let target = Target::parse(spec.trim(), aliases)?;
+        Ok(self.add(Point::Watchpoint{ target, value: target.read(vm) }))
+    }
+
+    /// Deletes one breakpoint or watchpoint, or all of them
+    pub fn delete(&mut self, id: Option<usize>) -> Result<(), DebugError> {
+        match id {
+            Some(id) => self.points.remove(&id).map(|_| ()).ok_or(DebugError::NoSuchPoint{ id }),
+            None => {
+                self.points.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// One line for each breakpoint and watchpoint
+    pub fn points(&self) -> Vec<String> {
+        self.points.iter().map(|(id, point)| match point {
+            Point::Breakpoint{ pc, condition } => {
+                let location = self.location(*pc).map_or(String::new(), |l| format!(" ({})", l));
+                let condition = condition.as_ref().map_or(String::new(), |c| format!(" if {}", c));
+                format!("{}: breakpoint at {}{}{}", id, pc, location, condition)
+            },
+            Point::Watchpoint{ target, .. } => format!("{}: watchpoint on {}", id, target)
+        }).collect()
+    }
+
+    /// Runs `count` instructions, stopping early at breakpoints and watchpoints
+    pub fn step(&mut self, vm: &mut VM, count: usize) -> Result<Stop, DebugError> {
+        self.run(vm, Some(count), None)
+    }
+
+    /// Runs one instruction, or a whole call if the instruction is a call
+    pub fn next(&mut self, vm: &mut VM) -> Result<Stop, DebugError> {
+        match vm.program.get(vm.pc()).map(|code| Opcode::from(*code)) {
+            Some(Opcode::CALL) | Some(Opcode::CALLX) => {
+                let depth = vm.call_depth();
+                self.run(vm, None, Some(depth))
+            },
+            _ => self.run(vm, Some(1), None)
+        }
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program
+    pub fn resume(&mut self, vm: &mut VM) -> Result<Stop, DebugError> {
+        self.run(vm, None, None)
+    }
+
+    /// The instructions around `pc`, with the current one marked by `=>` and breakpoints by `*`
+    pub fn context(&self, vm: &VM, aliases: &RegisterAliases) -> Vec<String> {
+        let code_start = PieLayout::parse(MAIN_MODULE, &vm.program).map_or(0, |layout| layout.code_start);
+        let pc = vm.pc();
+        let first = pc.saturating_sub(CONTEXT * INSTRUCTION_LENGTH).max(code_start);
+        let last = (pc + CONTEXT * INSTRUCTION_LENGTH).min(vm.program.len().saturating_sub(INSTRUCTION_LENGTH));
+        let in_main = vm.current_module() == 0;
+        let disassembler = Disassembler::with_aliases(aliases.clone());
+
+        let mut lines = vec![];
+        for address in (first..=last).step_by(INSTRUCTION_LENGTH) {
+            let label = self.debug_info.as_ref().filter(|_| in_main).and_then(|debug| debug.labels.get(&address));
+            if let Some(label) = label {
+                lines.push(format!("{}:", label));
+            }
+            let marker = if address == pc { "=>" } else { "  " };
+            let breakpoint = if in_main && self.has_breakpoint(address) { "*" } else { " " };
+            let line = format!("{}{} {:04}: {}", marker, breakpoint, address, disassembler.instruction(&vm.program[address..]));
+            let source = self.debug_info.as_ref().filter(|_| in_main)
+                .and_then(|debug| debug.line_at(address).and_then(|n| Some((n, debug.source_line(n)?))));
+            match source {
+                Some((number, text)) => lines.push(format!("{:<36}; line {}: {}", line, number, text)),
+                None => lines.push(line)
+            }
+        }
+        lines
+    }
+
+    fn add(&mut self, point: Point) -> usize {
+        self.next_id += 1;
+        self.points.insert(self.next_id, point);
+        self.next_id
+    }
+
+    fn location(&self, pc: usize) -> Option<String> {
+        self.debug_info.as_ref()?.location(pc)
+    }
+
+    fn has_breakpoint(&self, address: usize) -> bool {
+        self.points.values().any(|point| matches!(point, Point::Breakpoint{ pc, .. } if *pc == address))
+    }
+
+    /// Runs until `limit` instructions have run, the call depth drops back to `depth`, or something
+    /// stops the program. Breakpoints are checked once the program gets to them, so the instruction the
+    /// program is stopped at always runs first.
+    fn run(&mut self, vm: &mut VM, limit: Option<usize>, depth: Option<usize>) -> Result<Stop, DebugError> {
+        if !self.running {
+            return Err(DebugError::NotRunning);
+        }
+        let mut steps = 0;
+        loop {
+            let is_done = vm.run_once();
+            steps += 1;
+            if is_done {
+                self.running = false;
+                return Ok(vm.error().map_or(Stop::Halted, |e| Stop::Failed{ error: e.to_string() }));
+            }
+            if let Some(stop) = self.check_points(vm) {
+                return Ok(stop);
+            }
+            if limit == Some(steps) || depth.is_some_and(|depth| vm.call_depth() <= depth) {
+                return Ok(Stop::Stepped);
+            }
+        }
+    }
+
+    /// Updates the watched values, and finds the first watchpoint that changed or breakpoint that was hit
+    fn check_points(&mut self, vm: &VM) -> Option<Stop> {
+        let mut stop = None;
+        for (id, point) in self.points.iter_mut() {
+            match point {
+                Point::Watchpoint{ target, value } => {
+                    let new = target.read(vm);
+                    if new != *value {
+                        let old = std::mem::replace(value, new);
+                        stop = stop.or(Some(Stop::Watchpoint{ id: *id, target: *target, old, new }));
+                    }
+                },
+                Point::Breakpoint{ pc, condition } => {
+                    let hit = vm.current_module() == 0 && vm.pc() == *pc && condition.as_ref().is_none_or(|c| c.holds(vm));
+                    if hit && stop.is_none() {
+                        stop = Some(Stop::Breakpoint{ id: *id, pc: *pc });
+                    }
+                }
+            }
+        }
+        stop
+    }
+}
+
+/// Reads a number written in decimal or, with `0x`, in hex
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const PROGRAM: &str = ".data\n.code\nload $t0 #0\nload $t1 #20\nload $t3 #1\n\
+                           loop: call @bump\nneq $t0 $t1\ndjmpe @loop\nhlt\n\
+                           bump: add $t0 $t3 $t0\nret";
+
+    fn load() -> (Debugger, VM) {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(PROGRAM).unwrap();
+        let debug_info = asm.debug_info(PROGRAM, &program);
+        let mut vm = VM::default();
+        vm.program = program;
+        let mut debugger = Debugger::new();
+        debugger.load(&mut vm, Some(debug_info)).unwrap();
+        (debugger, vm)
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (mut debugger, mut vm) = load();
+        let aliases = RegisterAliases::new();
+        assert_eq!(debugger.add_breakpoint("@loop if $t0 == 10", &aliases), Ok(1));
+        assert_eq!(debugger.add_breakpoint("nowhere", &aliases), Err(DebugError::UnknownLabel{ name: "nowhere".to_string() }));
+        assert!(debugger.add_breakpoint("@loop if $t0 = 10", &aliases).is_err());
+
+        assert_eq!(debugger.resume(&mut vm), Ok(Stop::Breakpoint{ id: 1, pc: 76 }));
+        assert_eq!(vm.registers[4], 10);
+        assert_eq!(debugger.points(), vec!["1: breakpoint at 76 (loop) if $t0 == 10"]);
+        // The breakpoint's own instruction runs before it can be hit again
+        debugger.delete(Some(1)).unwrap();
+        assert_eq!(debugger.add_breakpoint("88", &aliases), Ok(2));
+        assert_eq!(debugger.resume(&mut vm), Ok(Stop::Breakpoint{ id: 2, pc: 88 }));
+        debugger.delete(None).unwrap();
+        assert_eq!(debugger.resume(&mut vm), Ok(Stop::Halted));
+        assert_eq!(vm.registers[4], 20);
+        assert_eq!(debugger.step(&mut vm, 1), Err(DebugError::NotRunning));
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let (mut debugger, mut vm) = load();
+        assert_eq!(debugger.step(&mut vm, 3), Ok(Stop::Stepped));
+        assert_eq!(vm.pc(), 76);
+        // Stepping goes into the call, but next runs all of it
+        assert_eq!(debugger.step(&mut vm, 1), Ok(Stop::Stepped));
+        assert_eq!((vm.pc(), vm.call_depth()), (92, 1));
+        debugger.step(&mut vm, 2).unwrap();
+        assert_eq!(vm.pc(), 80);
+        debugger.step(&mut vm, 2).unwrap();
+        assert_eq!(debugger.next(&mut vm), Ok(Stop::Stepped));
+        assert_eq!((vm.pc(), vm.call_depth(), vm.registers[4]), (80, 0, 2));
+
+        debugger.add_breakpoint("loop", &RegisterAliases::new()).unwrap();
+        let context = debugger.context(&vm, &RegisterAliases::new());
+        assert_eq!(context, vec![
+            "    0072: load $t3 #1               ; line 5: load $t3 #1",
+            "loop:",
+            "  * 0076: call #92                  ; line 6: loop: call @bump",
+            "=>  0080: neq $t0 $t1               ; line 7: neq $t0 $t1",
+            "    0084: djmpe #76                 ; line 8: djmpe @loop",
+            "    0088: hlt                       ; line 9: hlt"
+        ]);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut debugger, mut vm) = load();
+        let aliases = RegisterAliases::new();
+        assert_eq!(debugger.add_watchpoint("$t1", &aliases, &vm), Ok(1));
+        assert_eq!(debugger.add_watchpoint("heap[4]", &aliases, &vm), Ok(2));
+        assert!(debugger.add_watchpoint("$99", &aliases, &vm).is_err());
+        assert!(debugger.add_watchpoint("heap[x]", &aliases, &vm).is_err());
+        let stop = debugger.resume(&mut vm).unwrap();
+        assert_eq!(stop, Stop::Watchpoint{ id: 1, target: Target::Register(5), old: Some(0), new: Some(20) });
+        assert_eq!(stop.to_string(), "Watchpoint 1: $t1 changed from 0 to 20");
+        assert_eq!(debugger.resume(&mut vm), Ok(Stop::Halted));
+    }
+
+    #[test]
+    fn test_restored() {
+        let (mut debugger, mut vm) = load();
+        let aliases = RegisterAliases::new();
+        debugger.step(&mut vm, 2).unwrap();
+        let snapshot = vm.snapshot();
+        debugger.resume(&mut vm).unwrap();
+        assert!(!debugger.is_running());
+
+        assert_eq!(debugger.add_watchpoint("$t3", &aliases, &vm), Ok(1));
+        vm.restore(&snapshot).unwrap();
+        debugger.restored(&vm);
+        assert!(debugger.is_running());
+        assert_eq!(debugger.debug_info, None);
+        // $t3 was 0 in the snapshot, so loading it is a change from there rather than from 1
+        assert_eq!(debugger.step(&mut vm, 1), Ok(Stop::Watchpoint{ id: 1, target: Target::Register(7), old: Some(0), new: Some(1) }));
+        assert_eq!(debugger.resume(&mut vm), Ok(Stop::Halted));
+        assert_eq!(vm.registers[4], 20);
+    }
+}
@@ -8,6 +8,13 @@ use std::num::ParseIntError;
 use crate::vm::VM;
 use crate::assembler::program_parsers::{program};
 use crate::assembler::Assembler;
+use crate::assembler::pseudo_instructions;
+use crate::assembler::registers::{abi_name, RegisterAliases};
+use crate::disassembler::Disassembler;
+
+pub mod debugger;
+
+use self::debugger::{DebugError, Debugger, Stop};
 
 /// Core structure for the REPL for the assembler
 #[derive(Default)]
@@ -15,10 +22,19 @@ pub struct REPL {
     command_buffer: Vec<String>,
     // The vm the REPL will use to execute code
     vm: VM,
-    asm: Assembler
+    asm: Assembler,
+    // Register aliases defined with `.alias` while typing instructions
+    aliases: RegisterAliases,
+    // Breakpoints and watchpoints for the program loaded with `.load_file`
+    debugger: Debugger
 }
 
 impl REPL {
+    /// Logs every instruction the VM runs, see `VM::trace`
+    pub fn set_trace(&mut self, trace: bool) {
+        self.vm.trace = trace;
+    }
+
     pub fn run(&mut self) {
         println!("Welcome to Iridium! Let's be productive!");
 
@@ -34,8 +50,12 @@ impl REPL {
             let buffer = buffer.trim();
 
             self.command_buffer.push(buffer.to_string());
+            let (command, args) = match buffer.find(' ') {
+                Some(space) => (&buffer[..space], buffer[space..].trim()),
+                None => (buffer, "")
+            };
 
-            match buffer {
+            match command {
                 ".quit" => {
                     println!("Farewell! Have a great day!");
                     std::process::exit(0);
@@ -54,9 +74,23 @@ impl REPL {
                 },
                 ".registers" => {
                     println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
+                    for line in self.register_listing() {
+                        println!("{}", line);
+                    }
                     println!("End of Register Listing");
                 },
+                ".tagged" => {
+                    let tagged = !self.vm.tagged;
+                    self.vm.set_tagged(tagged);
+                    println!("Tagged values are {}", if self.vm.tagged { "on" } else { "off" });
+                },
+                ".disassemble" => {
+                    println!("Disassembling the VM's program vector");
+                    for line in Disassembler::with_aliases(self.aliases.clone()).program(&self.vm.program) {
+                        println!("{}", line);
+                    }
+                    println!("End of disassembly");
+                },
                 ".clear" => {
                     self.vm.program.clear();
                     println!("Clearing program vector");
@@ -71,12 +105,20 @@ impl REPL {
                     let mut f = File::open(Path::new(&filename)).expect("File not found");
                     let mut contents = String::new();
                     f.read_to_string(&mut contents).expect("There was an error reading from the file");
+                    // Labels from a file loaded earlier would clash with the new file's
+                    self.asm = Assembler::new();
                     match self.asm.assemble(&contents) {
-                        Ok(mut assembled_program) => {
+                        Ok(assembled_program) => {
                             println!("Sending assembled program to VM");
-                            self.vm.program.append(&mut assembled_program);
-                            println!("{:#?}", self.vm.program);
-                            self.vm.run();
+                            let debug_info = self.asm.debug_info(&contents, &assembled_program);
+                            self.vm.program = assembled_program;
+                            match self.debugger.load(&mut self.vm, Some(debug_info)) {
+                                Ok(_) => {
+                                    println!("Stopped before the first instruction, use .continue to run the program or .step to step through it");
+                                    self.print_context();
+                                },
+                                Err(e) => println!("Unable to load the program: {}", e)
+                            }
                         },
                         Err(errors) => {
                             for error in errors {
@@ -85,7 +127,91 @@ impl REPL {
                             continue;
                         }
                     }
-                }
+                },
+                ".break" => {
+                    if args.is_empty() {
+                        for line in self.debugger.points() {
+                            println!("{}", line);
+                        }
+                        continue;
+                    }
+                    match self.debugger.add_breakpoint(args, &self.aliases) {
+                        Ok(id) => println!("Breakpoint {} set", id),
+                        Err(e) => println!("Unable to set breakpoint: {}", e)
+                    }
+                },
+                ".watch" => {
+                    match self.debugger.add_watchpoint(args, &self.aliases, &self.vm) {
+                        Ok(id) => println!("Watchpoint {} set", id),
+                        Err(e) => println!("Unable to set watchpoint: {}", e)
+                    }
+                },
+                ".delete" => {
+                    let id = match args {
+                        "" => None,
+                        id => match id.parse() {
+                            Ok(id) => Some(id),
+                            Err(_) => {
+                                println!("{} is not the number of a breakpoint or watchpoint", id);
+                                continue;
+                            }
+                        }
+                    };
+                    match self.debugger.delete(id) {
+                        Ok(_) => println!("Deleted {}", id.map_or("every breakpoint and watchpoint".to_string(), |id| id.to_string())),
+                        Err(e) => println!("{}", e)
+                    }
+                },
+                ".step" => {
+                    let count = match args {
+                        "" => 1,
+                        count => match count.parse() {
+                            Ok(count) if count > 0 => count,
+                            _ => {
+                                println!("{} is not a number of instructions to step", count);
+                                continue;
+                            }
+                        }
+                    };
+                    let stop = self.debugger.step(&mut self.vm, count);
+                    self.print_stop(stop);
+                },
+                ".next" => {
+                    let stop = self.debugger.next(&mut self.vm);
+                    self.print_stop(stop);
+                },
+                ".continue" => {
+                    let stop = self.debugger.resume(&mut self.vm);
+                    self.print_stop(stop);
+                },
+                ".save_state" => {
+                    print!("Please enter the path to save the VM's state to: ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+                    let mut tmp = String::new();
+                    stdin.read_line(&mut tmp).expect("Unable to read line from user");
+                    match File::create(Path::new(tmp.trim())).and_then(|mut f| f.write_all(&self.vm.snapshot())) {
+                        Ok(_) => println!("Saved the VM's state to {}", tmp.trim()),
+                        Err(e) => println!("Unable to save the VM's state: {}", e)
+                    }
+                },
+                ".load_state" => {
+                    print!("Please enter the path of the state you wish to load: ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+                    let mut tmp = String::new();
+                    stdin.read_line(&mut tmp).expect("Unable to read line from user");
+                    let mut contents = vec![];
+                    if let Err(e) = File::open(Path::new(tmp.trim())).and_then(|mut f| f.read_to_end(&mut contents)) {
+                        println!("Unable to read {}: {}", tmp.trim(), e);
+                        continue;
+                    }
+                    match self.vm.restore(&contents) {
+                        Ok(_) => {
+                            self.debugger.restored(&self.vm);
+                            println!("Restored the VM's state from {}", tmp.trim());
+                        },
+                        Err(e) => println!("Unable to restore the VM's state: {}", e)
+                    }
+                },
                 _ => {
                     let program = match program(buffer.into()) {
                         Ok((_, program)) => program,
@@ -94,13 +220,70 @@ impl REPL {
                             continue;
                         }
                     };
-                    self.vm.program.append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
+                    for instruction in &program.instructions {
+                        let result = match self.aliases.process_directive(instruction) {
+                            Ok(true) => continue,
+                            Ok(false) => instruction.check_opcode().and_then(|_| pseudo_instructions::expand(instruction)),
+                            Err(e) => Err(e)
+                        };
+                        let expanded = match result {
+                            Ok(expanded) => expanded,
+                            Err(e) => {
+                                println!("Unable to parse input: {}", e);
+                                continue;
+                            }
+                        };
+                        for real in expanded {
+                            match real.check_operands().and_then(|_| self.aliases.resolve(&real)) {
+                                Ok(resolved) => {
+                                    self.vm.program.append(&mut resolved.to_bytes(&self.asm.symbols));
+                                    self.vm.run_once();
+                                },
+                                Err(e) => println!("Unable to parse input: {}", e)
+                            }
+                        }
+                    }
                 }
             };
         }
     }
 
+    /// Says why the program stopped, then shows where it is if it can carry on
+    fn print_stop(&self, stop: Result<Stop, DebugError>) {
+        match stop {
+            Ok(Stop::Stepped) => {},
+            Ok(stop) => println!("{}", stop),
+            Err(e) => println!("{}", e)
+        }
+        if self.debugger.is_running() {
+            self.print_context();
+        }
+    }
+
+    fn print_context(&self) {
+        for line in self.debugger.context(&self.vm, &self.aliases) {
+            println!("{}", line);
+        }
+    }
+
+    /// One line per register with its number, conventional name, any aliases and its value, along
+    /// with the value's type in tagged mode
+    fn register_listing(&self) -> Vec<String> {
+        (0..self.vm.registers.len()).map(|index| {
+            let register = index as u8;
+            let mut names = format!("${:<3} ${:<4}", register, abi_name(register).unwrap_or_default());
+            for alias in self.aliases.aliases_of(register) {
+                names.push_str(&format!(" ${}", alias));
+            }
+            let value = self.vm.value(index);
+            if self.vm.tagged {
+                format!("{:<24} = {} ({})", names, value, value.value_type())
+            } else {
+                format!("{:<24} = {}", names, value)
+            }
+        }).collect()
+    }
+
     /// Acceps a hexidecimal string WITHOUT a leading `0x` and returns a Vec of u8
     /// Example for a load command: 00 01 03 E8
     #[allow(dead_code)]
@@ -108,7 +291,7 @@ impl REPL {
         let split = i.split(' ').collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => {
                     results.push(result);
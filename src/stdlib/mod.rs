@@ -0,0 +1,117 @@
+use crate::assembler::object::ObjectFile;
+use crate::assembler::Assembler;
+
+/// Assembly sources of the standard library, embedded in the binary so `.include <std/...>` works
+/// without anything installed next to it.
+///
+/// Every routine follows the same calling convention: arguments are passed in `$0` to `$3`, a result
+/// comes back in `$0`, and the routine may clobber `$0` to `$3` and the temporaries `$4` to `$15`.
+/// Routines are entered with `call` and return with `ret`.
+///
+/// * `std/mem`: `memcpy` (dest `$0`, source `$1`, length `$2`) and `memset` (dest `$0`, byte `$1`,
+///   length `$2`) on the heap
+/// * `std/string`: `strlen` (string `$0`) returns the length of a NUL terminated heap string, and
+///   `itoa` (value `$0`, buffer `$1`) writes a value as NUL terminated decimal and returns its length
+/// * `std/io`: `printint` (value `$0`, scratch buffer `$1` of at least 12 bytes) and `prints`
+///   (heap string `$0`)
+const MODULES: [(&str, &str); 3] = [
+    ("std/mem", include_str!("mem.iasm")),
+    ("std/string", include_str!("string.iasm")),
+    ("std/io", include_str!("io.iasm")),
+];
+
+/// Returns the source of a standard library module, such as `std/io`
+pub fn source(name: &str) -> Option<&'static str> {
+    MODULES.iter().find(|(module, _)| *module == name).map(|(_, source)| *source)
+}
+
+/// Names of all the bundled modules
+pub fn modules() -> Vec<&'static str> {
+    MODULES.iter().map(|(module, _)| *module).collect()
+}
+
+/// Assembles a standard library module into an object file that can be handed to the linker
+pub fn assemble(name: &str) -> Result<ObjectFile, String> {
+    let source = match source(name) {
+        Some(source) => source,
+        None => return Err(format!("there is no module named {} in the standard library", name))
+    };
+    Assembler::new().assemble_object(source).map_err(|errors| {
+        errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(", ")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn run(source: &str) -> VM {
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut vm = VM::buffered(b"");
+        vm.add_bytes(program);
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_every_module_assembles() {
+        for module in modules() {
+            assert!(assemble(module).is_ok(), "{} did not assemble", module);
+        }
+        assert!(assemble("std/nothing").is_err());
+    }
+
+    #[test]
+    fn test_memset_and_memcpy() {
+        let vm = run(".include <std/mem>\n.data\n.code\nload $0 #16\naloc $0 $20\n\
+                      mov $0 $20\nload $1 #7\nload $2 #4\ncall @memset\n\
+                      load $0 #8\nadd $0 $20 $0\nmov $1 $20\nload $2 #5\ncall @memcpy\nhlt");
+        let block = vm.registers[20] as usize;
+        assert_eq!(&vm.heap()[block..block + 16], &[7, 7, 7, 7, 0, 0, 0, 0, 7, 7, 7, 7, 0, 0, 0, 0]);
+        assert_eq!(vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_strlen() {
+        let vm = run(".include <std/mem>\n.include <std/string>\n.data\n.code\nload $0 #8\naloc $0 $20\n\
+                      mov $0 $20\nload $1 #65\nload $2 #5\ncall @memset\n\
+                      mov $0 $20\ncall @strlen\nhlt");
+        assert_eq!(vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_itoa() {
+        let vm = run(".include <std/string>\n.data\n.code\nload $0 #16\naloc $0 $22\n\
+                      load $0 #12345\nmov $1 $22\ncall @itoa\n\
+                      load $20 #0\nload $21 #907\nsub $20 $21 $0\nload $1 #8\nadd $1 $22 $1\ncall @itoa\nhlt");
+        let block = vm.registers[22] as usize;
+        assert_eq!(&vm.heap()[block..block + 6], b"12345\0");
+        assert_eq!(&vm.heap()[block + 8..block + 13], b"-907\0");
+        assert_eq!(vm.registers[0], 4);
+
+        let vm = run(".include <std/string>\n.data\n.code\nload $0 #4\naloc $0 $1\nload $0 #0\ncall @itoa\nhlt");
+        assert_eq!(&vm.heap()[4..6], b"0\0");
+        assert_eq!(vm.registers[0], 1);
+
+        // The digits are worked out from the negative value, which has room for all of i32::MIN
+        let vm = run(".include <std/string>\n.data\n.code\nload $0 #16\naloc $0 $1\nli $0 #-2147483648\ncall @itoa\nhlt");
+        assert_eq!(&vm.heap()[4..16], b"-2147483648\0");
+        assert_eq!(vm.registers[0], 11);
+    }
+
+    #[test]
+    fn test_printint() {
+        let vm = run(".include <std/io>\n.data\n.code\nload $0 #16\naloc $0 $1\nload $0 #42\ncall @printint\nhlt");
+        assert_eq!(&vm.heap()[4..7], b"42\0");
+        assert_eq!(vm.registers[1], 4);
+        assert_eq!(vm.io.stdout_buffer().unwrap(), b"42".to_vec());
+    }
+
+    #[test]
+    fn test_unknown_include() {
+        let errors = Assembler::new().assemble(".include <std/nothing>\n.data\n.code\nhlt").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("std/nothing"));
+    }
+}
@@ -1,39 +1,213 @@
-use crate::instruction::Opcode;
-use crate::assembler::PIE_HEADER_PREFIX;
+use std::io::{Read, Write};
+
+use log::Level;
+
+use crate::instruction::{Opcode, Operand, OperandKind, INSTRUCTION_LENGTH};
+use crate::disassembler::Disassembler;
+use crate::assembler::object::Import;
+use crate::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+use crate::loader::{LoadError, LoadedModule, ModuleLoader, PieLayout};
+
+pub mod allocator;
+mod dispatch;
+pub mod files;
+pub mod gc;
+pub mod host;
+mod io;
+pub mod memory;
+pub mod profiler;
+pub mod recorder;
+pub mod sandbox;
+pub mod snapshot;
+pub mod values;
+mod vm_errors;
+
+use self::allocator::{AllocError, Allocator};
+use self::dispatch::DecodedProgram;
+use self::files::FileTable;
+use self::gc::{GarbageCollector, HEADER_SIZE};
+use self::host::{HostContext, HostError, HostFunctions};
+use self::memory::{Access, Fault, FaultReason, MemoryMap, Segment, CODE_BASE, RO_BASE, STACK_SIZE, STACK_TOP};
+use self::profiler::Profiler;
+use self::recorder::TraceRecorder;
+use self::sandbox::{Capability, SandboxPolicy};
+use self::values::{Value, ValueType};
+pub use self::io::VmIo;
+pub use self::vm_errors::VmError;
+
+/// Name the program being run is known by in load errors
+pub const MAIN_MODULE: &str = "<main>";
 
 #[derive(Default)]
 pub struct VM {
     /// Array that simulates having hardware registers
     pub registers: [i32; 32],
+    /// In tagged mode every register's value has a type, which is kept here
+    tags: [ValueType; 32],
+    /// Runs programs with typed registers, so arithmetic on the wrong types faults. See `values`.
+    pub tagged: bool,
     /// Program counter that tracks which byte is being executed
     pc: usize,
     /// The bytecode of the program being run
     pub program: Vec<u8>,
     /// Vector used for heap memory
     heap: Vec<u8>,
+    /// Keeps track of the heap blocks handed out by `aloc`, and sets how big the heap can get
+    pub allocator: Allocator,
+    /// Frees heap objects programs can no longer reach, when it is enabled
+    pub gc: GarbageCollector,
     /// Contains the remainder of modulo division ops
     remainder: u32,
     /// Contains the result of the last comparison operation
     equal_flag: bool,
     /// Contains the read only section data
-    ro_data: Vec<u8>
+    ro_data: Vec<u8>,
+    /// Memory for the stack, which `$sp` points to the top of when a program starts
+    stack: Vec<u8>,
+    /// Offset in `program` of the first instruction. Nothing before it can be run.
+    code_start: usize,
+    /// Finds the shared modules named in the program's import table
+    pub loader: ModuleLoader,
+    /// Return addresses pushed by CALL and CALLX
+    call_stack: Vec<Frame>,
+    /// Every loaded module. The first entry is the program itself, and whichever module is running has
+    /// its program and read only data moved into `program` and `ro_data`.
+    modules: Vec<LoadedModule>,
+    /// Index into `modules` of the module that is running
+    current_module: usize,
+    /// Code of each module decoded for the fast engine, indexed like `modules` and filled in the
+    /// first time the module runs
+    decoded: Vec<Option<DecodedProgram>>,
+    /// Logs every instruction as it runs, along with the registers it changed, at the trace level
+    pub trace: bool,
+    /// Writes every instruction that runs to a JSON Lines trace, when it is set
+    pub recorder: Option<TraceRecorder>,
+    /// Counts the instructions that run and how long they take, when it is set
+    pub profiler: Option<Profiler>,
+    /// Streams the program reads from and writes to
+    pub io: VmIo,
+    /// Functions programs can call with `syscall`
+    pub host_functions: HostFunctions,
+    /// What programs are allowed to do beyond computing
+    pub sandbox: SandboxPolicy,
+    /// Files the program has open, and the directory it can open them in
+    pub files: FileTable,
+    /// Why the program stopped, if it did not stop at `hlt`
+    error: Option<VmError>
+}
+
+/// Where to continue once a called routine returns
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    module: usize,
+    return_pc: usize
 }
 
 impl VM {
+    /// A VM whose programs write to and read from the given streams instead of the process's own
+    pub fn with_io(stdout: Box<dyn Write>, stderr: Box<dyn Write>, stdin: Box<dyn Read>) -> VM {
+        VM {
+            io: VmIo::new(stdout, stderr, stdin),
+            ..VM::default()
+        }
+    }
+
+    /// A VM that keeps program output in memory, see `VmIo::stdout_buffer`, and reads `stdin` as input
+    pub fn buffered(stdin: &[u8]) -> VM {
+        VM {
+            io: VmIo::buffered(stdin),
+            ..VM::default()
+        }
+    }
+
+    /// Loads the program and runs it with the fast engine until it stops
     pub fn run(&mut self) {
-        if !self.verify_header() {
-            println!("Header was incorrect");
-            std::process::exit(1);
+        if !self.start() {
+            return;
+        }
+        self.run_decoded();
+        self.io.flush();
+    }
+
+    /// Loads the program and runs it one instruction at a time straight from its bytes. This is slow
+    /// but simple, and is what the fast engine is checked against.
+    pub fn run_reference(&mut self) {
+        if !self.start() {
+            return;
         }
-        self.pc = 65;
         let mut is_done = false;
         while !is_done {
             is_done = self.execute_instruction();
         }
+        self.io.flush();
+    }
+
+    /// Loads the program, returning whether it can be run. If it cannot, the reason is left in `error`.
+    fn start(&mut self) -> bool {
+        if !self.verify_header() {
+            self.fail(VmError::BadHeader);
+            return false;
+        }
+        match self.load() {
+            Ok(code_start) => {
+                self.pc = code_start;
+                true
+            },
+            Err(error) => {
+                self.fail(VmError::LoadFailed{ error });
+                false
+            }
+        }
+    }
+
+    /// Runs the instruction at `pc`, returning whether the program stopped
+    pub fn run_once(&mut self) -> bool {
+        self.execute_instruction()
+    }
+
+    /// Loads the program and stops before its first instruction, so it can be run a step at a time
+    /// with `run_once`
+    pub fn start_paused(&mut self) -> Result<(), LoadError> {
+        self.pc = self.load()?;
+        Ok(())
+    }
+
+    /// Offset in the running module's program of the next instruction to run
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Index of the module that is running. The main program is 0.
+    pub fn current_module(&self) -> usize {
+        self.current_module
+    }
+
+    /// Number of calls the program is in the middle of
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// Why the last program stopped, if it stopped because of an error rather than reaching `hlt`
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Read only view of the heap, for hosts and tests that want to inspect what a program wrote
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// Clears the error the program stopped with and carries on from `pc`. After a fault, `pc` is the
+    /// instruction that faulted, so it is run again.
+    pub fn resume(&mut self) {
+        self.error = None;
+        self.run_decoded();
+        self.io.flush();
+    }
+
+    /// The regions programs can address with `ldb`, `stb` and `prth`, as things stand now
+    pub fn memory_map(&self) -> MemoryMap {
+        MemoryMap::new(self.heap.len(), self.ro_data.len(), self.code_start, self.program.len(), self.stack.len())
     }
 
     pub fn add_byte(&mut self, b: u8) {
@@ -45,194 +219,505 @@ impl VM {
     }
 
     fn execute_instruction(&mut self) -> bool {
-        println!("executing instruction, pc = {}", self.pc);
-        if self.pc >= self.program.len() {
+        if self.pc == self.program.len() {
             return true;
         }
-        match self.decode_opcode() {
+        if self.profiler.is_some() {
+            return self.profile_instruction();
+        }
+        self.run_instruction()
+    }
+
+    /// Runs the instruction at `pc`, recording it in the trace file and logging it if either is on
+    fn run_instruction(&mut self) -> bool {
+        if self.recorder.is_some() {
+            return self.record_instruction();
+        }
+        if self.trace && log_enabled!(Level::Trace) {
+            return self.trace_instruction();
+        }
+        self.step()
+    }
+
+    /// Runs the instruction at `pc` and logs it with the registers and flag it changed
+    fn trace_instruction(&mut self) -> bool {
+        let start = self.pc;
+        // A pc past the end of the program has nothing to show, and the step below reports the fault
+        let text = self.program.get(start..).map_or_else(|| Opcode::IGL.mnemonic().to_string(), |bytes| Disassembler::new().instruction(bytes));
+        let registers = self.registers;
+        let equal_flag = self.equal_flag;
+        let is_done = self.step();
+        let line = format!("{:04}: {:<24}{}", start, text, self.changes_since(&registers, equal_flag));
+        trace!("{}", line.trim_end());
+        is_done
+    }
+
+    /// Lists the registers, and the equal flag, that differ from the values given
+    fn changes_since(&self, registers: &[i32; 32], equal_flag: bool) -> String {
+        let mut changes: Vec<String> = registers.iter().zip(self.registers.iter()).enumerate()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(register, (before, after))| format!("{}: {} -> {}", Operand::Register(register as u8), before, after))
+            .collect();
+        if equal_flag != self.equal_flag {
+            changes.push(format!("equal: {} -> {}", equal_flag, self.equal_flag));
+        }
+        changes.join(", ")
+    }
+
+    /// Decodes and runs the instruction at `pc` straight from the program bytes
+    fn step(&mut self) -> bool {
+        let start = self.pc;
+        let decoded = self.decode_opcode().map_err(|fault| fault.at(start))
+            .and_then(|opcode| Ok((opcode, self.decode_operands(opcode, start)?)));
+        match decoded {
+            Ok((opcode, operands)) => self.execute(opcode, operands, start + INSTRUCTION_LENGTH),
+            Err(error) => {
+                self.pc = start;
+                self.fail(error)
+            }
+        }
+    }
+
+    /// Carries out an instruction whose operands were already read, with `pc` just past them.
+    /// `next` is where execution carries on unless the instruction jumps. Both engines run
+    /// instructions through here, so they cannot disagree on what an instruction does.
+    #[inline(always)]
+    fn execute(&mut self, opcode: Opcode, operands: [usize; 3], next: usize) -> bool {
+        if self.tagged {
+            return self.execute_tagged(opcode, operands, next);
+        }
+        self.execute_untagged(opcode, operands, next)
+    }
+
+    /// Carries out an instruction on registers that all hold ints
+    #[inline(always)]
+    #[allow(clippy::needless_bool_assign)]
+    fn execute_untagged(&mut self, opcode: Opcode, [a, b, c]: [usize; 3], next: usize) -> bool {
+        match opcode {
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = u32::from(self.next_16_bits());
-                println!("loading {} into register {}", number, register);
-                self.registers[register] = number as i32;
+                self.registers[a] = b as i32;
             },
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                self.registers[c] = self.registers[a].wrapping_add(self.registers[b]);
             },
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                self.registers[c] = self.registers[a].wrapping_sub(self.registers[b]);
             },
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                self.registers[c] = self.registers[a].wrapping_mul(self.registers[b]);
             },
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
+                if register2 == 0 {
+                    let pc = next - INSTRUCTION_LENGTH;
+                    self.pc = pc;
+                    return self.fail(VmError::DivideByZero{ pc });
+                }
+                self.registers[c] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as u32;
             },
             Opcode::HLT => {
-                println!("HLT encountered");
+                debug!("HLT encountered");
                 return true;
             },
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                self.pc = self.registers[a] as usize;
+                return false;
             },
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += value;
+                self.pc = self.pc.wrapping_add(self.registers[a] as usize);
+                return false;
             },
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= value;
+                self.pc = self.pc.wrapping_sub(self.registers[a] as usize);
+                return false;
             },
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
                 if register1 == register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
             },
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
                 if register1 != register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
             },
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
                 if register1 > register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
             },
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
                 if register1 < register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
             },
             Opcode::GTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
                 if register1 >= register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
             },
             Opcode::LTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
                 if register1 <= register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
             },
             Opcode::JMPE => {
-                let register = self.next_8_bits() as usize;
-                let target = self.registers[register];
-                if self.equal_flag {
-                    self.pc = target as usize;
+                self.pc = if self.equal_flag { self.registers[a] as usize } else { next };
+                return false;
+            },
+            Opcode::NOP => {},
+            Opcode::ALOC => {
+                let size = self.registers[a];
+                let result = if self.gc.enabled {
+                    self.allocate_object(size, 0)
                 } else {
-                    self.next_16_bits();
+                    self.allocator.allocate(&mut self.heap, size)
+                };
+                match result {
+                    Ok(address) => self.registers[b] = address as i32,
+                    Err(_) => {
+                        let pc = next - INSTRUCTION_LENGTH;
+                        self.pc = pc;
+                        return self.fail(VmError::OutOfMemory{ pc, size });
+                    }
                 }
             },
-            Opcode::NOP => {
-                self.next_8_bits();
-                self.next_8_bits();
-                self.next_8_bits();
+            Opcode::FREE => {
+                let address = self.registers[a] as u32;
+                // Objects have a header in front of them, and the block starts there
+                let block = if self.gc.enabled { (address as usize).wrapping_sub(HEADER_SIZE) } else { address as usize };
+                if let Err(error) = self.allocator.free(block) {
+                    let pc = next - INSTRUCTION_LENGTH;
+                    self.pc = pc;
+                    return self.fail(match error {
+                        AllocError::DoubleFree => VmError::DoubleFree{ pc, address },
+                        _ => VmError::InvalidFree{ pc, address }
+                    });
+                }
             },
-            Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+            Opcode::NEW => {
+                let size = self.registers[a];
+                match self.allocate_object(size, self.registers[b] as u8) {
+                    Ok(address) => self.registers[c] = address as i32,
+                    Err(_) => {
+                        let pc = next - INSTRUCTION_LENGTH;
+                        self.pc = pc;
+                        return self.fail(VmError::OutOfMemory{ pc, size });
+                    }
+                }
+            },
+            Opcode::GC => {
+                if self.gc.enabled {
+                    self.collect();
+                }
             },
             Opcode::INC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                self.registers[a] = self.registers[a].wrapping_add(1);
             },
             Opcode::DEC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                self.registers[a] = self.registers[a].wrapping_sub(1);
             },
             Opcode::DJMPE => {
-                let destination = self.next_16_bits();
-                if self.equal_flag {
-                    self.pc = destination as usize;
-                    self.next_8_bits();
-                } else {
-                    self.next_8_bits();
-                }
-            }
+                self.pc = if self.equal_flag { a } else { next };
+                return false;
+            },
             Opcode::PRTS => {
-                let starting_offset = self.next_16_bits() as usize;
-                let mut ending_offset = starting_offset;
-                let slice = self.ro_data.as_slice();
-
-                while slice[ending_offset] != 0 {
-                    ending_offset += 1;
+                if let Err(capability) = self.sandbox.check(Capability::Console) {
+                    return self.fail_at(next - INSTRUCTION_LENGTH, VmError::PermissionDenied{ capability });
                 }
-
-                let result = std::str::from_utf8(&slice[starting_offset..ending_offset]);
-                match result {
-                    Ok(s) => { print!("{}", s); },
-                    Err(e) => { print!("Error decoding string for prts instruction: {:#?}", e); }
+                match self.read_string(RO_BASE + a as u32) {
+                    Ok(bytes) => self.io.print_string(&bytes, "prts"),
+                    Err(fault) => return self.fault(fault, next - INSTRUCTION_LENGTH)
                 }
-            }
+            },
+            Opcode::CALL => {
+                self.call_stack.push(Frame { module: self.current_module, return_pc: next });
+                self.pc = a;
+                return false;
+            },
+            Opcode::RET => {
+                match self.call_stack.pop() {
+                    Some(frame) => {
+                        self.switch_module(frame.module);
+                        self.pc = frame.return_pc;
+                        return false;
+                    },
+                    None => {
+                        let pc = next - INSTRUCTION_LENGTH;
+                        return self.fail_at(pc, VmError::EmptyCallStack{ address: pc });
+                    }
+                }
+            },
+            Opcode::CALLX => {
+                let target = self.modules.get(self.current_module).and_then(|m| m.imports.get(a)).cloned();
+                match target {
+                    Some((module, address)) => {
+                        self.call_stack.push(Frame { module: self.current_module, return_pc: next });
+                        self.switch_module(module);
+                        self.pc = address;
+                        return false;
+                    },
+                    None => {
+                        return self.fail_at(next - INSTRUCTION_LENGTH, VmError::UnresolvedImport{ slot: a });
+                    }
+                }
+            },
+            Opcode::LDB => {
+                match self.load_byte(self.registers[b] as u32) {
+                    Ok(byte) => self.registers[a] = i32::from(byte),
+                    Err(fault) => return self.fault(fault, next - INSTRUCTION_LENGTH)
+                }
+            },
+            Opcode::STB => {
+                if let Err(fault) = self.store_byte(self.registers[b] as u32, self.registers[a] as u8) {
+                    return self.fault(fault, next - INSTRUCTION_LENGTH);
+                }
+            },
+            Opcode::LDW => {
+                match self.load_word(self.registers[b] as u32) {
+                    Ok(word) => self.registers[a] = word,
+                    Err(fault) => return self.fault(fault, next - INSTRUCTION_LENGTH)
+                }
+            },
+            Opcode::STW => {
+                if let Err(fault) = self.store_word(self.registers[b] as u32, self.registers[a]) {
+                    return self.fault(fault, next - INSTRUCTION_LENGTH);
+                }
+            },
+            Opcode::TYPEOF => {
+                self.registers[b] = ValueType::Int as i32;
+            },
+            Opcode::CAST => {
+                match ValueType::from_code(b as u16) {
+                    Some(to) => self.registers[a] = Value::Int(self.registers[a]).cast(to).map_or(0, Value::bits),
+                    None => {
+                        let pc = next - INSTRUCTION_LENGTH;
+                        return self.fail_at(pc, VmError::InvalidType{ pc, code: b as u16 });
+                    }
+                }
+            },
+            Opcode::PRTH => {
+                if let Err(capability) = self.sandbox.check(Capability::Console) {
+                    return self.fail_at(next - INSTRUCTION_LENGTH, VmError::PermissionDenied{ capability });
+                }
+                match self.read_string(self.registers[a] as u32) {
+                    Ok(bytes) => self.io.print_string(&bytes, "prth"),
+                    Err(fault) => return self.fault(fault, next - INSTRUCTION_LENGTH)
+                }
+            },
+            Opcode::PRTI | Opcode::PRTX | Opcode::PRTC | Opcode::RDI | Opcode::RDL => {
+                let mut context = self.context();
+                let result = match opcode {
+                    Opcode::PRTI => host::print_int(&mut context, a),
+                    Opcode::PRTX => host::print_hex(&mut context, a),
+                    Opcode::PRTC => host::print_char(&mut context, a),
+                    Opcode::RDI => host::read_int(&mut context, a),
+                    _ => host::read_line(&mut context, a, b)
+                };
+                if let Err(error) = result {
+                    return self.fail_host_call(next - INSTRUCTION_LENGTH, opcode.mnemonic(), error);
+                }
+            },
+            Opcode::SYSCALL => {
+                let memory_map = self.memory_map();
+                let mut context = HostContext {
+                    registers: &mut self.registers,
+                    heap: &mut self.heap,
+                    ro_data: &self.ro_data,
+                    memory_map,
+                    equal_flag: &mut self.equal_flag,
+                    io: &mut self.io,
+                    files: &mut self.files,
+                    policy: &self.sandbox
+                };
+                let number = a as u16;
+                match self.host_functions.call(number, &mut context) {
+                    Some(Ok(())) => {},
+                    Some(Err(error)) => {
+                        let name = self.host_functions.name_of(number).unwrap_or_default().to_string();
+                        return self.fail_host_call(next - INSTRUCTION_LENGTH, &name, error);
+                    },
+                    None => return self.fail_at(next - INSTRUCTION_LENGTH, VmError::UnknownSyscall{ number })
+                }
+            },
             Opcode::IGL => {
-                println!("Unrecognized opcode found! Terminating!");
-                return true;
+                let pc = next - INSTRUCTION_LENGTH;
+                return self.fail_at(pc, VmError::IllegalOpcode{ address: pc });
             }
         }
+        self.pc = next;
         false
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    /// Stops the program because of an error
+    fn fail(&mut self, error: VmError) -> bool {
+        error!("{}! Terminating!", error);
+        self.error = Some(error);
+        true
+    }
+
+    /// Stops the program because of an error in the instruction at `pc`, leaving `pc` there
+    fn fail_at(&mut self, pc: usize, error: VmError) -> bool {
+        self.pc = pc;
+        self.fail(error)
+    }
+
+    /// Stops the program because of a memory fault, leaving `pc` on the instruction at fault
+    fn fault(&mut self, fault: Fault, pc: usize) -> bool {
+        self.pc = pc;
+        self.fail(fault.at(pc))
+    }
+
+    /// Stops the program because a host function called by the instruction at `pc` failed
+    fn fail_host_call(&mut self, pc: usize, name: &str, error: HostError) -> bool {
+        match error {
+            HostError::Failed(error) => self.fail_at(pc, VmError::HostFunctionFailed{ name: name.to_string(), error }),
+            HostError::PermissionDenied(capability) => self.fail_at(pc, VmError::PermissionDenied{ capability })
+        }
+    }
+
+    /// The parts of the VM host functions and the console opcodes work on
+    fn context(&mut self) -> HostContext<'_> {
+        let memory_map = self.memory_map();
+        HostContext {
+            registers: &mut self.registers,
+            heap: &mut self.heap,
+            ro_data: &self.ro_data,
+            memory_map,
+            equal_flag: &mut self.equal_flag,
+            io: &mut self.io,
+            files: &mut self.files,
+            policy: &self.sandbox
+        }
+    }
+
+    /// Reads the operands of an instruction as the opcode table describes them, leaving `pc` just past
+    /// the last operand. Registers come back as register numbers and the rest as their 16 bit value.
+    fn decode_operands(&mut self, opcode: Opcode, start: usize) -> Result<[usize; 3], VmError> {
+        let mut operands = [0; 3];
+        for (operand, kind) in operands.iter_mut().zip(opcode.operands()) {
+            *operand = match kind {
+                OperandKind::Register => {
+                    let register = self.next_8_bits().map_err(|fault| fault.at(start))? as usize;
+                    if register >= self.registers.len() {
+                        return Err(VmError::InvalidRegister{ pc: start, register });
+                    }
+                    register
+                },
+                OperandKind::Integer | OperandKind::Address => self.next_16_bits().map_err(|fault| fault.at(start))? as usize
+            };
+        }
+        Ok(operands)
+    }
+
+    fn decode_opcode(&mut self) -> Result<Opcode, Fault> {
+        self.next_8_bits().map(Opcode::from)
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+    fn next_8_bits(&mut self) -> Result<u8, Fault> {
+        let result = self.fetch(self.pc)?;
         self.pc += 1;
-        result
+        Ok(result)
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = (u16::from(self.program[self.pc]) << 8) | u16::from(self.program[self.pc + 1]);
+    fn next_16_bits(&mut self) -> Result<u16, Fault> {
+        let result = (u16::from(self.fetch(self.pc)?) << 8) | u16::from(self.fetch(self.pc + 1)?);
         self.pc += 2;
-        result
+        Ok(result)
+    }
+
+    /// Reads a byte of code to run, which has to be in the code region
+    fn fetch(&self, pc: usize) -> Result<u8, Fault> {
+        if pc < self.code_start || pc >= self.program.len() {
+            let reason = if pc < self.program.len() { FaultReason::Permission } else { FaultReason::Unmapped };
+            return Err(Fault { address: CODE_BASE.wrapping_add(pc as u32), access: Access::Execute, reason });
+        }
+        Ok(self.program[pc])
+    }
+
+    fn segment(&self, segment: Segment) -> &[u8] {
+        match segment {
+            Segment::Heap => &self.heap,
+            Segment::ReadOnly => &self.ro_data,
+            Segment::Code => &self.program,
+            Segment::Stack => &self.stack,
+            Segment::Guard => &[]
+        }
+    }
+
+    fn load_byte(&self, address: u32) -> Result<u8, Fault> {
+        let (segment, offset) = self.memory_map().translate(address, Access::Read)?;
+        Ok(self.segment(segment)[offset])
+    }
+
+    fn store_byte(&mut self, address: u32, value: u8) -> Result<(), Fault> {
+        let (segment, offset) = self.memory_map().translate(address, Access::Write)?;
+        match segment {
+            Segment::Heap => self.heap[offset] = value,
+            Segment::Stack => self.stack[offset] = value,
+            // The memory map only lets the heap and the stack be written
+            _ => unreachable!()
+        }
+        Ok(())
+    }
+
+    fn load_word(&self, address: u32) -> Result<i32, Fault> {
+        let mut bytes = [0; 4];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.load_byte(address.wrapping_add(offset as u32))?;
+        }
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    /// Stores a word, checking every byte can be written before writing any of them
+    fn store_word(&mut self, address: u32, value: i32) -> Result<(), Fault> {
+        let map = self.memory_map();
+        for offset in 0..4 {
+            map.translate(address.wrapping_add(offset), Access::Write)?;
+        }
+        for (offset, byte) in value.to_le_bytes().iter().enumerate() {
+            self.store_byte(address.wrapping_add(offset as u32), *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the NUL terminated string at `address`, which has to end inside the region it starts in
+    fn read_string(&self, address: u32) -> Result<Vec<u8>, Fault> {
+        let map = self.memory_map();
+        let (segment, offset) = map.translate(address, Access::Read)?;
+        let end = map.region_at(address).map_or(address, |region| region.end);
+        let bytes = &self.segment(segment)[offset..offset + (end - address) as usize];
+        match bytes.iter().position(|b| *b == 0) {
+            Some(length) => Ok(bytes[..length].to_vec()),
+            None => Err(map.translate(end, Access::Read).err()
+                .unwrap_or(Fault { address: end, access: Access::Read, reason: FaultReason::Unmapped }))
+        }
     }
 
     pub fn get_test_vm() -> VM {
@@ -243,24 +728,89 @@ impl VM {
     }
 
     fn verify_header(&self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
+        if self.program.len() < PIE_HEADER_LENGTH || self.program[0..4] != PIE_HEADER_PREFIX {
             return false
         }
         true
     }
+
+    /// Copies the read only and data sections that follow the header into `ro_data` and the heap,
+    /// loads every module the program imports from, and returns the offset of the first instruction
+    pub fn load(&mut self) -> Result<usize, LoadError> {
+        let layout = PieLayout::parse(MAIN_MODULE, &self.program)?;
+        self.ro_data = self.program[layout.ro.clone()].to_vec();
+        self.heap = self.program[layout.data.clone()].to_vec();
+        self.stack = vec![0; STACK_SIZE];
+        self.allocator.reset();
+        self.registers[30] = STACK_TOP as i32;
+        self.tags = [ValueType::Nil; 32];
+        self.tags[30] = ValueType::Pointer;
+        self.code_start = layout.code_start;
+        self.call_stack.clear();
+        self.current_module = 0;
+        self.decoded.clear();
+        self.error = None;
+        self.files.close_all();
+        self.modules = vec![LoadedModule {
+            name: MAIN_MODULE.to_string(),
+            exports: layout.exports.clone(),
+            ..LoadedModule::default()
+        }];
+        self.modules[0].imports = self.resolve_imports(&layout.imports)?;
+        Ok(layout.code_start)
+    }
+
+    fn resolve_imports(&mut self, imports: &[Import]) -> Result<Vec<(usize, usize)>, LoadError> {
+        let mut resolved = vec![];
+        for import in imports {
+            let index = self.load_module(&import.module)?;
+            let address = self.modules[index].resolve(import)?;
+            resolved.push((index, address));
+        }
+        Ok(resolved)
+    }
+
+    /// Loads a module once, no matter how many modules import it. The module is registered before its
+    /// own imports are resolved so that modules importing each other do not recurse forever.
+    fn load_module(&mut self, name: &str) -> Result<usize, LoadError> {
+        if let Some(index) = self.modules.iter().position(|m| m.name == name) {
+            return Ok(index);
+        }
+        let module = self.loader.load(name)?;
+        let layout = PieLayout::parse(name, &module.program)?;
+        let index = self.modules.len();
+        self.modules.push(module);
+        self.modules[index].imports = self.resolve_imports(&layout.imports)?;
+        Ok(index)
+    }
+
+    /// Moves the program and read only data of `target` into place so its code can run
+    fn switch_module(&mut self, target: usize) {
+        if target == self.current_module {
+            return;
+        }
+        let current = &mut self.modules[self.current_module];
+        std::mem::swap(&mut self.program, &mut current.program);
+        std::mem::swap(&mut self.ro_data, &mut current.ro_data);
+        let next = &mut self.modules[target];
+        std::mem::swap(&mut self.program, &mut next.program);
+        std::mem::swap(&mut self.ro_data, &mut next.ro_data);
+        self.current_module = target;
+        self.code_start = PieLayout::parse("", &self.program).map_or(0, |layout| layout.code_start);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::bool_assert_comparison)]
     use super::*;
-    use crate::assembler::PIE_HEADER_LENGTH;
 
     fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
         let mut prepension = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            prepension.push(byte.clone());
+        for byte in PIE_HEADER_PREFIX.iter() {
+            prepension.push(*byte);
         }
-        while prepension.len() <= PIE_HEADER_LENGTH {
+        while prepension.len() < PIE_HEADER_LENGTH {
             prepension.push(0);
         }
         prepension.append(&mut b);
@@ -282,13 +832,21 @@ mod tests {
         assert_eq!(test_vm.pc, 1);
     }
 
+    #[test]
+    fn test_trace_past_the_program() {
+        let mut test_vm = VM { program: vec![16, 0, 0, 0], pc: 5000, ..VM::default() };
+        assert!(test_vm.trace_instruction());
+        assert!(matches!(test_vm.error(), Some(VmError::MemoryFault{ pc: 5000, .. })));
+    }
+
     #[test]
     fn test_igl_opcode() {
         let mut test_vm = VM::default();
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(test_vm.error(), Some(&VmError::IllegalOpcode{ address: 0 }));
     }
  
     #[test]
@@ -463,13 +1021,126 @@ mod tests {
         assert_eq!(test_vm.pc, 7);
     }
 
+    #[test]
+    fn test_djmpe_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.equal_flag = true;
+        test_vm.program = vec![20, 0, 8, 0, 16, 0, 0, 0, 16, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 8);
+        test_vm.equal_flag = false;
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_call_and_ret_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![22, 0, 8, 0, 5, 0, 0, 0, 23, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 8);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 4);
+        assert!(test_vm.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_callx_into_shared_module() {
+        use crate::assembler::Assembler;
+        use std::fs::File;
+        use std::io::Write;
+
+        let directory = std::env::temp_dir().join(format!("iridium-modules-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let library = Assembler::new().assemble(".global answer #2\n.data\nmsg: .asciiz 'lib'\n.code\nhlt\nanswer: load $2 #42\nret").unwrap();
+        File::create(directory.join("mathlib.pie")).unwrap().write_all(&library).unwrap();
+
+        let mut test_vm = VM::default();
+        test_vm.loader.add_search_path(&directory);
+        test_vm.program = Assembler::new().assemble(".import mathlib.answer #2\n.data\nmsg: .asciiz 'main'\n.code\ncallx @answer\nload $3 #7\nhlt").unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 42);
+        assert_eq!(test_vm.registers[3], 7);
+        // Back in the main program, so its own read only data is in place again
+        assert_eq!(test_vm.ro_data, b"main\0".to_vec());
+
+        let mut test_vm = VM::default();
+        test_vm.loader.add_search_path(&directory);
+        test_vm.program = Assembler::new().assemble(".import mathlib.answer #3\n.data\n.code\ncallx @answer\nhlt").unwrap();
+        assert!(matches!(test_vm.load(), Err(LoadError::VersionMismatch{ .. })));
+
+        let mut test_vm = VM::default();
+        test_vm.add_bytes(Assembler::new().assemble(".import mathlib.answer\n.data\n.code\ncallx @answer\nhlt").unwrap());
+        assert!(matches!(test_vm.load(), Err(LoadError::ModuleNotFound{ .. })));
+        // Running it leaves the reason in `error` rather than ending the process
+        test_vm.run();
+        assert!(matches!(test_vm.error(), Some(VmError::LoadFailed{ error: LoadError::ModuleNotFound{ .. } })));
+        let mut test_vm = VM::default();
+        test_vm.add_bytes(vec![1, 2, 3, 4]);
+        test_vm.run_reference();
+        assert_eq!(test_vm.error(), Some(&VmError::BadHeader));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
     #[test]
     fn test_aloc_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 1024;
-        test_vm.program = vec![17, 0, 0, 0];
+        test_vm.program = vec![17, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.heap.len(), 1024);
+        assert_eq!(test_vm.registers[1], 4);
+        assert_eq!(test_vm.heap.len(), 1028);
+    }
+
+    #[test]
+    fn test_free_opcode() {
+        use crate::assembler::Assembler;
+
+        let run = |source: &str| {
+            let mut test_vm = VM { program: Assembler::new().assemble(source).unwrap(), ..VM::default() };
+            test_vm.allocator.max_heap = 256;
+            test_vm.run();
+            test_vm
+        };
+        // The freed block is handed out again
+        let test_vm = run(".data\n.code\nload $t0 #100\naloc $t0 $s0\nfree $s0\naloc $t0 $s1\nhlt");
+        assert_eq!(test_vm.error(), None);
+        assert_eq!(test_vm.registers[16], test_vm.registers[17]);
+
+        let test_vm = run(".data\n.code\nload $t0 #100\naloc $t0 $s0\nfree $s0\nfree $s0\nhlt");
+        assert!(matches!(test_vm.error(), Some(VmError::DoubleFree{ address: 4, .. })));
+        let test_vm = run(".data\n.code\nload $t0 #100\naloc $t0 $s0\ninc $s0\nfree $s0\nhlt");
+        assert!(matches!(test_vm.error(), Some(VmError::InvalidFree{ address: 5, .. })));
+        let test_vm = run(".data\n.code\nload $t0 #200\naloc $t0 $s0\naloc $t0 $s1\nhlt");
+        assert!(matches!(test_vm.error(), Some(VmError::OutOfMemory{ size: 200, .. })));
+        let test_vm = run(".data\n.code\nli $t0 #-8\naloc $t0 $s0\nhlt");
+        assert!(matches!(test_vm.error(), Some(VmError::OutOfMemory{ size: -8, .. })));
+    }
+
+    #[test]
+    fn test_load_sections() {
+        let mut test_vm = VM::default();
+        let mut program = crate::assembler::pie_header(3, 2, 0, 0);
+        program.extend(vec![72, 105, 0, 7, 8, 5, 0, 0, 0]);
+        test_vm.program = program;
+        test_vm.run();
+        assert_eq!(test_vm.ro_data, vec![72, 105, 0]);
+        assert_eq!(test_vm.heap, vec![7, 8]);
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 6);
+    }
+
+    #[test]
+    fn test_ldb_and_stb_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = 200;
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![26, 0, 1, 0, 25, 2, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.heap[3], 200);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 200);
     }
 
     #[test]
@@ -479,4 +1150,252 @@ mod tests {
         test_vm.program = vec![21, 0, 0, 0];
         test_vm.run_once();
     }
+
+    #[test]
+    fn test_print_opcodes() {
+        let mut test_vm = VM::buffered(b"");
+        test_vm.registers[0] = -42;
+        test_vm.registers[1] = 255;
+        test_vm.registers[2] = 0x263A;
+        test_vm.program = vec![28, 0, 0, 0, 29, 1, 0, 0, 30, 2, 0, 0];
+        test_vm.run_once();
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(String::from_utf8(test_vm.io.stdout_buffer().unwrap()).unwrap(), "-42ff\u{263A}");
+    }
+
+    #[test]
+    fn test_rdi_opcode() {
+        let mut test_vm = VM::buffered(b" -17 \nseven\n");
+        test_vm.program = vec![31, 0, 0, 0, 31, 0, 0, 0, 31, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], -17);
+        assert!(test_vm.equal_flag);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], -17);
+        assert!(!test_vm.equal_flag);
+        test_vm.equal_flag = true;
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_rdl_opcode() {
+        let mut test_vm = VM::buffered(b"Hello world\n");
+        test_vm.heap = vec![7; 8];
+        test_vm.registers[0] = 1;
+        test_vm.registers[1] = 6;
+        test_vm.program = vec![32, 0, 1, 0, 32, 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.heap, b"\x07Hello\0\x07".to_vec());
+        assert_eq!(test_vm.registers[1], 5);
+        assert!(test_vm.equal_flag);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[1], 0);
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_rdl_outside_the_heap() {
+        let source = ".data\n.code\nload $t0 #8\naloc $t0 $s0\nload $t1 #0\nload $t2 #1\nsub $t1 $t2 $t3\nrdl $t3 $t0\nhlt";
+        let mut test_vm = VM::buffered(b"Hello\n");
+        test_vm.program = crate::assembler::Assembler::new().assemble(source).unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.registers[7], -1);
+        assert_eq!(test_vm.error(), Some(&VmError::HostFunctionFailed{
+            name: "rdl".to_string(),
+            error: "the 6 byte buffer at -1 is not in the heap".to_string()
+        }));
+        assert!(test_vm.heap.iter().all(|b| *b == 0));
+
+        // A buffer that starts in the heap but runs off the end of it fails the same way
+        let mut test_vm = VM::buffered(b"Hello world\n");
+        test_vm.program = crate::assembler::Assembler::new().assemble(".data\n.code\nload $t0 #8\naloc $t0 $t1\nload $t1 #8\nrdl $t1 $t0\nhlt").unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.error().map(|e| e.to_string()), Some("Host function rdl failed: the 8 byte buffer at 8 is not in the heap".to_string()));
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        let mut test_vm = VM::buffered(b"");
+        test_vm.heap = vec![0; 4];
+        let number = test_vm.host_functions.register_named("poke", |c: &mut HostContext| {
+            let address = c.registers[0] as usize;
+            c.heap[address] = c.registers[1] as u8;
+            c.registers[0] = 1;
+            Ok(())
+        }).unwrap();
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 99;
+        test_vm.program = vec![33, 0, number as u8, 0, 33, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.heap, vec![0, 0, 99, 0]);
+        assert_eq!(test_vm.registers[0], 1);
+        // Syscall 0 is the built-in `print_int`
+        test_vm.run_once();
+        assert_eq!(test_vm.io.stdout_buffer().unwrap(), b"1".to_vec());
+        assert_eq!(test_vm.error(), None);
+    }
+
+    #[test]
+    fn test_errors_leave_pc_on_the_instruction() {
+        let run = |instruction: [u8; 4], sandbox: &str| {
+            let mut test_vm = VM::buffered(b"Hello\n");
+            test_vm.sandbox = SandboxPolicy::parse(sandbox).unwrap();
+            test_vm.registers[0] = -1;
+            test_vm.registers[1] = 6;
+            test_vm.program = vec![16, 0, 0, 0];
+            test_vm.program.extend_from_slice(&instruction);
+            assert!(!test_vm.run_once());
+            assert!(test_vm.run_once());
+            test_vm
+        };
+        let test_vm = run([21, 0, 0, 0], "clock");
+        assert!(matches!(test_vm.error(), Some(VmError::PermissionDenied{ .. })));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([27, 0, 0, 0], "clock");
+        assert!(matches!(test_vm.error(), Some(VmError::PermissionDenied{ .. })));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([33, 0, 99, 0], "all");
+        assert_eq!(test_vm.error(), Some(&VmError::UnknownSyscall{ number: 99 }));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([32, 0, 1, 0], "all");
+        assert!(matches!(test_vm.error(), Some(VmError::HostFunctionFailed{ .. })));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([28, 0, 0, 0], "clock");
+        assert!(matches!(test_vm.error(), Some(VmError::PermissionDenied{ .. })));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([255, 0, 0, 0], "all");
+        assert_eq!(test_vm.error(), Some(&VmError::IllegalOpcode{ address: 4 }));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([23, 0, 0, 0], "all");
+        assert_eq!(test_vm.error(), Some(&VmError::EmptyCallStack{ address: 4 }));
+        assert_eq!(test_vm.pc(), 4);
+        let test_vm = run([24, 0, 0, 0], "all");
+        assert_eq!(test_vm.error(), Some(&VmError::UnresolvedImport{ slot: 0 }));
+        assert_eq!(test_vm.pc(), 4);
+    }
+
+    #[test]
+    fn test_syscall_errors() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![33, 1, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VmError::UnknownSyscall{ number: 256 }));
+
+        let mut test_vm = VM::get_test_vm();
+        test_vm.host_functions.register(7, "broken", |_: &mut HostContext| Err("out of order".into()));
+        test_vm.program = vec![33, 0, 7, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error().unwrap().to_string(), "Host function broken failed: out of order");
+    }
+
+    #[test]
+    fn test_sandbox_denies_console() {
+        let mut test_vm = VM::buffered(b"");
+        test_vm.sandbox = SandboxPolicy::deny_all();
+        test_vm.ro_data = b"Hello\0".to_vec();
+        test_vm.program = vec![21, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VmError::PermissionDenied{ capability: Capability::Console }));
+        assert_eq!(test_vm.io.stdout_buffer().unwrap(), vec![]);
+
+        let mut test_vm = VM::buffered(b"");
+        test_vm.sandbox = SandboxPolicy::parse("console").unwrap();
+        test_vm.host_functions.register(9, "double", |c: &mut HostContext| {
+            c.registers[0] *= 2;
+            Ok(())
+        });
+        test_vm.program = vec![28, 0, 0, 0, 33, 0, 9, 0];
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.error().unwrap().to_string(), "The sandbox policy does not allow host:double");
+    }
+
+    #[test]
+    fn test_buffered_output() {
+        let mut test_vm = VM::buffered(b"");
+        test_vm.ro_data = b"Hello\0".to_vec();
+        test_vm.heap = b"world\0".to_vec();
+        test_vm.program = vec![21, 0, 0, 0, 27, 0, 0, 0];
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.io.stdout_buffer().unwrap(), b"Helloworld".to_vec());
+        assert_eq!(test_vm.io.stderr_buffer().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        use crate::assembler::Assembler;
+
+        let run = |source: &str| {
+            let mut test_vm = VM { program: Assembler::new().assemble(source).unwrap(), ..VM::default() };
+            test_vm.run();
+            test_vm
+        };
+        // The stack is ready to use through `$sp`, and the guard page above it catches underflow
+        let test_vm = run(".data\n.code\nload $t0 #65\ndec $sp\nstb $t0 $sp\nldb $t1 $sp\ninc $sp\nstb $t0 $sp\nhlt");
+        assert_eq!(test_vm.registers[5], 65);
+        let code_start = test_vm.code_start;
+        assert_eq!(test_vm.error(), Some(&VmError::MemoryFault{
+            pc: code_start + 20,
+            address: STACK_TOP,
+            access: Access::Write,
+            reason: FaultReason::Guard
+        }));
+        assert_eq!(test_vm.pc, code_start + 20);
+
+        // Read only data can be read but not written
+        let test_vm = run(".data\nmsg: .asciiz 'Hi'\n.code\nli $t0 #536870912\nldb $t1 $t0\nstb $t1 $t0\nhlt");
+        assert_eq!(test_vm.registers[5], 72);
+        assert!(matches!(test_vm.error(), Some(VmError::MemoryFault{ reason: FaultReason::Permission, access: Access::Write, .. })));
+
+        let test_vm = run(".data\n.code\nload $t0 #4\nldb $t1 $t0\nhlt");
+        assert!(matches!(test_vm.error(), Some(VmError::MemoryFault{ address: 4, reason: FaultReason::Unmapped, .. })));
+
+        // Jumping into the header is not allowed, as it is not code
+        let test_vm = run(".data\n.code\nload $t0 #8\njmp $t0\nhlt");
+        assert!(matches!(test_vm.error(), Some(VmError::MemoryFault{ pc: 8, access: Access::Execute, .. })));
+
+        let test_vm = run(".data\n.code\nload $t0 #7\ndiv $t0 $t1 $t2\nhlt");
+        assert_eq!(test_vm.error(), Some(&VmError::DivideByZero{ pc: code_start + 4 }));
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![18, 40, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VmError::InvalidRegister{ pc: 0, register: 40 }));
+        assert_eq!(test_vm.pc, 0);
+
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![0, 1];
+        test_vm.run_once();
+        assert!(matches!(test_vm.error(), Some(VmError::MemoryFault{ pc: 0, address, .. }) if *address == CODE_BASE + 2));
+    }
+
+    #[test]
+    fn test_resume_after_fault() {
+        let program = crate::assembler::Assembler::new().assemble(".data\n.code\nload $t0 #2\nldb $t1 $t0\nload $t2 #9\nhlt").unwrap();
+        let mut test_vm = VM { program, ..VM::default() };
+        test_vm.run();
+        assert!(test_vm.error().is_some());
+        test_vm.heap = vec![0, 0, 42];
+        test_vm.resume();
+        assert_eq!(test_vm.error(), None);
+        assert_eq!(test_vm.registers[5], 42);
+        assert_eq!(test_vm.registers[6], 9);
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let mut test_vm = VM::get_test_vm();
+        let registers = test_vm.registers;
+        test_vm.program = vec![1, 0, 1, 4, 9, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.changes_since(&registers, false), "$t0: 0 -> 15");
+        test_vm.run_once();
+        assert_eq!(test_vm.changes_since(&registers, false), "$t0: 0 -> 15, equal: false -> true");
+    }
 }
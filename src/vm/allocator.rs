@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+
+use super::memory::HEAP_LIMIT;
+
+/// Blocks are handed out in multiples of this many bytes, and never start at 0 so programs can use
+/// 0 as a null pointer
+pub const ALIGNMENT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AllocError {
+    /// The size was negative, or the heap cannot grow enough to fit the block
+    OutOfMemory,
+    /// The address is inside a block that is already free
+    DoubleFree,
+    /// Nothing was allocated at the address
+    InvalidFree
+}
+
+/// Hands out blocks of the heap for `aloc` and takes them back for `free`. Blocks are tracked here
+/// rather than in headers inside the heap, so a program writing past the end of a block cannot
+/// corrupt the allocator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocator {
+    /// Largest the heap may grow to, in bytes. It can never go past `memory::HEAP_LIMIT`.
+    pub max_heap: usize,
+    /// End of the part of the heap blocks are carved from. Everything before the first block, such as
+    /// the data section, is left alone.
+    top: usize,
+    /// Sizes of the allocated blocks, by address
+    allocated: BTreeMap<usize, usize>,
+    /// Sizes of the free blocks, by address. Free blocks next to each other are always merged.
+    free: BTreeMap<usize, usize>
+}
+
+impl Allocator {
+    pub fn new(max_heap: usize) -> Allocator {
+        Allocator {
+            max_heap,
+            top: 0,
+            allocated: BTreeMap::new(),
+            free: BTreeMap::new()
+        }
+    }
+
+    /// Rebuilds an allocator from what `top`, `blocks` and `free_blocks` returned, as when restoring
+    /// a snapshot
+    pub fn from_parts(max_heap: usize, top: usize, blocks: Vec<(usize, usize)>, free_blocks: Vec<(usize, usize)>) -> Allocator {
+        Allocator {
+            max_heap,
+            top,
+            allocated: blocks.into_iter().collect(),
+            free: free_blocks.into_iter().collect()
+        }
+    }
+
+    /// Forgets every block, for when a new program is loaded
+    pub fn reset(&mut self) {
+        self.top = 0;
+        self.allocated.clear();
+        self.free.clear();
+    }
+
+    /// Finds room for `size` bytes, growing the heap if no free block is big enough, and returns the
+    /// address of the block. Its bytes are all zero.
+    pub fn allocate(&mut self, heap: &mut Vec<u8>, size: i32) -> Result<usize, AllocError> {
+        if size < 0 {
+            return Err(AllocError::OutOfMemory);
+        }
+        let size = align(size as usize).max(ALIGNMENT);
+        self.top = self.top.max(align(heap.len())).max(ALIGNMENT);
+
+        let address = match self.free.iter().find(|(_, length)| **length >= size).map(|(a, l)| (*a, *l)) {
+            Some((address, length)) => {
+                self.free.remove(&address);
+                if length > size {
+                    self.free.insert(address + size, length - size);
+                }
+                address
+            },
+            None => {
+                // A free block at the very end only needs the heap to grow by what it is missing
+                let address = match self.free.iter().next_back().map(|(a, l)| (*a, *l)) {
+                    Some((address, length)) if address + length == self.top => address,
+                    _ => self.top
+                };
+                if address + size > self.max_heap.min(HEAP_LIMIT as usize) {
+                    return Err(AllocError::OutOfMemory);
+                }
+                self.free.remove(&address);
+                self.top = address + size;
+                address
+            }
+        };
+        if heap.len() < self.top {
+            heap.resize(self.top, 0);
+        }
+        for byte in &mut heap[address..address + size] {
+            *byte = 0;
+        }
+        self.allocated.insert(address, size);
+        Ok(address)
+    }
+
+    /// Gives the block at `address` back, merging it with any free blocks on either side
+    pub fn free(&mut self, address: usize) -> Result<(), AllocError> {
+        let mut size = match self.allocated.remove(&address) {
+            Some(size) => size,
+            None if self.free_block_containing(address).is_some() => return Err(AllocError::DoubleFree),
+            None => return Err(AllocError::InvalidFree)
+        };
+        let mut start = address;
+        if let Some(next) = self.free.remove(&(address + size)) {
+            size += next;
+        }
+        if let Some((previous, length)) = self.free.range(..address).next_back().map(|(a, l)| (*a, *l)) {
+            if previous + length == address {
+                self.free.remove(&previous);
+                start = previous;
+                size += length;
+            }
+        }
+        self.free.insert(start, size);
+        Ok(())
+    }
+
+    /// End of the last block handed out so far
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// Size of the block allocated at `address`
+    pub fn size_of(&self, address: usize) -> Option<usize> {
+        self.allocated.get(&address).cloned()
+    }
+
+    /// Every allocated block as its address and size, in address order
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.allocated.iter().map(|(address, size)| (*address, *size))
+    }
+
+    /// Free blocks as their address and size, in address order
+    pub fn free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.free.iter().map(|(address, size)| (*address, *size))
+    }
+
+    fn free_block_containing(&self, address: usize) -> Option<usize> {
+        self.free.range(..=address).next_back()
+            .filter(|(start, size)| address < **start + **size)
+            .map(|(start, _)| *start)
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Allocator {
+        Allocator::new(HEAP_LIMIT as usize)
+    }
+}
+
+fn align(size: usize) -> usize {
+    size.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_free() {
+        let mut heap = vec![9; 6];
+        let mut allocator = Allocator::default();
+        // Blocks start after the data already in the heap
+        let first = allocator.allocate(&mut heap, 10).unwrap();
+        assert_eq!(first, 8);
+        assert_eq!(heap.len(), 20);
+        assert_eq!(&heap[8..20], &[0; 12]);
+        let second = allocator.allocate(&mut heap, 4).unwrap();
+        let third = allocator.allocate(&mut heap, 4).unwrap();
+        assert_eq!((second, third), (20, 24));
+
+        heap[20] = 7;
+        allocator.free(second).unwrap();
+        allocator.free(first).unwrap();
+        assert_eq!(allocator.free_blocks().collect::<Vec<_>>(), vec![(8, 16)]);
+        // The freed space is reused, and handed out zeroed
+        assert_eq!(allocator.allocate(&mut heap, 16).unwrap(), 8);
+        assert_eq!(heap[20], 0);
+        assert_eq!(allocator.blocks().collect::<Vec<_>>(), vec![(8, 16), (24, 4)]);
+    }
+
+    #[test]
+    fn test_free_errors() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::default();
+        let block = allocator.allocate(&mut heap, 8).unwrap();
+        assert_eq!(block, ALIGNMENT);
+        assert_eq!(allocator.free(block + 4), Err(AllocError::InvalidFree));
+        allocator.free(block).unwrap();
+        assert_eq!(allocator.free(block), Err(AllocError::DoubleFree));
+        assert_eq!(allocator.free(0), Err(AllocError::InvalidFree));
+    }
+
+    #[test]
+    fn test_max_heap() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new(64);
+        let block = allocator.allocate(&mut heap, 40).unwrap();
+        assert_eq!(allocator.allocate(&mut heap, 40), Err(AllocError::OutOfMemory));
+        assert_eq!(allocator.allocate(&mut heap, -1), Err(AllocError::OutOfMemory));
+        // A free block at the end of the heap is grown into rather than left behind
+        allocator.free(block).unwrap();
+        assert_eq!(allocator.allocate(&mut heap, 60), Ok(ALIGNMENT));
+        assert_eq!(heap.len(), 64);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::instruction::{Opcode, OperandKind, INSTRUCTION_LENGTH};
+
+use super::VM;
+
+/// Marks a byte of code that no decoded instruction starts at
+const NO_INSTRUCTION: u32 = u32::MAX;
+/// Number of registers a VM has
+const REGISTERS: usize = 32;
+
+/// An instruction with its operands already read out of the bytecode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decoded {
+    pub opcode: Opcode,
+    /// Register numbers or 16 bit values, in the order the opcode table lists them
+    pub operands: [u16; 3],
+    /// Bytes taken up by the opcode and operands, which is where `pc` points when the instruction runs
+    pub operand_length: u8
+}
+
+/// The code of a module, checked and decoded once so the fast engine never looks at its bytes again
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedProgram {
+    pub instructions: Vec<Decoded>,
+    /// For every byte of the program, the index of the instruction that starts there
+    slots: Vec<u32>
+}
+
+impl DecodedProgram {
+    /// Decodes every instruction from `start` on. Unknown opcodes decode as `igl`, which stops the VM
+    /// like it does in the reference interpreter. Instructions naming a register that does not exist,
+    /// and trailing bytes too short to hold an instruction, are left undecoded so the reference
+    /// interpreter reports them.
+    pub fn decode(program: &[u8], start: usize) -> DecodedProgram {
+        let mut decoded = DecodedProgram {
+            instructions: vec![],
+            slots: vec![NO_INSTRUCTION; program.len()]
+        };
+        let mut position = start;
+        while position < program.len() {
+            let opcode = Opcode::from(program[position]);
+            let info = opcode.info();
+            if position + INSTRUCTION_LENGTH > program.len() {
+                break;
+            }
+            let mut operands = [0; 3];
+            let mut offset = position + 1;
+            let mut valid = true;
+            for (operand, kind) in operands.iter_mut().zip(info.operands) {
+                *operand = match kind {
+                    OperandKind::Register => {
+                        valid &= usize::from(program[offset]) < REGISTERS;
+                        u16::from(program[offset])
+                    },
+                    OperandKind::Integer | OperandKind::Address => {
+                        (u16::from(program[offset]) << 8) | u16::from(program[offset + 1])
+                    }
+                };
+                offset += kind.width();
+            }
+            if valid {
+                decoded.slots[position] = decoded.instructions.len() as u32;
+                decoded.instructions.push(Decoded {
+                    opcode,
+                    operands,
+                    operand_length: (offset - position) as u8
+                });
+            }
+            position += INSTRUCTION_LENGTH;
+        }
+        decoded
+    }
+
+    /// The instruction starting at `pc`, if one was decoded there
+    #[inline(always)]
+    pub fn at(&self, pc: usize) -> Option<Decoded> {
+        match self.slots.get(pc) {
+            Some(&index) if index != NO_INSTRUCTION => Some(self.instructions[index as usize]),
+            _ => None
+        }
+    }
+}
+
+impl VM {
+    /// Runs from `pc` until the program stops, using decoded instructions. A jump into the middle of
+    /// an instruction is run by the reference interpreter, so odd programs still behave the same.
+    pub(super) fn run_decoded(&mut self) {
+        loop {
+            let decoded = match self.decoded.get(self.current_module) {
+                // Tracing, recording and profiling are slow anyway, so they go through the reference interpreter
+                Some(Some(_)) if self.trace || self.recorder.is_some() || self.profiler.is_some() => None,
+                Some(Some(program)) => program.at(self.pc),
+                _ => {
+                    self.decode_current_module();
+                    continue;
+                }
+            };
+            let is_done = match decoded {
+                Some(d) => {
+                    let start = self.pc;
+                    self.pc = start + d.operand_length as usize;
+                    let [a, b, c] = d.operands;
+                    self.execute(d.opcode, [a as usize, b as usize, c as usize], start + INSTRUCTION_LENGTH)
+                },
+                None => self.execute_instruction()
+            };
+            if is_done {
+                return;
+            }
+        }
+    }
+
+    fn decode_current_module(&mut self) {
+        let start = self.code_start;
+        if self.decoded.len() <= self.current_module {
+            self.decoded.resize(self.current_module + 1, None);
+        }
+        self.decoded[self.current_module] = Some(DecodedProgram::decode(&self.program, start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn run_both(source: &str) -> (VM, VM) {
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut reference = VM { program: program.clone(), ..VM::default() };
+        reference.run_reference();
+        let mut fast = VM { program, ..VM::default() };
+        fast.run();
+        (reference, fast)
+    }
+
+    fn assert_same_state(reference: &VM, fast: &VM) {
+        assert_eq!(reference.registers, fast.registers);
+        assert_eq!(reference.pc, fast.pc);
+        assert_eq!(reference.heap, fast.heap);
+        assert_eq!(reference.remainder, fast.remainder);
+        assert_eq!(reference.equal_flag, fast.equal_flag);
+        assert_eq!(reference.call_stack, fast.call_stack);
+        assert_eq!(reference.error, fast.error);
+    }
+
+    #[test]
+    fn test_decode_program() {
+        let decoded = DecodedProgram::decode(&[0, 4, 1, 244, 5, 0, 0, 0, 200, 0, 0, 0, 1], 0);
+        assert_eq!(decoded.instructions.len(), 3);
+        assert_eq!(decoded.at(0).unwrap().operands, [4, 500, 0]);
+        assert_eq!(decoded.at(0).unwrap().operand_length, 4);
+        assert_eq!(decoded.at(4).unwrap().operand_length, 1);
+        assert_eq!(decoded.at(8).unwrap().opcode, Opcode::IGL);
+        assert_eq!(decoded.at(2), None);
+        assert_eq!(decoded.at(12), None);
+
+        // `inc $32` names a register past the last one
+        let decoded = DecodedProgram::decode(&[18, 32, 0, 0, 5, 0, 0, 0], 0);
+        assert_eq!(decoded.at(0), None);
+        assert_eq!(decoded.at(4).unwrap().opcode, Opcode::HLT);
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        let programs = [
+            ".data\n.code\nload $t0 #1000\nload $t1 #0\nloop: inc $t1\ndec $t0\nload $t2 #0\nneq $t0 $t2\ndjmpe @loop\nhlt",
+            ".data\n.code\nload $a0 #17\nload $a1 #5\ndiv $a0 $a1 $a2\nmul $a2 $a1 $a3\nsub $a0 $a3 $t0\nhlt",
+            ".data\n.code\nload $t0 #64\naloc $t0 $a0\nload $a1 #65\ncall @fill\nldb $t3 $a0\nhlt\nfill: stb $a1 $a0\nret",
+            ".data\n.code\nload $a0 #2\nlt $a0 $a1\njmpe $a0\nhlt",
+            ".data\n.code\nret"
+        ];
+        for source in programs.iter() {
+            let (reference, fast) = run_both(source);
+            assert_same_state(&reference, &fast);
+        }
+    }
+
+    #[test]
+    fn test_engines_agree_on_stdlib() {
+        let (reference, fast) = run_both(".include <std/string>\n.data\n.code\nload $t0 #32\naloc $t0 $s0\nmov $a1 $s0\nli $a0 #-12345\ncall @itoa\nmov $a0 $s0\ncall @strlen\nhlt");
+        assert_eq!(reference.registers[0], 6);
+        assert_same_state(&reference, &fast);
+    }
+
+    #[test]
+    fn test_jump_into_instruction() {
+        // The jump lands on the last two bytes of `load $t0 #4612`, whose 16 bit operand is [18, 4],
+        // so the VM runs `inc $t0` and then `hlt` out of the middle of the following `nop`. Neither
+        // starts where the fast engine decoded an instruction.
+        let mut program = crate::assembler::pie_header(0, 0, 0, 0);
+        let start = program.len() as u8;
+        program.extend(vec![0, 5, 0, start + 10, 6, 5, 0, 0, 0, 4, 18, 4, 16, 0, 5, 0, 5, 0, 0, 0]);
+        let mut reference = VM { program: program.clone(), ..VM::default() };
+        reference.run_reference();
+        let mut fast = VM { program, ..VM::default() };
+        fast.run();
+        assert_eq!(fast.registers[4], 1);
+        assert_eq!(fast.pc, start as usize + 15);
+        assert_same_state(&reference, &fast);
+    }
+}
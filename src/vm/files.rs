@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::host::{HostContext, HostError};
+use super::memory::{Access, Segment, HEAP_BASE, RO_BASE};
+use super::sandbox::Capability;
+
+/// Error codes the file syscalls return in `$a0`. Anything zero or above means success.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileError {
+    NotFound = -1,
+    /// The path leaves the root directory, or no root directory was set up
+    OutsideRoot = -2,
+    BadDescriptor = -3,
+    InvalidArgument = -4,
+    Io = -5,
+    /// The operating system does not let the VM use the file
+    Denied = -6
+}
+
+impl From<io::Error> for FileError {
+    fn from(error: io::Error) -> FileError {
+        match error.kind() {
+            io::ErrorKind::NotFound => FileError::NotFound,
+            io::ErrorKind::PermissionDenied => FileError::Denied,
+            io::ErrorKind::InvalidInput => FileError::InvalidArgument,
+            _ => FileError::Io
+        }
+    }
+}
+
+/// Files opened by a program, by descriptor. Programs can only open files under `root`, and cannot
+/// open any until it is set.
+#[derive(Debug, Default)]
+pub struct FileTable {
+    pub root: Option<PathBuf>,
+    files: HashMap<i32, File>,
+    next_descriptor: i32
+}
+
+/// Descriptors 0 to 2 are left alone, as programs may expect them to mean the console
+const FIRST_DESCRIPTOR: i32 = 3;
+
+impl FileTable {
+    pub fn new(root: Option<PathBuf>) -> FileTable {
+        FileTable {
+            root,
+            files: HashMap::new(),
+            next_descriptor: FIRST_DESCRIPTOR
+        }
+    }
+
+    /// Closes every open file
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+
+    /// Turns a path relative to the root into one that can be opened. Absolute paths, `..` and
+    /// symbolic links that lead out of the root, or that lead to nothing, are all rejected.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let root = self.root.as_ref().ok_or(FileError::OutsideRoot)?;
+        let relative = Path::new(path);
+        if path.is_empty() || relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(FileError::OutsideRoot);
+        }
+        let root = root.canonicalize()?;
+        let full = root.join(relative);
+        // Creating a file through a link to nothing creates whatever the link points to, which may be
+        // outside the root
+        if !full.exists() && full.symlink_metadata().is_ok() {
+            return Err(FileError::OutsideRoot);
+        }
+        // A file that does not exist yet cannot be a link, but the directory it goes in can be
+        let existing = if full.exists() { full.clone() } else { full.parent().map(Path::to_path_buf).unwrap_or_default() };
+        if !existing.canonicalize()?.starts_with(&root) {
+            return Err(FileError::OutsideRoot);
+        }
+        Ok(full)
+    }
+
+    fn open(&mut self, path: &str, mode: i32) -> Result<i32, FileError> {
+        let path = self.resolve(path)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            3 => options.read(true).write(true),
+            _ => return Err(FileError::InvalidArgument)
+        };
+        let file = options.open(path)?;
+        let descriptor = self.next_descriptor.max(FIRST_DESCRIPTOR);
+        self.next_descriptor = descriptor + 1;
+        self.files.insert(descriptor, file);
+        Ok(descriptor)
+    }
+
+    fn file(&mut self, descriptor: i32) -> Result<&mut File, FileError> {
+        self.files.get_mut(&descriptor).ok_or(FileError::BadDescriptor)
+    }
+}
+
+/// Returns a file syscall's result in `$a0`, as the value on success and the error code otherwise
+fn finish(context: &mut HostContext, result: Result<i32, FileError>) -> Result<(), HostError> {
+    context.registers[0] = match result {
+        Ok(value) => value,
+        Err(error) => error as i32
+    };
+    Ok(())
+}
+
+/// The heap range a buffer given by an address register and a length register covers, when the
+/// memory map allows the access to all of it
+fn buffer(context: &HostContext, address: usize, length: usize, access: Access) -> Result<std::ops::Range<usize>, FileError> {
+    let length = context.registers[length];
+    if length < 0 {
+        return Err(FileError::InvalidArgument);
+    }
+    context.heap_range(context.registers[address], length as usize, access).map_err(|_| FileError::InvalidArgument)
+}
+
+/// `open`: `$a0` is the address of a NUL terminated path, which is in the heap if `$a1` is 0 and in
+/// the read only section if it is 1. `$a2` is the mode: 0 reads, 1 creates or truncates for writing,
+/// 2 appends and 3 reads and writes. Returns the descriptor.
+pub fn open(context: &mut HostContext) -> Result<(), HostError> {
+    let (base, segment) = match context.registers[1] {
+        0 => (HEAP_BASE, Segment::Heap),
+        1 => (RO_BASE, Segment::ReadOnly),
+        _ => return finish(context, Err(FileError::InvalidArgument))
+    };
+    let start = context.registers[0];
+    if start < 0 {
+        return finish(context, Err(FileError::InvalidArgument));
+    }
+    let memory: &[u8] = match base.checked_add(start as u32).map(|address| context.memory_map.translate(address, Access::Read)) {
+        Some(Ok((Segment::Heap, offset))) if segment == Segment::Heap => &context.heap[offset..],
+        Some(Ok((Segment::ReadOnly, offset))) if segment == Segment::ReadOnly => &context.ro_data[offset..],
+        _ => return finish(context, Err(FileError::InvalidArgument))
+    };
+    let path = memory.iter().position(|b| *b == 0)
+        .and_then(|end| std::str::from_utf8(&memory[..end]).ok())
+        .map(str::to_string);
+    let path = match path {
+        Some(path) => path,
+        None => return finish(context, Err(FileError::InvalidArgument))
+    };
+    context.require(Capability::Filesystem(PathBuf::from(&path)))?;
+    let mode = context.registers[2];
+    let result = context.files.open(&path, mode);
+    finish(context, result)
+}
+
+/// `read`: reads up to `$a2` bytes from descriptor `$a0` into the heap at `$a1`. Returns the number of
+/// bytes read, which is 0 at the end of the file.
+pub fn read(context: &mut HostContext) -> Result<(), HostError> {
+    let result = buffer(context, 1, 2, Access::Write).and_then(|range| {
+        let file = context.files.file(context.registers[0])?;
+        Ok(file.read(&mut context.heap[range])? as i32)
+    });
+    finish(context, result)
+}
+
+/// `write`: writes `$a2` bytes from the heap at `$a1` to descriptor `$a0`. Returns the number of bytes
+/// written.
+pub fn write(context: &mut HostContext) -> Result<(), HostError> {
+    let result = buffer(context, 1, 2, Access::Read).and_then(|range| {
+        let file = context.files.file(context.registers[0])?;
+        file.write_all(&context.heap[range.clone()])?;
+        Ok(range.len() as i32)
+    });
+    finish(context, result)
+}
+
+/// `close`: closes descriptor `$a0`. Returns 0.
+pub fn close(context: &mut HostContext) -> Result<(), HostError> {
+    let descriptor = context.registers[0];
+    let result = context.files.files.remove(&descriptor).map(|_| 0).ok_or(FileError::BadDescriptor);
+    finish(context, result)
+}
+
+/// `seek`: moves descriptor `$a0` to offset `$a1`, counted from the start of the file if `$a2` is 0,
+/// from the current position if it is 1 and from the end if it is 2. Returns the new position.
+pub fn seek(context: &mut HostContext) -> Result<(), HostError> {
+    let offset = context.registers[1];
+    let position = match context.registers[2] {
+        0 if offset >= 0 => Some(SeekFrom::Start(offset as u64)),
+        1 => Some(SeekFrom::Current(i64::from(offset))),
+        2 => Some(SeekFrom::End(i64::from(offset))),
+        _ => None
+    };
+    let result = position.ok_or(FileError::InvalidArgument).and_then(|position| {
+        let file = context.files.file(context.registers[0])?;
+        let position = file.seek(position)?;
+        if position > i32::MAX as u64 {
+            return Err(FileError::InvalidArgument);
+        }
+        Ok(position as i32)
+    });
+    finish(context, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("iridium-files-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(root.join("reports")).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_resolve_paths() {
+        let root = temp_root("resolve");
+        let table = FileTable::new(Some(root.clone()));
+        assert_eq!(table.resolve("reports/today.txt").unwrap(), root.canonicalize().unwrap().join("reports/today.txt"));
+        assert_eq!(table.resolve("../escape.txt"), Err(FileError::OutsideRoot));
+        assert_eq!(table.resolve("reports/../../escape.txt"), Err(FileError::OutsideRoot));
+        assert_eq!(table.resolve("/etc/passwd"), Err(FileError::OutsideRoot));
+        assert_eq!(table.resolve("missing/file.txt"), Err(FileError::NotFound));
+        assert_eq!(FileTable::new(None).resolve("reports/today.txt"), Err(FileError::OutsideRoot));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), root.join("outside")).unwrap();
+            assert_eq!(table.resolve("outside/file.txt"), Err(FileError::OutsideRoot));
+            let nowhere = std::env::temp_dir().join(format!("iridium-files-nowhere-{}", std::process::id()));
+            std::os::unix::fs::symlink(&nowhere, root.join("dangling")).unwrap();
+            assert_eq!(table.resolve("dangling"), Err(FileError::OutsideRoot));
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_file_syscalls() {
+        use crate::assembler::Assembler;
+        use crate::vm::VM;
+
+        let root = temp_root("syscalls");
+        let program = Assembler::new().assemble(".data\nname: .asciiz 'reports/hi.txt'\nescape: .asciiz '../hi.txt'\n.code\n\
+            load $t0 #32\naloc $t0 $s5\nload $t1 #72\nmov $t2 $s5\nstb $t1 $t2\nload $t1 #105\ninc $t2\nstb $t1 $t2\n\
+            load $a0 @name\nload $a1 #1\nload $a2 #1\nsyscall #7\nmov $s0 $a0\n\
+            mov $a1 $s5\nload $a2 #2\nsyscall #9\nmov $s1 $a0\nmov $a0 $s0\nsyscall #10\n\
+            load $a0 @name\nload $a1 #1\nload $a2 #0\nsyscall #7\nmov $s0 $a0\n\
+            load $a1 #16\nadd $a1 $s5 $a1\nload $a2 #10\nsyscall #8\nmov $s2 $a0\n\
+            mov $a0 $s0\nload $a1 #1\nload $a2 #0\nsyscall #11\nmov $s3 $a0\n\
+            load $a0 @escape\nload $a1 #1\nload $a2 #0\nsyscall #7\nmov $s4 $a0\n\
+            load $a0 #99\nsyscall #10\nhlt").unwrap();
+
+        let mut vm = VM::default();
+        vm.files.root = Some(root.clone());
+        vm.add_bytes(program.clone());
+        vm.run();
+        assert_eq!(std::fs::read(root.join("reports/hi.txt")).unwrap(), b"Hi".to_vec());
+        assert_eq!(vm.registers[17], 2);
+        assert_eq!(vm.registers[18], 2);
+        assert_eq!(&vm.heap()[20..22], b"Hi");
+        assert_eq!(vm.registers[19], 1);
+        assert_eq!(vm.registers[20], FileError::OutsideRoot as i32);
+        assert_eq!(vm.registers[0], FileError::BadDescriptor as i32);
+
+        // The sandbox policy has to allow the path as well
+        let mut vm = VM::default();
+        vm.files.root = Some(root.clone());
+        vm.sandbox = crate::vm::sandbox::SandboxPolicy::parse("fs:logs").unwrap();
+        vm.add_bytes(program);
+        vm.run();
+        assert!(vm.error().unwrap().to_string().contains("fs:reports/hi.txt"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_open_negative_address() {
+        use crate::assembler::Assembler;
+        use crate::vm::VM;
+
+        let root = temp_root("negative");
+        let mut vm = VM::default();
+        vm.files.root = Some(root.clone());
+        let program = Assembler::new().assemble(".data\nname: .asciiz 'hi.txt'\n.code\n\
+            load $t0 #1\nload $a0 #0\nsub $a0 $t0 $a0\nload $a1 #1\nload $a2 #1\nsyscall #7\nhlt").unwrap();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.registers[0], FileError::InvalidArgument as i32);
+        assert!(!root.join("hi.txt").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::allocator::AllocError;
+use super::memory::STACK_TOP;
+use super::VM;
+
+/// Bytes in front of every collected object: its size as a little endian u32, then its type tag and
+/// its mark byte, then two bytes of padding. Object addresses point just past the header.
+pub const HEADER_SIZE: usize = 8;
+/// Tags with this bit set hold pointers: each of their 32 bit words that is the address of an object
+/// keeps that object alive. The other bits are for languages to use as they like.
+pub const TAG_POINTERS: u8 = 1;
+
+const TAG_OFFSET: usize = 4;
+const MARK_OFFSET: usize = 5;
+
+/// Whether the collector runs, and what it has done so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GarbageCollector {
+    /// In GC mode `aloc` and `new` allocate objects with headers, `gc` collects, and a failed
+    /// allocation collects before giving up
+    pub enabled: bool,
+    pub stats: GcStats
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    /// Objects and bytes, headers included, freed over every collection
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    /// Objects and bytes still live after the last collection
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    /// Time spent collecting
+    pub pause: Duration
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} collections freed {} objects ({} bytes) in {:?}, {} objects ({} bytes) live",
+            self.collections, self.objects_freed, self.bytes_freed, self.pause, self.live_objects, self.live_bytes)
+    }
+}
+
+impl VM {
+    /// Allocates an object of `size` bytes with a type tag and returns its address. In GC mode a
+    /// collection is tried before running out of memory.
+    pub(super) fn allocate_object(&mut self, size: i32, tag: u8) -> Result<usize, AllocError> {
+        let total = size.checked_add(HEADER_SIZE as i32).ok_or(AllocError::OutOfMemory)?;
+        let block = match self.allocator.allocate(&mut self.heap, total) {
+            Err(AllocError::OutOfMemory) if self.gc.enabled && size >= 0 => {
+                self.collect();
+                self.allocator.allocate(&mut self.heap, total)?
+            },
+            result => result?
+        };
+        let length = self.allocator.size_of(block).unwrap_or(0) - HEADER_SIZE;
+        LittleEndian::write_u32(&mut self.heap[block..], length as u32);
+        self.heap[block + TAG_OFFSET] = tag;
+        Ok(block + HEADER_SIZE)
+    }
+
+    /// Frees every object that cannot be reached from the registers or the stack, and returns the
+    /// statistics so far
+    pub fn collect(&mut self) -> GcStats {
+        let started = Instant::now();
+        let mut pending: Vec<usize> = self.registers.iter().filter_map(|value| self.object_at(*value)).collect();
+        let stack_start = (self.registers[30] as u32).clamp(STACK_TOP - self.stack.len() as u32, STACK_TOP);
+        let stack = &self.stack[(stack_start - (STACK_TOP - self.stack.len() as u32)) as usize..];
+        pending.extend(stack.chunks_exact(4).filter_map(|word| self.object_at(LittleEndian::read_i32(word))));
+
+        while let Some(block) = pending.pop() {
+            if self.heap[block + MARK_OFFSET] != 0 {
+                continue;
+            }
+            self.heap[block + MARK_OFFSET] = 1;
+            if self.heap[block + TAG_OFFSET] & TAG_POINTERS != 0 {
+                let end = block + self.allocator.size_of(block).unwrap_or(HEADER_SIZE);
+                let fields = &self.heap[block + HEADER_SIZE..end];
+                pending.extend(fields.chunks_exact(4).filter_map(|word| self.object_at(LittleEndian::read_i32(word))));
+            }
+        }
+
+        let blocks: Vec<(usize, usize)> = self.allocator.blocks().collect();
+        let (mut live_objects, mut live_bytes) = (0, 0);
+        for (block, size) in blocks {
+            if self.heap[block + MARK_OFFSET] != 0 {
+                self.heap[block + MARK_OFFSET] = 0;
+                live_objects += 1;
+                live_bytes += size;
+            } else if self.allocator.free(block).is_ok() {
+                self.gc.stats.objects_freed += 1;
+                self.gc.stats.bytes_freed += size;
+            }
+        }
+        let stats = &mut self.gc.stats;
+        stats.collections += 1;
+        stats.live_objects = live_objects;
+        stats.live_bytes = live_bytes;
+        stats.pause += started.elapsed();
+        debug!("Garbage collection {}: {}", stats.collections, stats);
+        *stats
+    }
+
+    /// The block of the object a value points at, if it is the address of one
+    fn object_at(&self, value: i32) -> Option<usize> {
+        let address = value as u32 as usize;
+        if address < HEADER_SIZE {
+            return None;
+        }
+        self.allocator.size_of(address - HEADER_SIZE).map(|_| address - HEADER_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn run(source: &str, max_heap: usize) -> VM {
+        let mut vm = VM { program: Assembler::new().assemble(source).unwrap(), ..VM::default() };
+        vm.gc.enabled = true;
+        vm.allocator.max_heap = max_heap;
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_unreachable_objects_are_freed() {
+        let vm = run(".data\n.code\nload $t0 #16\nload $t1 #0\nnew $t0 $t1 $s0\nnew $t0 $t1 $s1\nnew $t0 $t1 $s2\n\
+                      load $s1 #0\nload $s2 #0\ngc\nhlt", 4096);
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.gc.stats.collections, 1);
+        assert_eq!(vm.gc.stats.objects_freed, 2);
+        assert_eq!(vm.gc.stats.live_objects, 1);
+        assert_eq!(vm.gc.stats.live_bytes, 16 + HEADER_SIZE);
+        assert_eq!(LittleEndian::read_u32(&vm.heap()[vm.registers[16] as usize - HEADER_SIZE..]), 16);
+    }
+
+    #[test]
+    fn test_stack_slots_are_roots() {
+        // The only pointer to the object is pushed on the stack, and its contents survive a collection
+        let vm = run(".data\n.code\nload $t0 #4\nload $t1 #0\nnew $t0 $t1 $t2\nload $t3 #77\nstw $t3 $t2\n\
+                      load $t4 #4\nsub $sp $t4 $sp\nstw $t2 $sp\nload $t2 #0\ngc\n\
+                      ldw $t2 $sp\nldw $s0 $t2\nload $t4 #4\nadd $sp $t4 $sp\ngc\nhlt", 4096);
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.registers[16], 77);
+        assert_eq!(vm.gc.stats.objects_freed, 0);
+        assert_eq!(vm.gc.stats.live_objects, 1);
+    }
+
+    #[test]
+    fn test_linked_list_survives_stress() {
+        // Builds a list of 200 nodes, each holding a counter and a pointer to the next node, while
+        // throwing away a 64 byte object per node. The heap only has room for the list and a little
+        // more, so collections have to run, and afterwards the list is walked to sum its values.
+        let vm = run(".data\n.code\nload $s0 #0\nload $s1 #200\nload $s2 #0\n\
+                      load $t0 #8\nload $t1 #1\nload $t3 #64\nload $t4 #0\nload $t5 #4\n\
+                      build: new $t0 $t1 $t2\nstw $s1 $t2\nadd $t2 $t5 $t6\nstw $s0 $t6\nmov $s0 $t2\n\
+                      new $t3 $t4 $t7\ndec $s1\nneq $s1 $s2\ndjmpe @build\n\
+                      load $s3 #0\nwalk: eq $s0 $s2\ndjmpe @done\nldw $t7 $s0\nadd $s3 $t7 $s3\n\
+                      add $s0 $t5 $t6\nldw $s0 $t6\nload $t8 @walk\njmp $t8\ndone: hlt", 6000);
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.registers[19], 200 * 201 / 2);
+        assert!(vm.gc.stats.collections > 0);
+        assert!(vm.gc.stats.objects_freed >= 100);
+        assert!(vm.gc.stats.to_string().contains("collections freed"));
+    }
+
+    #[test]
+    fn test_out_of_memory_after_collection() {
+        // Every object stays reachable through the one before it, so nothing can be freed
+        let vm = run(".data\n.code\nload $t0 #60\nload $t1 #1\nload $s0 #0\n\
+                      grow: new $t0 $t1 $t2\nstw $s0 $t2\nmov $s0 $t2\nload $t3 @grow\njmp $t3", 1024);
+        assert!(matches!(vm.error(), Some(crate::vm::VmError::OutOfMemory{ size: 60, .. })));
+        assert_eq!(vm.gc.stats.objects_freed, 0);
+        assert!(vm.gc.stats.live_objects > 10);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::files::{self, FileTable};
+use super::memory::{Access, MemoryMap, Segment};
+use super::sandbox::{Capability, SandboxPolicy};
+use super::VmIo;
+
+/// What a host function can see of the VM while it runs. Arguments are passed in `$a0` to `$a3`
+/// (registers 0 to 3) and results are returned in `$a0`, the same as for assembly routines.
+pub struct HostContext<'a> {
+    pub registers: &'a mut [i32; 32],
+    pub heap: &'a mut [u8],
+    pub ro_data: &'a [u8],
+    /// Where the heap and read only data are mapped, which buffers passed by address are checked against
+    pub memory_map: MemoryMap,
+    pub equal_flag: &'a mut bool,
+    pub io: &'a mut VmIo,
+    pub files: &'a mut FileTable,
+    /// What the program is allowed to do. Host functions that reach outside the VM check it first.
+    pub policy: &'a SandboxPolicy
+}
+
+impl<'a> HostContext<'a> {
+    /// Fails with a permission error unless the sandbox policy allows the capability
+    pub fn require(&self, capability: Capability) -> Result<(), HostError> {
+        self.policy.check(capability).map_err(HostError::PermissionDenied)
+    }
+
+    /// The part of the heap a buffer of `length` bytes at `address` covers. Every byte of it has to be
+    /// mapped in the heap and allow the access.
+    pub fn heap_range(&self, address: i32, length: usize, access: Access) -> Result<Range<usize>, String> {
+        let outside = || format!("the {} byte buffer at {} is not in the heap", length, address);
+        if address < 0 {
+            return Err(outside());
+        }
+        let end = (address as u32).checked_add(length as u32).filter(|_| length <= u32::MAX as usize).ok_or_else(outside)?;
+        if length == 0 {
+            return if end as usize <= self.heap.len() { Ok(0..0) } else { Err(outside()) };
+        }
+        match (self.memory_map.translate(address as u32, access), self.memory_map.translate(end - 1, access)) {
+            (Ok((Segment::Heap, first)), Ok((Segment::Heap, last))) => Ok(first..last + 1),
+            _ => Err(outside())
+        }
+    }
+}
+
+/// Why a host function failed. Either way the program stops.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostError {
+    Failed(String),
+    PermissionDenied(Capability)
+}
+
+impl From<String> for HostError {
+    fn from(error: String) -> HostError {
+        HostError::Failed(error)
+    }
+}
+
+impl<'a> From<&'a str> for HostError {
+    fn from(error: &'a str) -> HostError {
+        HostError::Failed(error.to_string())
+    }
+}
+
+/// A function written in Rust that programs call with `syscall #n`. An error stops the program.
+pub trait HostFunction {
+    fn call(&mut self, context: &mut HostContext) -> Result<(), HostError>;
+}
+
+impl<F> HostFunction for F where F: FnMut(&mut HostContext) -> Result<(), HostError> {
+    fn call(&mut self, context: &mut HostContext) -> Result<(), HostError> {
+        self(context)
+    }
+}
+
+struct Entry {
+    name: String,
+    /// Built-in functions check the capabilities they use themselves, rather than needing to be
+    /// allowed by name
+    builtin: bool,
+    function: Box<dyn HostFunction>
+}
+
+/// Host functions registered on a VM, by syscall number. Every VM starts out with the console
+/// functions `print_int`, `print_hex`, `print_char`, `read_int` and `read_line` as syscalls 0 to 4,
+/// which work on `$a0` (and `$a1` for the buffer size of `read_line`) like the opcodes of the same
+/// name work on their register operands. Syscall 5 is `clock`, which returns the seconds since the
+/// Unix epoch, and 6 is `random`, which returns a random number. Syscalls 7 to 11 are the file
+/// functions `open`, `read`, `write`, `close` and `seek`, described in the `files` module.
+pub struct HostFunctions {
+    functions: BTreeMap<u16, Entry>
+}
+
+impl HostFunctions {
+    /// A registry without any functions, not even the built-in ones
+    pub fn new() -> HostFunctions {
+        HostFunctions {
+            functions: BTreeMap::new()
+        }
+    }
+
+    /// Registers a function under a syscall number, replacing whatever was registered there
+    pub fn register<F: HostFunction + 'static>(&mut self, number: u16, name: &str, function: F) {
+        self.functions.insert(number, Entry { name: name.to_string(), builtin: false, function: Box::new(function) });
+    }
+
+    fn register_builtin<F: HostFunction + 'static>(&mut self, number: u16, name: &str, function: F) {
+        self.functions.insert(number, Entry { name: name.to_string(), builtin: true, function: Box::new(function) });
+    }
+
+    /// Registers a function under the first syscall number after every one in use, and returns it.
+    /// Fails if a function is already registered as syscall 65535, since there is no number after it.
+    pub fn register_named<F: HostFunction + 'static>(&mut self, name: &str, function: F) -> Result<u16, String> {
+        let number = match self.functions.keys().next_back() {
+            Some(last) => last.checked_add(1).ok_or_else(|| format!("there is no syscall number left for {}", name))?,
+            None => 0
+        };
+        self.register(number, name, function);
+        Ok(number)
+    }
+
+    pub fn remove(&mut self, number: u16) -> bool {
+        self.functions.remove(&number).is_some()
+    }
+
+    /// Syscall number of the function registered under a name
+    pub fn number_of(&self, name: &str) -> Option<u16> {
+        self.functions.iter().find(|(_, entry)| entry.name == name).map(|(number, _)| *number)
+    }
+
+    pub fn name_of(&self, number: u16) -> Option<&str> {
+        self.functions.get(&number).map(|entry| entry.name.as_str())
+    }
+
+    /// Runs the function registered under `number` if the sandbox policy allows it, or returns None if
+    /// there is none
+    pub fn call(&mut self, number: u16, context: &mut HostContext) -> Option<Result<(), HostError>> {
+        self.functions.get_mut(&number).map(|entry| {
+            if !entry.builtin {
+                context.require(Capability::HostFunction(entry.name.clone()))?;
+            }
+            entry.function.call(context)
+        })
+    }
+}
+
+impl Default for HostFunctions {
+    fn default() -> HostFunctions {
+        let mut functions = HostFunctions::new();
+        functions.register_builtin(0, "print_int", |c: &mut HostContext| print_int(c, 0));
+        functions.register_builtin(1, "print_hex", |c: &mut HostContext| print_hex(c, 0));
+        functions.register_builtin(2, "print_char", |c: &mut HostContext| print_char(c, 0));
+        functions.register_builtin(3, "read_int", |c: &mut HostContext| read_int(c, 0));
+        functions.register_builtin(4, "read_line", |c: &mut HostContext| read_line(c, 0, 1));
+        functions.register_builtin(5, "clock", clock);
+        functions.register_builtin(6, "random", Random::new());
+        functions.register_builtin(7, "open", files::open);
+        functions.register_builtin(8, "read", files::read);
+        functions.register_builtin(9, "write", files::write);
+        functions.register_builtin(10, "close", files::close);
+        functions.register_builtin(11, "seek", files::seek);
+        functions
+    }
+}
+
+pub fn print_int(context: &mut HostContext, register: usize) -> Result<(), HostError> {
+    context.require(Capability::Console)?;
+    let text = context.registers[register].to_string();
+    context.io.print(&text);
+    Ok(())
+}
+
+pub fn print_hex(context: &mut HostContext, register: usize) -> Result<(), HostError> {
+    context.require(Capability::Console)?;
+    let text = format!("{:x}", context.registers[register]);
+    context.io.print(&text);
+    Ok(())
+}
+
+pub fn print_char(context: &mut HostContext, register: usize) -> Result<(), HostError> {
+    context.require(Capability::Console)?;
+    let character = std::char::from_u32(context.registers[register] as u32).unwrap_or(std::char::REPLACEMENT_CHARACTER);
+    context.io.print(&character.to_string());
+    Ok(())
+}
+
+/// Reads a line holding a decimal number into a register and sets the equal flag, or clears the flag
+/// and leaves the register alone when the line is not a number or input has ended
+pub fn read_int(context: &mut HostContext, register: usize) -> Result<(), HostError> {
+    context.require(Capability::Console)?;
+    match context.io.read_line().and_then(|line| line.trim().parse::<i32>().ok()) {
+        Some(value) => {
+            context.registers[register] = value;
+            *context.equal_flag = true;
+        },
+        None => *context.equal_flag = false
+    }
+    Ok(())
+}
+
+/// Reads a line into the heap buffer at the address in `address`, whose size is in `size`. The line is
+/// cut short to fit along with its NUL, `size` is set to the number of bytes stored before the NUL,
+/// and the equal flag is cleared once input has ended.
+pub fn read_line(context: &mut HostContext, address: usize, size: usize) -> Result<(), HostError> {
+    context.require(Capability::Console)?;
+    match context.io.read_line() {
+        Some(line) => {
+            let capacity = context.registers[size].max(0) as usize;
+            if capacity > 0 {
+                let length = line.len().min(capacity - 1);
+                let range = context.heap_range(context.registers[address], length + 1, Access::Write)?;
+                context.heap[range.start..range.end - 1].copy_from_slice(&line.as_bytes()[..length]);
+                context.heap[range.end - 1] = 0;
+                context.registers[size] = length as i32;
+            }
+            *context.equal_flag = true;
+        },
+        None => {
+            context.registers[size] = 0;
+            *context.equal_flag = false;
+        }
+    }
+    Ok(())
+}
+
+fn clock(context: &mut HostContext) -> Result<(), HostError> {
+    context.require(Capability::Clock)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+    context.registers[0] = now.as_secs() as i32;
+    Ok(())
+}
+
+/// A xorshift generator. It is not meant for cryptography, only for games and simulations.
+struct Random {
+    state: u32
+}
+
+impl Random {
+    fn new() -> Random {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        // Zero would stay zero forever
+        Random { state: nanos | 1 }
+    }
+}
+
+impl HostFunction for Random {
+    fn call(&mut self, context: &mut HostContext) -> Result<(), HostError> {
+        context.require(Capability::Random)?;
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        context.registers[0] = self.state as i32;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_host_functions() {
+        let mut functions = HostFunctions::default();
+        assert_eq!(functions.number_of("read_line"), Some(4));
+        let number = functions.register_named("double", |c: &mut HostContext| {
+            c.registers[0] *= 2;
+            Ok(())
+        });
+        assert_eq!(number, Ok(12));
+        assert_eq!(functions.name_of(12), Some("double"));
+
+        let mut registers = [0; 32];
+        registers[0] = 21;
+        let mut context = HostContext {
+            registers: &mut registers,
+            heap: &mut [],
+            ro_data: &[],
+            memory_map: MemoryMap::new(0, 0, 0, 0, 0),
+            equal_flag: &mut false,
+            io: &mut VmIo::buffered(b""),
+            files: &mut FileTable::default(),
+            policy: &SandboxPolicy::allow_all()
+        };
+        assert_eq!(functions.call(12, &mut context), Some(Ok(())));
+        assert_eq!(context.registers[0], 42);
+        assert!(functions.call(13, &mut context).is_none());
+        assert!(functions.remove(12));
+        assert!(functions.call(12, &mut context).is_none());
+
+        // Nothing comes after the last syscall number
+        functions.register(u16::MAX, "last", |_: &mut HostContext| Ok(()));
+        assert!(functions.register_named("after", |_: &mut HostContext| Ok(())).is_err());
+        assert_eq!(functions.number_of("after"), None);
+    }
+
+    #[test]
+    fn test_policy_is_checked() {
+        let mut functions = HostFunctions::default();
+        functions.register(10, "double", |c: &mut HostContext| {
+            c.registers[0] *= 2;
+            Ok(())
+        });
+        let policy = SandboxPolicy::parse("clock").unwrap();
+        let mut registers = [0; 32];
+        let mut context = HostContext {
+            registers: &mut registers,
+            heap: &mut [],
+            ro_data: &[],
+            memory_map: MemoryMap::new(0, 0, 0, 0, 0),
+            equal_flag: &mut false,
+            io: &mut VmIo::buffered(b""),
+            files: &mut FileTable::default(),
+            policy: &policy
+        };
+        assert_eq!(functions.call(10, &mut context), Some(Err(HostError::PermissionDenied(Capability::HostFunction("double".to_string())))));
+        assert_eq!(functions.call(0, &mut context), Some(Err(HostError::PermissionDenied(Capability::Console))));
+        assert_eq!(functions.call(6, &mut context), Some(Err(HostError::PermissionDenied(Capability::Random))));
+        assert_eq!(functions.call(5, &mut context), Some(Ok(())));
+        assert!(context.registers[0] > 1_500_000_000);
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// Where a VM's programs write their output and read their input. By default these are the
+/// process's own streams.
+pub struct VmIo {
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+    pub stdin: Box<dyn Read>,
+    /// The buffers `stdout` and `stderr` write into, when they were set up by `buffered`
+    captured: Option<(SharedBuffer, SharedBuffer)>
+}
+
+impl VmIo {
+    pub fn new(stdout: Box<dyn Write>, stderr: Box<dyn Write>, stdin: Box<dyn Read>) -> VmIo {
+        VmIo {
+            stdout,
+            stderr,
+            stdin,
+            captured: None
+        }
+    }
+
+    /// Keeps output in memory, where `stdout_buffer` and `stderr_buffer` can read it, and reads
+    /// input from `stdin`
+    pub fn buffered(stdin: &[u8]) -> VmIo {
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        VmIo {
+            stdout: Box::new(stdout.clone()),
+            stderr: Box::new(stderr.clone()),
+            stdin: Box::new(io::Cursor::new(stdin.to_vec())),
+            captured: Some((stdout, stderr))
+        }
+    }
+
+    /// Everything written to stdout so far, if it is kept in memory
+    pub fn stdout_buffer(&self) -> Option<Vec<u8>> {
+        self.captured.as_ref().map(|(stdout, _)| stdout.contents())
+    }
+
+    /// Everything written to stderr so far, if it is kept in memory
+    pub fn stderr_buffer(&self) -> Option<Vec<u8>> {
+        self.captured.as_ref().map(|(_, stderr)| stderr.contents())
+    }
+
+    /// Writes a UTF-8 string the program gave to `opcode` to stdout. Problems with the string are
+    /// reported on stderr, as they are the program's fault rather than the VM's.
+    pub fn print_string(&mut self, bytes: &[u8], opcode: &str) {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => self.print(s),
+            Err(e) => {
+                if let Err(e) = writeln!(self.stderr, "Error decoding string for {} instruction: {}", opcode, e) {
+                    error!("Unable to write program output: {}", e);
+                }
+            }
+        }
+    }
+
+    pub fn print(&mut self, text: &str) {
+        if let Err(e) = self.stdout.write_all(text.as_bytes()) {
+            error!("Unable to write program output: {}", e);
+        }
+    }
+
+    /// Reads the next line of input without its line ending, or returns None once input has ended
+    pub fn read_line(&mut self) -> Option<String> {
+        // Output asking for input has to be visible before waiting for it
+        self.flush();
+        let mut line = vec![];
+        let mut byte = [0];
+        loop {
+            match self.stdin.read(&mut byte) {
+                Ok(0) if line.is_empty() => return None,
+                Ok(0) => break,
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) => line.push(byte[0]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    error!("Unable to read program input: {}", e);
+                    return None;
+                }
+            }
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.stdout.flush().and_then(|_| self.stderr.flush()) {
+            error!("Unable to write program output: {}", e);
+        }
+    }
+}
+
+impl Default for VmIo {
+    fn default() -> VmIo {
+        VmIo::new(Box::new(io::stdout()), Box::new(io::stderr()), Box::new(io::stdin()))
+    }
+}
+
+/// A byte buffer that can be written through one handle and read through another
+#[derive(Debug, Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_print_string() {
+        let mut io = VmIo::buffered(b"");
+        io.print_string(b"Hello", "prts");
+        io.print_string(b"\xff", "prth");
+        assert_eq!(io.stdout_buffer().unwrap(), b"Hello".to_vec());
+        assert!(String::from_utf8(io.stderr_buffer().unwrap()).unwrap().starts_with("Error decoding string for prth instruction"));
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut io = VmIo::buffered(b"first\r\n\nlast");
+        assert_eq!(io.read_line(), Some("first".to_string()));
+        assert_eq!(io.read_line(), Some("".to_string()));
+        assert_eq!(io.read_line(), Some("last".to_string()));
+        assert_eq!(io.read_line(), None);
+    }
+
+    #[test]
+    fn test_process_streams_are_not_captured() {
+        let io = VmIo::default();
+        assert_eq!(io.stdout_buffer(), None);
+        assert_eq!(io.stderr_buffer(), None);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+
+/// Where each region starts in the address space `ldb`, `stb`, `prth` and the file syscalls use. The
+/// heap starts at 0, so heap addresses are simply offsets into the heap.
+pub const HEAP_BASE: u32 = 0;
+/// The heap cannot grow past this, which keeps it clear of the other regions
+pub const HEAP_LIMIT: u32 = 0x1000_0000;
+pub const RO_BASE: u32 = 0x2000_0000;
+/// Code is mapped so that `CODE_BASE + pc` is the byte at `pc`
+pub const CODE_BASE: u32 = 0x3000_0000;
+/// The stack grows down from here, and `$sp` starts out pointing here
+pub const STACK_TOP: u32 = 0x4000_0000;
+pub const STACK_SIZE: usize = 64 * 1024;
+/// Size of the unmapped pages just below and above the stack, which catch it overflowing or underflowing
+pub const GUARD_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => f.write_str("read"),
+            Access::Write => f.write_str("write"),
+            Access::Execute => f.write_str("execute")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions { read: false, write: false, execute: false };
+    pub const READ_ONLY: Permissions = Permissions { read: true, write: false, execute: false };
+    pub const READ_WRITE: Permissions = Permissions { read: true, write: true, execute: false };
+    /// Code can be run and read but never written, so a program cannot change what it runs
+    pub const READ_EXECUTE: Permissions = Permissions { read: true, write: false, execute: true };
+
+    pub fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute
+        }
+    }
+}
+
+/// Which of the VM's buffers a region is backed by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Heap,
+    ReadOnly,
+    Code,
+    Stack,
+    /// Nothing backs a guard page, every access to one faults
+    Guard
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub name: &'static str,
+    pub segment: Segment,
+    pub start: u32,
+    /// One past the last address in the region
+    pub end: u32,
+    pub permissions: Permissions,
+    /// Offset into the backing buffer of the region's first byte
+    pub offset: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultReason {
+    /// Nothing is mapped at the address
+    Unmapped,
+    /// The address is in a guard page
+    Guard,
+    /// The region at the address does not allow the access
+    Permission
+}
+
+/// An access the memory map did not allow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    pub address: u32,
+    pub access: Access,
+    pub reason: FaultReason
+}
+
+/// The regions of a VM's address space, worked out from the sizes of its buffers
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap {
+    pub regions: [Region; 6]
+}
+
+impl MemoryMap {
+    /// `code_start` is where the code begins in the program, after any header and sections
+    pub fn new(heap_length: usize, ro_length: usize, code_start: usize, program_length: usize, stack_length: usize) -> MemoryMap {
+        let stack_bottom = STACK_TOP - stack_length as u32;
+        MemoryMap {
+            regions: [
+                Region { name: "heap", segment: Segment::Heap, start: HEAP_BASE, end: HEAP_BASE + heap_length as u32, permissions: Permissions::READ_WRITE, offset: 0 },
+                Region { name: "ro_data", segment: Segment::ReadOnly, start: RO_BASE, end: RO_BASE + ro_length as u32, permissions: Permissions::READ_ONLY, offset: 0 },
+                Region {
+                    name: "code",
+                    segment: Segment::Code,
+                    start: CODE_BASE + code_start as u32,
+                    end: CODE_BASE + program_length.max(code_start) as u32,
+                    permissions: Permissions::READ_EXECUTE,
+                    offset: code_start
+                },
+                Region { name: "stack guard", segment: Segment::Guard, start: stack_bottom - GUARD_SIZE, end: stack_bottom, permissions: Permissions::NONE, offset: 0 },
+                Region { name: "stack", segment: Segment::Stack, start: stack_bottom, end: STACK_TOP, permissions: Permissions::READ_WRITE, offset: 0 },
+                Region { name: "stack guard", segment: Segment::Guard, start: STACK_TOP, end: STACK_TOP + GUARD_SIZE, permissions: Permissions::NONE, offset: 0 }
+            ]
+        }
+    }
+
+    /// Finds the buffer and offset an access goes to, or the fault it causes
+    pub fn translate(&self, address: u32, access: Access) -> Result<(Segment, usize), Fault> {
+        let fault = |reason| Fault { address, access, reason };
+        let region = self.regions.iter()
+            .find(|region| region.start <= address && address < region.end)
+            .ok_or_else(|| fault(FaultReason::Unmapped))?;
+        if region.segment == Segment::Guard {
+            return Err(fault(FaultReason::Guard));
+        }
+        if !region.permissions.allows(access) {
+            return Err(fault(FaultReason::Permission));
+        }
+        Ok((region.segment, region.offset + (address - region.start) as usize))
+    }
+
+    pub fn region_at(&self, address: u32) -> Option<&Region> {
+        self.regions.iter().find(|region| region.start <= address && address < region.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let map = MemoryMap::new(16, 4, 64, 72, STACK_SIZE);
+        assert_eq!(map.translate(3, Access::Write), Ok((Segment::Heap, 3)));
+        assert_eq!(map.translate(RO_BASE + 1, Access::Read), Ok((Segment::ReadOnly, 1)));
+        assert_eq!(map.translate(CODE_BASE + 68, Access::Read), Ok((Segment::Code, 68)));
+        assert_eq!(map.translate(STACK_TOP - 1, Access::Write), Ok((Segment::Stack, STACK_SIZE - 1)));
+
+        let fault = |address, access, reason| Err(Fault { address, access, reason });
+        assert_eq!(map.translate(16, Access::Read), fault(16, Access::Read, FaultReason::Unmapped));
+        assert_eq!(map.translate(RO_BASE, Access::Write), fault(RO_BASE, Access::Write, FaultReason::Permission));
+        assert_eq!(map.translate(CODE_BASE + 64, Access::Write), fault(CODE_BASE + 64, Access::Write, FaultReason::Permission));
+        assert_eq!(map.translate(CODE_BASE + 8, Access::Read), fault(CODE_BASE + 8, Access::Read, FaultReason::Unmapped));
+        assert_eq!(map.translate(STACK_TOP, Access::Write), fault(STACK_TOP, Access::Write, FaultReason::Guard));
+        let below = STACK_TOP - STACK_SIZE as u32 - 1;
+        assert_eq!(map.translate(below, Access::Read), fault(below, Access::Read, FaultReason::Guard));
+        assert_eq!(map.region_at(RO_BASE + 2).unwrap().name, "ro_data");
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::assembler::debug_info::DebugInfo;
+use crate::instruction::Opcode;
+
+use super::VM;
+
+/// Number of rows of each table the report shows
+const REPORT_ROWS: usize = 20;
+
+/// Counts what a VM runs: how often each opcode and each instruction ran, how many instructions ran
+/// in all, and how long that took. It also keeps the calls that were active at each step, for flame
+/// graphs. Instructions are told apart by module and `pc`, as shared modules have their own code.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    /// Maps the main program back to its source, so the report can show lines and labels
+    pub debug_info: Option<DebugInfo>,
+    pub steps: u64,
+    /// Runs of each opcode, indexed by opcode number
+    opcodes: Vec<u64>,
+    /// Runs of each instruction, by module and `pc`
+    instructions: HashMap<(usize, usize), u64>,
+    /// Module and entry point of the program and every routine it is in the middle of
+    calls: Vec<(usize, usize)>,
+    /// Steps taken with each list of calls active, and the steps taken since `calls` last changed
+    stacks: HashMap<Vec<(usize, usize)>, u64>,
+    pending: u64,
+    started: Option<Instant>,
+    elapsed: Duration
+}
+
+impl Profiler {
+    pub fn new(debug_info: Option<DebugInfo>) -> Profiler {
+        Profiler { debug_info, opcodes: vec![0; 256], ..Profiler::default() }
+    }
+
+    /// Time spent running, from the first instruction profiled to the last
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// How many times the instruction at `pc` in a module ran
+    pub fn count(&self, module: usize, pc: usize) -> u64 {
+        self.instructions.get(&(module, pc)).cloned().unwrap_or(0)
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(opcode.to_u8() as usize).cloned().unwrap_or(0)
+    }
+
+    /// The report printed when a profiled program stops: opcodes and instructions by how often they
+    /// ran, then source lines and labels when there is debug information. `modules` names the modules
+    /// by index.
+    pub fn report(&self, modules: &[&str]) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "Profile: {} steps in {:?}", self.steps, self.elapsed);
+
+        let mut opcodes: Vec<(Opcode, u64)> = self.opcodes.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(code, count)| (Opcode::from(code as u8), *count))
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_u8().cmp(&b.0.to_u8())));
+        let _ = writeln!(report, "\nOpcodes:");
+        for (opcode, count) in opcodes.iter().take(REPORT_ROWS) {
+            let _ = writeln!(report, "  {:<8}{:>10}  {}", opcode.mnemonic(), count, self.percent(*count));
+        }
+
+        let mut instructions: Vec<(&(usize, usize), &u64)> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(report, "\nHot instructions:");
+        for ((module, pc), count) in instructions.iter().take(REPORT_ROWS) {
+            let mut line = format!("  {:<16}{:>10}  {}", self.location(modules, *module, *pc), count, self.percent(**count));
+            if let Some((number, text)) = self.source(*module, *pc) {
+                let _ = write!(line, "  line {}: {}", number, text);
+            }
+            let _ = writeln!(report, "{}", line);
+        }
+
+        if let Some(debug) = &self.debug_info {
+            let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+            let mut labels: BTreeMap<&str, u64> = BTreeMap::new();
+            for ((module, pc), count) in &self.instructions {
+                if *module != 0 {
+                    continue;
+                }
+                if let Some(line) = debug.line_at(*pc) {
+                    *lines.entry(line).or_insert(0) += count;
+                }
+                if let Some((label, _)) = debug.label_at(*pc) {
+                    *labels.entry(label).or_insert(0) += count;
+                }
+            }
+            let mut lines: Vec<(u32, u64)> = lines.into_iter().collect();
+            lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            let _ = writeln!(report, "\nSource lines:");
+            for (line, count) in lines.iter().take(REPORT_ROWS) {
+                let text = debug.source_line(*line).unwrap_or_default();
+                let _ = writeln!(report, "  {:<6}{:>10}  {}  {}", line, count, self.percent(*count), text);
+            }
+            let mut labels: Vec<(&str, u64)> = labels.into_iter().collect();
+            labels.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            let _ = writeln!(report, "\nLabels:");
+            for (label, count) in labels.iter().take(REPORT_ROWS) {
+                let _ = writeln!(report, "  {:<16}{:>10}  {}", label, count, self.percent(*count));
+            }
+        }
+        report
+    }
+
+    /// The steps taken in each list of active calls, in the folded stack format flame graph tools read:
+    /// one line per list, with the calls outermost first and separated by `;`, then a space and the
+    /// number of steps
+    pub fn folded_stacks(&self, modules: &[&str]) -> String {
+        let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+        let current = Some((&self.calls, &self.pending)).filter(|(_, pending)| **pending > 0);
+        for (calls, count) in self.stacks.iter().chain(current) {
+            let names: Vec<String> = calls.iter().map(|(module, pc)| self.location(modules, *module, *pc)).collect();
+            *folded.entry(names.join(";")).or_insert(0) += count;
+        }
+        folded.iter().map(|(stack, count)| format!("{} {}\n", stack, count)).collect()
+    }
+
+    fn percent(&self, count: u64) -> String {
+        format!("{:>5.1}%", count as f64 * 100.0 / self.steps.max(1) as f64)
+    }
+
+    /// A label and offset in the main program when there is debug information for it, and otherwise
+    /// the module and `pc`
+    fn location(&self, modules: &[&str], module: usize, pc: usize) -> String {
+        let label = self.debug_info.as_ref().filter(|_| module == 0).and_then(|debug| debug.location(pc));
+        label.unwrap_or_else(|| format!("{}@{}", modules.get(module).cloned().unwrap_or("?"), pc))
+    }
+
+    fn source(&self, module: usize, pc: usize) -> Option<(u32, &str)> {
+        let debug = self.debug_info.as_ref().filter(|_| module == 0)?;
+        let line = debug.line_at(pc)?;
+        Some((line, debug.source_line(line)?))
+    }
+
+    /// Counts an instruction, given what was running before it and after it
+    fn record(&mut self, opcode: Opcode, before: (usize, usize, usize), after: (usize, usize, usize)) {
+        let (module, pc, depth) = before;
+        if self.calls.is_empty() {
+            self.calls.push((module, pc));
+        }
+        self.steps += 1;
+        self.opcodes[opcode.to_u8() as usize] += 1;
+        *self.instructions.entry((module, pc)).or_insert(0) += 1;
+        self.pending += 1;
+
+        let (module, pc, new_depth) = after;
+        if new_depth != depth {
+            *self.stacks.entry(self.calls.clone()).or_insert(0) += self.pending;
+            self.pending = 0;
+            if new_depth > depth {
+                self.calls.push((module, pc));
+            } else if self.calls.len() > 1 {
+                self.calls.pop();
+            }
+        }
+    }
+}
+
+impl VM {
+    /// Runs the instruction at `pc` and counts it in the profiler
+    pub(super) fn profile_instruction(&mut self) -> bool {
+        let mut profiler = match self.profiler.take() {
+            Some(profiler) => profiler,
+            None => return self.run_instruction()
+        };
+        let started = *profiler.started.get_or_insert_with(Instant::now);
+        let opcode = self.program.get(self.pc).map_or(Opcode::IGL, |code| Opcode::from(*code));
+        let before = (self.current_module, self.pc, self.call_stack.len());
+        let is_done = self.run_instruction();
+        profiler.record(opcode, before, (self.current_module, self.pc, self.call_stack.len()));
+        profiler.elapsed = started.elapsed();
+        self.profiler = Some(profiler);
+        is_done
+    }
+
+    /// The profiler's report, if the VM has a profiler
+    pub fn profile_report(&self) -> Option<String> {
+        Some(self.profiler.as_ref()?.report(&self.module_names()))
+    }
+
+    /// The profiler's folded stacks, if the VM has a profiler
+    pub fn folded_stacks(&self) -> Option<String> {
+        Some(self.profiler.as_ref()?.folded_stacks(&self.module_names()))
+    }
+
+    fn module_names(&self) -> Vec<&str> {
+        self.modules.iter().map(|module| module.name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const PROGRAM: &str = ".data\n.code\nload $t0 #3\nload $t1 @double\nloop: call @double\ndec $t0\n\
+                           load $t2 #0\nneq $t0 $t2\ndjmpe @loop\nhlt\n\
+                           double: add $t3 $t3 $t3\nret";
+
+    fn profile(debug: bool) -> VM {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(PROGRAM).unwrap();
+        let debug_info = if debug { Some(asm.debug_info(PROGRAM, &program)) } else { None };
+        let mut vm = VM { program, ..VM::default() };
+        vm.profiler = Some(Profiler::new(debug_info));
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_counts() {
+        let vm = profile(false);
+        assert_eq!(vm.error(), None);
+        let profiler = vm.profiler.as_ref().unwrap();
+        assert_eq!(profiler.steps, 2 + 3 * 7 + 1);
+        assert_eq!(profiler.opcode_count(Opcode::CALL), 3);
+        assert_eq!(profiler.opcode_count(Opcode::LOAD), 5);
+        assert_eq!(profiler.count(0, 72), 3);
+        let report = vm.profile_report().unwrap();
+        assert!(report.starts_with("Profile: 24 steps in"));
+        assert!(report.contains("<main>@72"));
+        assert!(!report.contains("Source lines:"));
+    }
+
+    #[test]
+    fn test_source_lines_and_labels() {
+        let vm = profile(true);
+        let report = vm.profile_report().unwrap();
+        assert!(report.contains("  loop                     3   12.5%  line 5: loop: call @double"));
+        assert!(report.contains("Source lines:\n  5              3   12.5%  loop: call @double"));
+        assert!(report.contains("\nLabels:\n  loop                    16   66.7%\n  double                   6   25.0%"));
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let vm = profile(true);
+        let folded = vm.folded_stacks().unwrap();
+        // The program starts before any label, so the outermost frame is named by its address
+        assert_eq!(folded, "<main>@64 18\n<main>@64;double 6\n");
+    }
+}
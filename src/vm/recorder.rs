@@ -0,0 +1,260 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::instruction::{Instruction, Opcode, Operand};
+
+use super::memory::{HEAP_BASE, STACK_TOP};
+use super::VM;
+
+/// Opcodes that can change the heap or the stack. Only these have the memory before and after them
+/// compared, so recording everything else does not copy the heap and stack on every step.
+const MEMORY_WRITERS: [Opcode; 8] = [
+    Opcode::STB, Opcode::STW, Opcode::ALOC, Opcode::FREE, Opcode::NEW, Opcode::GC, Opcode::RDL, Opcode::SYSCALL
+];
+
+/// Which instructions a `TraceRecorder` writes out. Instructions it leaves out still run and still
+/// count towards the step numbers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    /// First and last `pc` to record, inclusive
+    pub pc_range: Option<(usize, usize)>,
+    /// Opcodes to record. Empty records every opcode.
+    pub opcodes: Vec<Opcode>
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: usize, opcode: Opcode) -> bool {
+        let in_range = self.pc_range.is_none_or(|(first, last)| first <= pc && pc <= last);
+        in_range && (self.opcodes.is_empty() || self.opcodes.contains(&opcode))
+    }
+
+    /// Reads a range written as `first-last`, such as `64-128`
+    pub fn parse_pc_range(range: &str) -> Result<(usize, usize), String> {
+        let mut ends = range.splitn(2, '-').map(|end| end.trim().parse::<usize>());
+        match (ends.next(), ends.next()) {
+            (Some(Ok(first)), Some(Ok(last))) if first <= last => Ok((first, last)),
+            _ => Err(format!("{} is not a range of program counters like 64-128", range))
+        }
+    }
+
+    /// Reads a comma separated list of mnemonics, such as `add,stw`
+    pub fn parse_opcodes(list: &str) -> Result<Vec<Opcode>, String> {
+        list.split(',')
+            .map(|mnemonic| Opcode::from_mnemonic(mnemonic.trim()).ok_or_else(|| format!("{} is not an opcode", mnemonic.trim())))
+            .collect()
+    }
+}
+
+/// Writes every instruction a VM runs as a line of JSON, in the JSON Lines format. Each line has the
+/// step number, counting from 1, the `pc` and opcode, the operands as the disassembler shows them, the
+/// registers that changed with their old and new values, the flags after the instruction, and the
+/// bytes of the heap and stack it changed. An instruction that stops the program with an error has the
+/// error as well:
+///
+/// ```text
+/// {"step":3,"pc":72,"opcode":"stw","operands":["$t1","$t0"],"registers":[],"flags":{"equal":false,"remainder":0},"writes":[{"address":16,"bytes":[7]}]}
+/// ```
+pub struct TraceRecorder {
+    output: Box<dyn Write>,
+    pub filter: TraceFilter,
+    /// Instructions run since recording started
+    steps: u64,
+    /// The heap and stack from before the last instruction recorded that can write memory, kept
+    /// between steps so they are not allocated every time
+    heap: Vec<u8>,
+    stack: Vec<u8>
+}
+
+impl TraceRecorder {
+    pub fn new(output: Box<dyn Write>, filter: TraceFilter) -> TraceRecorder {
+        TraceRecorder { output, filter, steps: 0, heap: vec![], stack: vec![] }
+    }
+
+    /// Records to a file, which is created or truncated
+    pub fn create<P: AsRef<Path>>(path: P, filter: TraceFilter) -> io::Result<TraceRecorder> {
+        Ok(TraceRecorder::new(Box::new(BufWriter::new(File::create(path)?)), filter))
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+impl VM {
+    /// Runs the instruction at `pc` and writes it to the recorder if the recorder's filter wants it.
+    /// If the trace cannot be written, recording stops and the program carries on.
+    pub(super) fn record_instruction(&mut self) -> bool {
+        let mut recorder = match self.recorder.take() {
+            Some(recorder) => recorder,
+            None => return self.run_instruction()
+        };
+        recorder.steps += 1;
+        let start = self.pc;
+        // A pc past the end of the program decodes as nothing, and the step below reports the fault
+        let instruction = self.program.get(start..)
+            .and_then(|bytes| Instruction::decode(bytes).ok())
+            .map(|(instruction, _)| instruction);
+        let opcode = instruction.as_ref().map_or(Opcode::IGL, |i| i.opcode());
+        if !recorder.filter.matches(start, opcode) {
+            let is_done = self.run_instruction();
+            self.recorder = Some(recorder);
+            return is_done;
+        }
+
+        let registers = self.registers;
+        let writes_memory = MEMORY_WRITERS.contains(&opcode);
+        if writes_memory {
+            recorder.heap.clone_from(&self.heap);
+            recorder.stack.clone_from(&self.stack);
+        }
+        let had_error = self.error.is_some();
+        // The recorder is taken out of the VM, so this only logs the instruction if the VM is tracing
+        let is_done = self.run_instruction();
+
+        let mut line = format!("{{\"step\":{},\"pc\":{},\"opcode\":\"{}\",\"operands\":[", recorder.steps, start, opcode.mnemonic());
+        let operands: Vec<String> = instruction.iter().flat_map(|i| i.operands()).map(|o| format!("\"{}\"", o)).collect();
+        line.push_str(&operands.join(","));
+        line.push_str("],\"registers\":[");
+        let changes: Vec<String> = registers.iter().zip(self.registers.iter()).enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(register, (old, new))| {
+                format!("{{\"register\":\"{}\",\"old\":{},\"new\":{}}}", Operand::Register(register as u8), old, new)
+            })
+            .collect();
+        line.push_str(&changes.join(","));
+        let _ = write!(line, "],\"flags\":{{\"equal\":{},\"remainder\":{}}},\"writes\":[", self.equal_flag, self.remainder);
+        let mut writes = vec![];
+        if writes_memory {
+            writes = changed_runs(&recorder.heap, &self.heap, HEAP_BASE);
+            writes.extend(changed_runs(&recorder.stack, &self.stack, STACK_TOP - self.stack.len() as u32));
+        }
+        let writes: Vec<String> = writes.iter().map(|(address, bytes)| {
+            let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+            format!("{{\"address\":{},\"bytes\":[{}]}}", address, bytes.join(","))
+        }).collect();
+        line.push_str(&writes.join(","));
+        line.push(']');
+        if let (false, Some(error)) = (had_error, &self.error) {
+            let _ = write!(line, ",\"error\":\"{}\"", escape(&error.to_string()));
+        }
+        line.push_str("}\n");
+
+        let written = recorder.output.write_all(line.as_bytes())
+            .and_then(|_| if is_done { recorder.output.flush() } else { Ok(()) });
+        match written {
+            Ok(_) => self.recorder = Some(recorder),
+            Err(e) => error!("Unable to write the trace, so recording has stopped: {}", e)
+        }
+        is_done
+    }
+}
+
+/// The runs of bytes that differ between two versions of a buffer mapped at `base`. Bytes the buffer
+/// grew by count as changed unless they are still zero.
+fn changed_runs(before: &[u8], after: &[u8], base: u32) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = vec![];
+    let mut previous = None;
+    for (offset, byte) in after.iter().enumerate() {
+        if before.get(offset).cloned().unwrap_or(0) == *byte {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((_, bytes)) if previous == Some(offset - 1) => bytes.push(*byte),
+            _ => runs.push((base + offset as u32, vec![*byte]))
+        }
+        previous = Some(offset);
+    }
+    runs
+}
+
+fn escape(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => { let _ = write!(escaped, "\\u{:04x}", c as u32); },
+            c => escaped.push(c)
+        }
+        escaped
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::assembler::Assembler;
+
+    /// Collects what the recorder writes so tests can read it back
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(source: &str, filter: TraceFilter) -> (VM, Vec<String>) {
+        let output = Shared::default();
+        let mut vm = VM { program: Assembler::new().assemble(source).unwrap(), ..VM::default() };
+        vm.recorder = Some(TraceRecorder::new(Box::new(output.clone()), filter));
+        vm.run();
+        let lines = String::from_utf8(output.0.borrow().clone()).unwrap().lines().map(String::from).collect();
+        (vm, lines)
+    }
+
+    #[test]
+    fn test_records_every_instruction() {
+        let (vm, lines) = record(".data\n.code\nload $t0 #4\naloc $t0 $t1\nload $t2 #258\nstw $t2 $t1\ndiv $t2 $t0 $t3\nhlt", TraceFilter::default());
+        assert_eq!(vm.recorder.as_ref().unwrap().steps(), 6);
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "{\"step\":1,\"pc\":64,\"opcode\":\"load\",\"operands\":[\"$t0\",\"#4\"],\
+                              \"registers\":[{\"register\":\"$t0\",\"old\":0,\"new\":4}],\
+                              \"flags\":{\"equal\":false,\"remainder\":0},\"writes\":[]}");
+        assert!(lines[3].contains("\"opcode\":\"stw\""));
+        assert!(lines[3].ends_with("\"writes\":[{\"address\":4,\"bytes\":[2,1]}]}"));
+        assert!(lines[4].contains("\"remainder\":2"));
+
+        // Only instructions that can write memory copy it
+        let (vm, _) = record(".data\n.code\nload $t0 #4\nadd $t0 $t0 $t1\nhlt", TraceFilter::default());
+        assert!(vm.recorder.as_ref().unwrap().stack.is_empty());
+    }
+
+    #[test]
+    fn test_filters_and_errors() {
+        let filter = TraceFilter { pc_range: Some((68, 80)), opcodes: TraceFilter::parse_opcodes("load,div").unwrap() };
+        let (_, lines) = record(".data\n.code\nload $t0 #4\nload $t1 #0\nadd $t0 $t0 $t2\ndiv $t0 $t1 $t3\nhlt", filter);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"step\":2,\"pc\":68,\"opcode\":\"load\""));
+        assert!(lines[1].starts_with("{\"step\":4,\"pc\":76,\"opcode\":\"div\""));
+        assert!(lines[1].ends_with("\"error\":\"Division by zero at 76\"}"));
+    }
+
+    #[test]
+    fn test_jump_past_the_program() {
+        let (vm, lines) = record(".data\n.code\nload $t0 #5000\njmp $t0", TraceFilter::default());
+        assert!(matches!(vm.error(), Some(crate::vm::VmError::MemoryFault{ pc: 5000, .. })));
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with("{\"step\":3,\"pc\":5000,\"opcode\":\"igl\",\"operands\":[]"));
+        assert!(lines[2].contains("\"error\":\"Memory fault"));
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(TraceFilter::parse_pc_range("64-128"), Ok((64, 128)));
+        assert!(TraceFilter::parse_pc_range("128-64").is_err());
+        assert!(TraceFilter::parse_pc_range("64").is_err());
+        assert_eq!(TraceFilter::parse_opcodes("add, hlt"), Ok(vec![Opcode::ADD, Opcode::HLT]));
+        assert!(TraceFilter::parse_opcodes("add,nope").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// Something a program needs permission for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capability {
+    /// Printing and reading through the VM's streams
+    Console,
+    /// Opening the file at a path
+    Filesystem(PathBuf),
+    /// Reading the current time
+    Clock,
+    /// Getting random numbers
+    Random,
+    /// Calling the host function registered under a name, other than the built-in ones
+    HostFunction(String)
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Capability::Console => f.write_str("console"),
+            Capability::Filesystem(path) => write!(f, "fs:{}", path.display()),
+            Capability::Clock => f.write_str("clock"),
+            Capability::Random => f.write_str("random"),
+            Capability::HostFunction(name) => write!(f, "host:{}", name)
+        }
+    }
+}
+
+/// The capabilities programs run by a VM are given. The default policy allows everything, so only
+/// hosts that run untrusted code need to set one up.
+///
+/// Policies can be written as a list of capabilities separated by commas or newlines, where `#`
+/// starts a comment: `console`, `clock`, `random`, `fs:<directory>` for the files under a directory,
+/// `fs:*` for every file, `host:<name>` for a host function, `host:*` for every host function, and
+/// `all`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SandboxPolicy {
+    pub console: bool,
+    /// Directories whose files can be opened, unless `all_files` is set
+    pub filesystem: Vec<PathBuf>,
+    pub all_files: bool,
+    pub clock: bool,
+    pub random: bool,
+    /// Host functions that can be called, unless `all_host_functions` is set
+    pub host_functions: Vec<String>,
+    pub all_host_functions: bool
+}
+
+impl SandboxPolicy {
+    /// Allows everything, including every file
+    pub fn allow_all() -> SandboxPolicy {
+        SandboxPolicy {
+            console: true,
+            filesystem: vec![],
+            all_files: true,
+            clock: true,
+            random: true,
+            host_functions: vec![],
+            all_host_functions: true
+        }
+    }
+
+    /// Allows nothing, so programs can only compute
+    pub fn deny_all() -> SandboxPolicy {
+        SandboxPolicy {
+            console: false,
+            filesystem: vec![],
+            all_files: false,
+            clock: false,
+            random: false,
+            host_functions: vec![],
+            all_host_functions: false
+        }
+    }
+
+    /// Builds a policy that allows only the capabilities listed
+    pub fn parse(spec: &str) -> Result<SandboxPolicy, String> {
+        let mut policy = SandboxPolicy::deny_all();
+        let entries = spec.lines()
+            .map(|line| line.split('#').next().unwrap_or(""))
+            .flat_map(|line| line.split(','))
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty());
+        for entry in entries {
+            match entry {
+                "all" => policy = SandboxPolicy::allow_all(),
+                "console" => policy.console = true,
+                "clock" => policy.clock = true,
+                "random" => policy.random = true,
+                "fs:*" => policy.all_files = true,
+                "host:*" => policy.all_host_functions = true,
+                _ if entry.starts_with("fs:") && entry.len() > 3 => policy.filesystem.push(PathBuf::from(&entry[3..])),
+                _ if entry.starts_with("host:") && entry.len() > 5 => policy.host_functions.push(entry[5..].to_string()),
+                _ => return Err(format!("unknown capability `{}`", entry))
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn allows(&self, capability: &Capability) -> bool {
+        match capability {
+            Capability::Console => self.console,
+            Capability::Filesystem(path) => self.allows_path(path),
+            Capability::Clock => self.clock,
+            Capability::Random => self.random,
+            Capability::HostFunction(name) => self.all_host_functions || self.host_functions.contains(name)
+        }
+    }
+
+    /// Fails with the capability that is missing
+    pub fn check(&self, capability: Capability) -> Result<(), Capability> {
+        if self.allows(&capability) {
+            Ok(())
+        } else {
+            Err(capability)
+        }
+    }
+
+    /// Paths are compared by their components, and paths that climb out of a directory with `..` are
+    /// never allowed, as they could get out of every allowed directory
+    fn allows_path(&self, path: &Path) -> bool {
+        if self.all_files {
+            return true;
+        }
+        if path.components().any(|c| c == Component::ParentDir) {
+            return false;
+        }
+        let path = normalize(path);
+        self.filesystem.iter().any(|directory| path.starts_with(normalize(directory)))
+    }
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> SandboxPolicy {
+        SandboxPolicy::allow_all()
+    }
+}
+
+/// Drops `.` components, so `./data/file` is under `data`
+fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|c| *c != Component::CurDir).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = SandboxPolicy::parse("console, clock # time is fine\nfs:./data\nhost:double").unwrap();
+        assert!(policy.allows(&Capability::Console));
+        assert!(policy.allows(&Capability::Clock));
+        assert!(!policy.allows(&Capability::Random));
+        assert!(policy.allows(&Capability::HostFunction("double".to_string())));
+        assert!(!policy.allows(&Capability::HostFunction("triple".to_string())));
+        assert_eq!(SandboxPolicy::parse("all").unwrap(), SandboxPolicy::allow_all());
+        assert_eq!(SandboxPolicy::parse("").unwrap(), SandboxPolicy::deny_all());
+        assert!(SandboxPolicy::parse("console,network").is_err());
+        assert!(SandboxPolicy::parse("fs:").is_err());
+    }
+
+    #[test]
+    fn test_filesystem_paths() {
+        let policy = SandboxPolicy::parse("fs:data").unwrap();
+        assert!(policy.allows(&Capability::Filesystem(PathBuf::from("data/scores.txt"))));
+        assert!(policy.allows(&Capability::Filesystem(PathBuf::from("./data/scores.txt"))));
+        assert!(!policy.allows(&Capability::Filesystem(PathBuf::from("database.txt"))));
+        assert!(!policy.allows(&Capability::Filesystem(PathBuf::from("data/../secret.txt"))));
+        assert_eq!(policy.check(Capability::Clock), Err(Capability::Clock));
+    }
+}
@@ -0,0 +1,430 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::assembler::object::Export;
+use crate::loader::LoadedModule;
+
+use super::allocator::Allocator;
+use super::gc::{GarbageCollector, GcStats};
+use super::memory::STACK_SIZE;
+use super::values::ValueType;
+use super::{Frame, VM};
+
+/// Snapshots start with these bytes
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"IRSN";
+/// Bumped whenever the layout of a snapshot changes. Only snapshots of this version can be restored.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion{ version: u16 },
+    ChecksumMismatch,
+    Truncated,
+    /// The snapshot is intact but describes a VM that cannot exist
+    Invalid{ reason: String }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => f.write_str("Not a VM snapshot"),
+            SnapshotError::UnsupportedVersion{ version } => {
+                f.write_str(&format!("Snapshot version {} is not supported, only version {} is", version, SNAPSHOT_VERSION))
+            },
+            SnapshotError::ChecksumMismatch => f.write_str("Snapshot checksum does not match, it is corrupted"),
+            SnapshotError::Truncated => f.write_str("Snapshot ends early"),
+            SnapshotError::Invalid{ reason } => f.write_str(&format!("Snapshot is invalid: {}", reason))
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl VM {
+    /// Saves everything a program's run depends on, so `restore` can carry it on later, possibly on
+    /// another machine. The layout is the magic bytes, a little endian u16 version, the state and a
+    /// CRC-32 of everything before it. How the VM is set up by its host, such as its streams, sandbox
+    /// policy and host functions, is not part of the snapshot, and neither are open files.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = Encoder(SNAPSHOT_MAGIC.to_vec());
+        out.u16(SNAPSHOT_VERSION);
+        for register in self.registers.iter() {
+            out.u32(*register as u32);
+        }
+        for tag in self.tags.iter() {
+            out.u8(*tag as u8);
+        }
+        out.bool(self.tagged);
+        out.usize(self.pc);
+        out.u32(self.remainder);
+        out.bool(self.equal_flag);
+        out.usize(self.code_start);
+        out.bytes(&self.program);
+        out.bytes(&self.ro_data);
+        out.bytes(&self.heap);
+        out.bytes(&self.stack);
+
+        out.u32(self.call_stack.len() as u32);
+        for frame in &self.call_stack {
+            out.usize(frame.module);
+            out.usize(frame.return_pc);
+        }
+        out.u32(self.modules.len() as u32);
+        for module in &self.modules {
+            out.bytes(module.name.as_bytes());
+            out.bytes(&module.program);
+            out.bytes(&module.ro_data);
+            out.u32(module.exports.len() as u32);
+            for export in &module.exports {
+                out.bytes(export.name.as_bytes());
+                out.bool(export.version.is_some());
+                out.u16(export.version.unwrap_or(0));
+                out.u32(export.address);
+            }
+            out.pairs(module.imports.iter().cloned());
+        }
+        out.usize(self.current_module);
+
+        out.usize(self.allocator.max_heap);
+        out.usize(self.allocator.top());
+        out.pairs(self.allocator.blocks());
+        out.pairs(self.allocator.free_blocks());
+        let stats = &self.gc.stats;
+        out.bool(self.gc.enabled);
+        for count in [stats.collections, stats.objects_freed, stats.bytes_freed, stats.live_objects, stats.live_bytes].iter() {
+            out.usize(*count);
+        }
+        out.u64(stats.pause.as_nanos() as u64);
+
+        let checksum = crc32(&out.0);
+        out.u32(checksum);
+        out.0
+    }
+
+    /// Puts the VM back in the state a snapshot was taken in. Nothing changes unless the whole snapshot
+    /// is valid. Open files are closed, and `resume` carries on running the program.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        if snapshot.len() < SNAPSHOT_MAGIC.len() || snapshot[..4] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if snapshot.len() < 10 {
+            return Err(SnapshotError::Truncated);
+        }
+        let version = LittleEndian::read_u16(&snapshot[4..]);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion{ version });
+        }
+        let (body, checksum) = snapshot.split_at(snapshot.len() - 4);
+        if crc32(body) != LittleEndian::read_u32(checksum) {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        let invalid = |reason: &str| SnapshotError::Invalid{ reason: reason.to_string() };
+
+        let mut input = Decoder { bytes: body, position: 6 };
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = input.u32()? as i32;
+        }
+        let mut tags = [ValueType::Nil; 32];
+        for tag in tags.iter_mut() {
+            *tag = ValueType::from_code(u16::from(input.u8()?)).ok_or_else(|| invalid("unknown value type"))?;
+        }
+        let tagged = input.bool()?;
+        let pc = input.usize()?;
+        let remainder = input.u32()?;
+        let equal_flag = input.bool()?;
+        let code_start = input.usize()?;
+        let program = input.bytes()?;
+        let ro_data = input.bytes()?;
+        let heap = input.bytes()?;
+        let stack = input.bytes()?;
+        if stack.len() > STACK_SIZE {
+            return Err(invalid("the stack is too big"));
+        }
+
+        let mut call_stack = vec![];
+        for _ in 0..input.u32()? {
+            call_stack.push(Frame { module: input.usize()?, return_pc: input.usize()? });
+        }
+        let mut modules = vec![];
+        for _ in 0..input.u32()? {
+            let name = input.string()?;
+            let program = input.bytes()?;
+            let ro_data = input.bytes()?;
+            let mut exports = vec![];
+            for _ in 0..input.u32()? {
+                let name = input.string()?;
+                let has_version = input.bool()?;
+                let version = input.u16()?;
+                exports.push(Export { name, version: if has_version { Some(version) } else { None }, address: input.u32()? });
+            }
+            let imports = input.pairs()?;
+            modules.push(LoadedModule { name, program, ro_data, exports, imports });
+        }
+        let current_module = input.usize()?;
+        let module_count = modules.len().max(1);
+        let refers_to_missing_module = current_module >= module_count
+            || call_stack.iter().any(|frame| frame.module >= module_count)
+            || modules.iter().flat_map(|m| m.imports.iter()).any(|(module, _)| *module >= module_count);
+        if refers_to_missing_module {
+            return Err(invalid("it refers to a module that is not in it"));
+        }
+
+        let max_heap = input.usize()?;
+        let top = input.usize()?;
+        let blocks = input.pairs()?;
+        let free_blocks = input.pairs()?;
+        if top > heap.len() {
+            return Err(invalid("the allocator's top is past the end of the heap"));
+        }
+        let mut all_blocks: Vec<&(usize, usize)> = blocks.iter().chain(free_blocks.iter()).collect();
+        all_blocks.sort();
+        let mut previous_end = 0;
+        for (address, size) in all_blocks {
+            let end = address.checked_add(*size).filter(|end| *end <= heap.len()).ok_or_else(|| invalid("a heap block is outside the heap"))?;
+            if *address < previous_end {
+                return Err(invalid("heap blocks overlap"));
+            }
+            previous_end = end;
+        }
+        let enabled = input.bool()?;
+        let stats = GcStats {
+            collections: input.usize()?,
+            objects_freed: input.usize()?,
+            bytes_freed: input.usize()?,
+            live_objects: input.usize()?,
+            live_bytes: input.usize()?,
+            pause: Duration::from_nanos(input.u64()?)
+        };
+        if input.position != body.len() {
+            return Err(invalid("there are bytes left over"));
+        }
+
+        self.registers = registers;
+        self.tags = tags;
+        self.tagged = tagged;
+        self.pc = pc;
+        self.remainder = remainder;
+        self.equal_flag = equal_flag;
+        self.code_start = code_start;
+        self.program = program;
+        self.ro_data = ro_data;
+        self.heap = heap;
+        self.stack = stack;
+        self.call_stack = call_stack;
+        self.modules = modules;
+        self.current_module = current_module;
+        self.allocator = Allocator::from_parts(max_heap, top, blocks, free_blocks);
+        self.gc = GarbageCollector { enabled, stats };
+        self.decoded.clear();
+        self.error = None;
+        self.files.close_all();
+        Ok(())
+    }
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    fn u16(&mut self, value: u16) {
+        let mut bytes = [0; 2];
+        LittleEndian::write_u16(&mut bytes, value);
+        self.0.extend_from_slice(&bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        let mut bytes = [0; 4];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.0.extend_from_slice(&bytes);
+    }
+
+    fn u64(&mut self, value: u64) {
+        let mut bytes = [0; 8];
+        LittleEndian::write_u64(&mut bytes, value);
+        self.0.extend_from_slice(&bytes);
+    }
+
+    /// Sizes and addresses are always written as 64 bits, so snapshots move between machines
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn pairs<I: Iterator<Item = (usize, usize)>>(&mut self, pairs: I) {
+        let pairs: Vec<(usize, usize)> = pairs.collect();
+        self.u32(pairs.len() as u32);
+        for (first, second) in pairs {
+            self.usize(first);
+            self.usize(second);
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() - self.position < length {
+            return Err(SnapshotError::Truncated);
+        }
+        let taken = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        let value = self.u64()?;
+        if value > usize::MAX as u64 {
+            return Err(SnapshotError::Invalid{ reason: format!("{} does not fit in this machine's memory", value) });
+        }
+        Ok(value as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, SnapshotError> {
+        String::from_utf8(self.bytes()?).map_err(|e| SnapshotError::Invalid{ reason: e.to_string() })
+    }
+
+    fn pairs(&mut self) -> Result<Vec<(usize, usize)>, SnapshotError> {
+        let mut pairs = vec![];
+        for _ in 0..self.u32()? {
+            pairs.push((self.usize()?, self.usize()?));
+        }
+        Ok(pairs)
+    }
+}
+
+/// The CRC-32 used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    /// Builds a linked list with the collector on, so the heap, stack pointer and allocator all matter
+    const PROGRAM: &str = ".data\n.code\nload $s0 #0\nload $s1 #50\nload $s2 #0\nload $t0 #8\nload $t1 #1\nload $t5 #4\n\
+                           build: new $t0 $t1 $t2\nstw $s1 $t2\nadd $t2 $t5 $t6\nstw $s0 $t6\nmov $s0 $t2\nnew $t0 $s2 $t7\n\
+                           dec $s1\nneq $s1 $s2\ndjmpe @build\n\
+                           load $s3 #0\nwalk: eq $s0 $s2\ndjmpe @done\nldw $t7 $s0\nadd $s3 $t7 $s3\n\
+                           add $s0 $t5 $t6\nldw $s0 $t6\nload $t8 @walk\njmp $t8\ndone: hlt";
+
+    fn vm() -> VM {
+        let mut vm = VM { program: Assembler::new().assemble(PROGRAM).unwrap(), ..VM::default() };
+        vm.gc.enabled = true;
+        vm.allocator.max_heap = 1024;
+        vm
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let mut finished = vm();
+        finished.run();
+
+        let mut interrupted = vm();
+        interrupted.pc = interrupted.load().unwrap();
+        for _ in 0..300 {
+            interrupted.run_once();
+        }
+        let snapshot = interrupted.snapshot();
+
+        let mut restored = VM::default();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        restored.resume();
+        assert_eq!(restored.error(), None);
+        assert_eq!(restored.registers, finished.registers);
+        assert_eq!(restored.registers[19], 50 * 51 / 2);
+        assert_eq!(restored.heap, finished.heap);
+        assert_eq!(restored.gc.stats.collections, finished.gc.stats.collections);
+    }
+
+    #[test]
+    fn test_damaged_snapshots() {
+        let snapshot = vm().snapshot();
+        let mut restored = VM::get_test_vm();
+
+        let mut corrupted = snapshot.clone();
+        corrupted[20] ^= 1;
+        assert_eq!(restored.restore(&corrupted), Err(SnapshotError::ChecksumMismatch));
+        let mut newer = snapshot.clone();
+        newer[4] = 2;
+        assert_eq!(restored.restore(&newer), Err(SnapshotError::UnsupportedVersion{ version: 2 }));
+        assert_eq!(restored.restore(b"PIE?"), Err(SnapshotError::BadMagic));
+
+        // A snapshot cut short, with its checksum redone so only the missing bytes are wrong
+        let mut truncated = snapshot[..100].to_vec();
+        let checksum = crc32(&truncated);
+        truncated.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(restored.restore(&truncated), Err(SnapshotError::Truncated));
+
+        // A block whose end is past the largest address there can be
+        let mut overflowing = vm();
+        overflowing.allocator = Allocator::from_parts(1024, 0, vec![(usize::MAX, 1)], vec![]);
+        let invalid = SnapshotError::Invalid{ reason: "a heap block is outside the heap".to_string() };
+        assert_eq!(restored.restore(&overflowing.snapshot()), Err(invalid.clone()));
+
+        // Blocks and a top past the end of an empty heap, which the collector would later index
+        let outside = VM { allocator: Allocator::from_parts(1024, 0, vec![(0, 16)], vec![]), ..VM::default() };
+        assert_eq!(restored.restore(&outside.snapshot()), Err(invalid));
+        let outside = VM { allocator: Allocator::from_parts(1024, 64, vec![], vec![]), ..VM::default() };
+        assert_eq!(restored.restore(&outside.snapshot()), Err(SnapshotError::Invalid{ reason: "the allocator's top is past the end of the heap".to_string() }));
+
+        let overlapping = VM { heap: vec![0; 64], allocator: Allocator::from_parts(1024, 64, vec![(0, 16)], vec![(8, 16)]), ..VM::default() };
+        assert_eq!(restored.restore(&overlapping.snapshot()), Err(SnapshotError::Invalid{ reason: "heap blocks overlap".to_string() }));
+        // Failed restores leave the VM alone
+        assert_eq!(restored.registers[1], 10);
+    }
+}
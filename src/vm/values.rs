@@ -0,0 +1,316 @@
+use std::fmt;
+
+use crate::instruction::{Opcode, INSTRUCTION_LENGTH};
+
+use super::{VmError, VM};
+
+/// `$at`, the register the assembler keeps for expanding pseudo-instructions
+const AT_REGISTER: usize = 31;
+
+/// The type of a register's value in tagged mode, numbered as `typeof` and `cast` number them
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ValueType {
+    #[default]
+    Nil = 0,
+    Int = 1,
+    Float = 2,
+    Bool = 3,
+    Pointer = 4
+}
+
+impl ValueType {
+    pub fn from_code(code: u16) -> Option<ValueType> {
+        match code {
+            0 => Some(ValueType::Nil),
+            1 => Some(ValueType::Int),
+            2 => Some(ValueType::Float),
+            3 => Some(ValueType::Bool),
+            4 => Some(ValueType::Pointer),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::Nil => "nil",
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::Bool => "bool",
+            ValueType::Pointer => "pointer"
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A register's value along with its type. The VM keeps the 32 bits of the value in the register
+/// and the type beside it, with floats stored as their IEEE 754 bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    Pointer(u32)
+}
+
+impl Value {
+    pub fn from_bits(value_type: ValueType, bits: i32) -> Value {
+        match value_type {
+            ValueType::Nil => Value::Nil,
+            ValueType::Int => Value::Int(bits),
+            ValueType::Float => Value::Float(f32::from_bits(bits as u32)),
+            ValueType::Bool => Value::Bool(bits != 0),
+            ValueType::Pointer => Value::Pointer(bits as u32)
+        }
+    }
+
+    /// What goes in the register
+    pub fn bits(self) -> i32 {
+        match self {
+            Value::Nil => 0,
+            Value::Int(value) => value,
+            Value::Float(value) => value.to_bits() as i32,
+            Value::Bool(value) => i32::from(value),
+            Value::Pointer(address) => address as i32
+        }
+    }
+
+    pub fn value_type(self) -> ValueType {
+        match self {
+            Value::Nil => ValueType::Nil,
+            Value::Int(_) => ValueType::Int,
+            Value::Float(_) => ValueType::Float,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Pointer(_) => ValueType::Pointer
+        }
+    }
+
+    /// Converts to another type. Anything can become nil or a bool, which is whether it is non-zero,
+    /// and numbers convert between each other, with floats rounded toward zero. Only ints and
+    /// pointers become pointers, and nil becomes nothing but nil and false.
+    pub fn cast(self, to: ValueType) -> Option<Value> {
+        let value = match (self, to) {
+            (_, ValueType::Nil) => Value::Nil,
+            (_, ValueType::Bool) => Value::Bool(self.bits() != 0 && self != Value::Float(-0.0)),
+            (Value::Nil, _) => return None,
+            (Value::Float(value), ValueType::Int) => Value::Int(value as i32),
+            (_, ValueType::Int) => Value::Int(self.bits()),
+            (Value::Float(value), ValueType::Float) => Value::Float(value),
+            (Value::Pointer(_), ValueType::Float) => return None,
+            (_, ValueType::Float) => Value::Float(self.bits() as f32),
+            (Value::Int(value), ValueType::Pointer) => Value::Pointer(value as u32),
+            (Value::Pointer(address), ValueType::Pointer) => Value::Pointer(address),
+            (_, ValueType::Pointer) => return None
+        };
+        Some(value)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => f.write_str("nil"),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Pointer(address) => write!(f, "{:#010x}", address)
+        }
+    }
+}
+
+impl VM {
+    /// The value in a register. Outside tagged mode every register holds an int.
+    pub fn value(&self, register: usize) -> Value {
+        let value_type = if self.tagged { self.tags[register] } else { ValueType::Int };
+        Value::from_bits(value_type, self.registers[register])
+    }
+
+    /// Turns tagged mode on or off. Registers hold ints until then, so that is the type they start
+    /// out with.
+    pub fn set_tagged(&mut self, tagged: bool) {
+        if tagged && !self.tagged {
+            self.tags = [ValueType::Int; 32];
+        }
+        self.tagged = tagged;
+    }
+
+    pub fn set_value(&mut self, register: usize, value: Value) {
+        self.registers[register] = value.bits();
+        self.tags[register] = value.value_type();
+    }
+
+    /// Runs an instruction in tagged mode. Arithmetic and comparisons check the types of their
+    /// operands, and everything else runs as usual before the registers it wrote are given a type.
+    /// Ints and floats never mix without a `cast`, but pointers can have ints added or taken away.
+    pub(super) fn execute_tagged(&mut self, opcode: Opcode, [a, b, c]: [usize; 3], next: usize) -> bool {
+        let pc = next - INSTRUCTION_LENGTH;
+        match opcode {
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                let result = match (opcode, self.value(a), self.value(b)) {
+                    (_, Value::Int(_), Value::Int(_)) => {
+                        let is_done = self.execute_untagged(opcode, [a, b, c], next);
+                        self.tags[c] = ValueType::Int;
+                        return is_done;
+                    },
+                    // `mov $dst $src` expands to `load $at #0` and `add $src $at $dst`, so adding an int zero
+                    // held in `$at` copies a value of any type. `$at` is kept for the assembler, and adding a
+                    // zero held in any other register is type checked as usual.
+                    (Opcode::ADD, value, Value::Int(0)) if b == AT_REGISTER => value,
+                    (Opcode::ADD, Value::Float(x), Value::Float(y)) => Value::Float(x + y),
+                    (Opcode::SUB, Value::Float(x), Value::Float(y)) => Value::Float(x - y),
+                    (Opcode::MUL, Value::Float(x), Value::Float(y)) => Value::Float(x * y),
+                    (Opcode::DIV, Value::Float(x), Value::Float(y)) => Value::Float(x / y),
+                    (Opcode::ADD, Value::Pointer(address), Value::Int(offset))
+                        | (Opcode::ADD, Value::Int(offset), Value::Pointer(address)) => Value::Pointer(address.wrapping_add(offset as u32)),
+                    (Opcode::SUB, Value::Pointer(address), Value::Int(offset)) => Value::Pointer(address.wrapping_sub(offset as u32)),
+                    (Opcode::SUB, Value::Pointer(x), Value::Pointer(y)) => Value::Int(x.wrapping_sub(y) as i32),
+                    _ => return self.type_fault(pc, opcode, &[a, b])
+                };
+                self.set_value(c, result);
+            },
+            Opcode::EQ => self.equal_flag = self.value(a) == self.value(b),
+            Opcode::NEQ => self.equal_flag = self.value(a) != self.value(b),
+            Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => {
+                let ordering = match (self.value(a), self.value(b)) {
+                    (Value::Int(x), Value::Int(y)) => x.partial_cmp(&y),
+                    (Value::Float(x), Value::Float(y)) => x.partial_cmp(&y),
+                    _ => return self.type_fault(pc, opcode, &[a, b])
+                };
+                self.equal_flag = match opcode {
+                    Opcode::GT => ordering == Some(std::cmp::Ordering::Greater),
+                    Opcode::LT => ordering == Some(std::cmp::Ordering::Less),
+                    Opcode::GTE => ordering.is_some_and(|o| o != std::cmp::Ordering::Less),
+                    _ => ordering.is_some_and(|o| o != std::cmp::Ordering::Greater)
+                };
+            },
+            Opcode::INC | Opcode::DEC => {
+                if !matches!(self.tags[a], ValueType::Int | ValueType::Pointer) {
+                    return self.type_fault(pc, opcode, &[a]);
+                }
+                return self.execute_untagged(opcode, [a, b, c], next);
+            },
+            Opcode::TYPEOF => {
+                let value_type = self.tags[a];
+                self.set_value(b, Value::Int(value_type as i32));
+            },
+            Opcode::CAST => {
+                let to = match ValueType::from_code(b as u16) {
+                    Some(to) => to,
+                    None => return self.fail_at(pc, VmError::InvalidType{ pc, code: b as u16 })
+                };
+                let from = self.value(a);
+                match from.cast(to) {
+                    Some(value) => self.set_value(a, value),
+                    None => return self.fail_at(pc, VmError::InvalidCast{ pc, from: from.value_type(), to })
+                }
+            },
+            _ => {
+                let registers = self.registers;
+                let is_done = self.execute_untagged(opcode, [a, b, c], next);
+                let written = match opcode {
+                    Opcode::LOAD | Opcode::LDB | Opcode::LDW => Some((a, ValueType::Int)),
+                    Opcode::ALOC => Some((b, ValueType::Pointer)),
+                    Opcode::NEW => Some((c, ValueType::Pointer)),
+                    _ => None
+                };
+                match written {
+                    Some((register, value_type)) if self.error.is_none() => self.tags[register] = value_type,
+                    Some(_) => {},
+                    // Host functions and the console opcodes return ints in whichever registers they change
+                    None => {
+                        for (register, before) in registers.iter().enumerate() {
+                            if self.registers[register] != *before {
+                                self.tags[register] = ValueType::Int;
+                            }
+                        }
+                    }
+                }
+                return is_done;
+            }
+        }
+        self.pc = next;
+        false
+    }
+
+    fn type_fault(&mut self, pc: usize, opcode: Opcode, registers: &[usize]) -> bool {
+        let types = registers.iter().map(|register| self.tags[*register]).collect();
+        self.fail_at(pc, VmError::TypeFault{ pc, mnemonic: opcode.mnemonic(), types })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn run(source: &str) -> VM {
+        let mut vm = VM { program: Assembler::new().assemble(source).unwrap(), tagged: true, ..VM::default() };
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_casts() {
+        assert_eq!(Value::Float(2.9).cast(ValueType::Int), Some(Value::Int(2)));
+        assert_eq!(Value::Int(-3).cast(ValueType::Float), Some(Value::Float(-3.0)));
+        assert_eq!(Value::Int(0).cast(ValueType::Bool), Some(Value::Bool(false)));
+        assert_eq!(Value::Float(-0.0).cast(ValueType::Bool), Some(Value::Bool(false)));
+        assert_eq!(Value::Pointer(16).cast(ValueType::Bool), Some(Value::Bool(true)));
+        assert_eq!(Value::Nil.cast(ValueType::Int), None);
+        assert_eq!(Value::Bool(true).cast(ValueType::Pointer), None);
+        assert_eq!(Value::from_bits(ValueType::Float, Value::Float(1.5).bits()), Value::Float(1.5));
+        assert_eq!(Value::Float(3.0).to_string(), "3.0");
+        assert_eq!(Value::Pointer(16).to_string(), "0x00000010");
+    }
+
+    #[test]
+    fn test_tagged_arithmetic() {
+        // 7 / 2 as floats, then back to an int
+        let vm = run(".data\n.code\nload $t0 #7\nload $t1 #2\ncast $t0 #2\ncast $t1 #2\ndiv $t0 $t1 $t2\n\
+                      mov $t3 $t2\ncast $t3 #1\ntypeof $t2 $t4\ngt $t2 $t1\nhlt");
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.value(6), Value::Float(3.5));
+        assert_eq!(vm.value(7), Value::Int(3));
+        assert_eq!(vm.value(8), Value::Int(ValueType::Float as i32));
+        assert!(vm.equal_flag);
+        assert_eq!(vm.value(30), Value::Pointer(crate::vm::memory::STACK_TOP));
+        assert_eq!(vm.value(9), Value::Nil);
+
+        let vm = run(".data\n.code\nload $t0 #16\naloc $t0 $t1\nadd $t1 $t0 $t2\nsub $t2 $t1 $t3\nhlt");
+        assert_eq!(vm.value(6), Value::Pointer(20));
+        assert_eq!(vm.value(7), Value::Int(16));
+    }
+
+    #[test]
+    fn test_type_faults() {
+        let vm = run(".data\n.code\nload $t0 #1\nload $t1 #2\ncast $t1 #2\nadd $t0 $t1 $t2\nhlt");
+        let code_start = vm.code_start;
+        assert_eq!(vm.error(), Some(&VmError::TypeFault{ pc: code_start + 12, mnemonic: "add", types: vec![ValueType::Int, ValueType::Float] }));
+        assert_eq!(vm.error().unwrap().to_string(), format!("Type fault at {}: add cannot take int and float", code_start + 12));
+
+        // Only `mov`, which adds the zero in `$at`, gets past the check with an int zero
+        let vm = run(".data\n.code\nload $t0 #1\ncast $t0 #2\nload $t1 #0\nadd $t0 $t1 $t2\nhlt");
+        assert_eq!(vm.error(), Some(&VmError::TypeFault{ pc: code_start + 12, mnemonic: "add", types: vec![ValueType::Float, ValueType::Int] }));
+
+        let vm = run(".data\n.code\ninc $t0\nhlt");
+        assert!(matches!(vm.error(), Some(VmError::TypeFault{ mnemonic: "inc", .. })));
+        let vm = run(".data\n.code\ncast $t0 #2\nhlt");
+        assert!(matches!(vm.error(), Some(VmError::InvalidCast{ from: ValueType::Nil, to: ValueType::Float, .. })));
+        let vm = run(".data\n.code\ncast $t0 #9\nhlt");
+        assert!(matches!(vm.error(), Some(VmError::InvalidType{ code: 9, .. })));
+    }
+
+    #[test]
+    fn test_untagged_mode_is_unchanged() {
+        let mut vm = VM { program: Assembler::new().assemble(".data\n.code\nload $t0 #1\nload $t1 #2\ncast $t1 #2\nadd $t0 $t1 $t2\ntypeof $t2 $t3\nhlt").unwrap(), ..VM::default() };
+        vm.run();
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.registers[6], 1 + 2.0f32.to_bits() as i32);
+        assert_eq!(vm.registers[7], ValueType::Int as i32);
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::error::Error;
+
+use crate::loader::LoadError;
+
+use super::memory::{Access, Fault, FaultReason};
+use super::sandbox::Capability;
+use super::values::ValueType;
+
+/// Why a program stopped before reaching `hlt`
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// The program does not start with the PIE header
+    BadHeader,
+    /// The program or one of the modules it imports from could not be loaded
+    LoadFailed{ error: LoadError },
+    IllegalOpcode{ address: usize },
+    EmptyCallStack{ address: usize },
+    UnresolvedImport{ slot: usize },
+    UnknownSyscall{ number: u16 },
+    HostFunctionFailed{ name: String, error: String },
+    PermissionDenied{ capability: Capability },
+    /// An access the memory map does not allow. `pc` is where the faulting instruction starts, and is
+    /// where the VM is left so the program can be resumed once the cause is dealt with.
+    MemoryFault{ pc: usize, address: u32, access: Access, reason: FaultReason },
+    InvalidRegister{ pc: usize, register: usize },
+    DivideByZero{ pc: usize },
+    OutOfMemory{ pc: usize, size: i32 },
+    DoubleFree{ pc: usize, address: u32 },
+    InvalidFree{ pc: usize, address: u32 },
+    /// In tagged mode, an instruction was given values of types it does not work on
+    TypeFault{ pc: usize, mnemonic: &'static str, types: Vec<ValueType> },
+    InvalidCast{ pc: usize, from: ValueType, to: ValueType },
+    InvalidType{ pc: usize, code: u16 }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::BadHeader => {
+                f.write_str("The program does not start with a valid header")
+            },
+            VmError::LoadFailed{ error } => {
+                f.write_str(&format!("Unable to load program: {}", error))
+            },
+            VmError::IllegalOpcode{ address } => {
+                f.write_str(&format!("Unrecognized opcode found at {}", address))
+            },
+            VmError::EmptyCallStack{ address } => {
+                f.write_str(&format!("RET at {} with an empty call stack", address))
+            },
+            VmError::UnresolvedImport{ slot } => {
+                f.write_str(&format!("CALLX to unresolved import {}", slot))
+            },
+            VmError::UnknownSyscall{ number } => {
+                f.write_str(&format!("No host function is registered for syscall {}", number))
+            },
+            VmError::HostFunctionFailed{ name, error } => {
+                f.write_str(&format!("Host function {} failed: {}", name, error))
+            },
+            VmError::PermissionDenied{ capability } => {
+                f.write_str(&format!("The sandbox policy does not allow {}", capability))
+            },
+            VmError::MemoryFault{ pc, address, access, reason } => {
+                let reason = match reason {
+                    FaultReason::Unmapped => "nothing is mapped there",
+                    FaultReason::Guard => "it is in a stack guard page",
+                    FaultReason::Permission => "the region does not allow it"
+                };
+                f.write_str(&format!("Memory fault at {}: {} of address {:#010x} failed, as {}", pc, access, address, reason))
+            },
+            VmError::InvalidRegister{ pc, register } => {
+                f.write_str(&format!("Invalid register {} used at {}", register, pc))
+            },
+            VmError::DivideByZero{ pc } => {
+                f.write_str(&format!("Division by zero at {}", pc))
+            },
+            VmError::OutOfMemory{ pc, size } => {
+                f.write_str(&format!("Unable to allocate {} bytes at {}", size, pc))
+            },
+            VmError::DoubleFree{ pc, address } => {
+                f.write_str(&format!("Block at {:#010x} freed twice at {}", address, pc))
+            },
+            VmError::InvalidFree{ pc, address } => {
+                f.write_str(&format!("Freeing {:#010x} at {}, where no block was allocated", address, pc))
+            },
+            VmError::TypeFault{ pc, mnemonic, types } => {
+                let types: Vec<&str> = types.iter().map(|t| t.name()).collect();
+                f.write_str(&format!("Type fault at {}: {} cannot take {}", pc, mnemonic, types.join(" and ")))
+            },
+            VmError::InvalidCast{ pc, from, to } => {
+                f.write_str(&format!("Cannot cast {} to {} at {}", from, to, pc))
+            },
+            VmError::InvalidType{ pc, code } => {
+                f.write_str(&format!("No type is numbered {}, at {}", code, pc))
+            }
+        }
+    }
+}
+
+impl Error for VmError {}
+
+impl Fault {
+    /// The error for this fault happening in the instruction at `pc`
+    pub fn at(self, pc: usize) -> VmError {
+        VmError::MemoryFault{ pc, address: self.address, access: self.access, reason: self.reason }
+    }
+}